@@ -0,0 +1,138 @@
+//! Machine state left by the boot ROM, to start a cartridge at 0x0100
+//! directly when no boot ROM is available
+
+use crate::bus::Bus;
+use crate::emulator::Emulator;
+use crate::model::Model;
+
+/// IO registers as left by the boot ROM, the same on every model apart
+/// from SC on CGB
+const DMG_IO: [(u16, u8); 34] = [
+    (0xFF00, 0xCF), // P1
+    (0xFF01, 0x00), // SB
+    (0xFF02, 0x7E), // SC
+    (0xFF05, 0x00), // TIMA
+    (0xFF06, 0x00), // TMA
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0xBF), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0xBF), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0xBF), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0xBF), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF26, 0xF1), // NR52, channel flags are not emulated
+    (0xFF40, 0x91), // LCDC
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF45, 0x00), // LYC
+    (0xFF47, 0xFC), // BGP
+    (0xFF50, 0x01), // Boot ROM lock
+];
+
+/// Internal timer counter when the boot ROM jumps to 0x0100. It depends on
+/// the SGB handshake and the CGB boot animation on the other models, they are
+/// left at 0.
+fn div_counter(model: Model) -> u16 {
+    match model {
+        Model::Dmg0 => 0x1830,
+        Model::Dmg | Model::Mgb => 0xABCC,
+        _ => 0x0000,
+    }
+}
+
+/// AF, BC, DE and HL as left by the boot ROM of `model`
+fn registers(model: Model, header: impl Fn(usize) -> u8) -> [u16; 4] {
+    // H and C are only set when the header checksum is not zero
+    let checksum_flags = if header(0x14D) == 0 { 0x80 } else { 0xB0 };
+    let cgb_cartridge = header(0x143) & 0x80 == 0x80;
+    match model {
+        Model::Dmg0 => [0x0100, 0xFF13, 0x00C1, 0x8403],
+        Model::Dmg => [0x0100 | checksum_flags, 0x0013, 0x00D8, 0x014D],
+        Model::Mgb => [0xFF00 | checksum_flags, 0x0013, 0x00D8, 0x014D],
+        Model::Sgb => [0x0100, 0x0014, 0x0000, 0xC060],
+        Model::Sgb2 => [0xFF00, 0x0014, 0x0000, 0xC060],
+        Model::Cgb if cgb_cartridge => [0x1180, 0x0000, 0xFF56, 0x000D],
+        Model::Cgb => [0x1180, 0x0000, 0x0008, 0x007C],
+        // The AGB boot ROM ends with an extra INC B
+        Model::Agb if cgb_cartridge => [0x1100, 0x0100, 0xFF56, 0x000D],
+        Model::Agb => [0x1100, 0x0100, 0x0008, 0x007C],
+    }
+}
+
+/// The ® symbol drawn next to the logo
+const REGISTERED_TILE: [u8; 8] =
+    [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+/// Put the machine in the state the boot ROM leaves it in
+pub(crate) fn skip_boot(emulator: &mut Emulator) {
+    let model = emulator.model();
+    let rom = emulator.memory.rom().to_vec();
+    let header = |address: usize| rom.get(address).copied().unwrap_or(0);
+
+    let [af, bc, de, hl] = registers(model, header);
+    let regs = emulator.registers_mut();
+    regs.set_af(af);
+    regs.set_bc(bc);
+    regs.set_de(de);
+    regs.set_hl(hl);
+    regs.sp = 0xFFFE;
+    regs.pc = 0x0100;
+
+    let memory = &mut emulator.memory;
+    for &(address, val) in DMG_IO.iter() {
+        let _ = memory.write_byte(address, val);
+    }
+    if model.is_cgb() {
+        // SC - The CGB boot ROM also leaves the fast clock bit set
+        let _ = memory.write_byte(0xFF02, 0x7F);
+    }
+    memory.timer.set_counter(div_counter(model));
+
+    // The CGB boot ROM clears VRAM after its animation
+    if model.is_cgb() {
+        return;
+    }
+
+    // The boot ROM scales the cartridge logo up and copies it in VRAM, each
+    // nibble giving two lines of a tile
+    let mut address = 0x8010;
+    for byte in (0x104..0x134).map(header) {
+        for nibble in [byte >> 4, byte & 0x0F].iter() {
+            let line = (0..4).fold(0, |acc, bit| {
+                acc | (((nibble >> bit) & 1) * (0b11 << (bit * 2)))
+            });
+            for _ in 0..2 {
+                let _ = memory.write_byte(address, line);
+                let _ = memory.write_byte(address + 1, 0);
+                address += 2;
+            }
+        }
+    }
+    for &line in REGISTERED_TILE.iter() {
+        let _ = memory.write_byte(address, line);
+        let _ = memory.write_byte(address + 1, 0);
+        address += 2;
+    }
+
+    // Logo tiles in the background map, with the ® at the end of the top row
+    for tile in 0..12 {
+        let _ = memory.write_byte(0x9904 + tile, tile as u8 + 1);
+        let _ = memory.write_byte(0x9924 + tile, tile as u8 + 13);
+    }
+    let _ = memory.write_byte(0x9910, 0x19);
+}
@@ -0,0 +1,87 @@
+use crate::emulator::VmExit;
+
+/// Everything the CPU can see through its address and data lines
+pub trait Bus {
+    /// Read the byte at `address`
+    fn read_byte(&mut self, address: u16) -> Result<u8, VmExit>;
+
+    /// Write `val` at `address`
+    fn write_byte(&mut self, address: u16, val: u8) -> Result<(), VmExit>;
+
+    /// Read the byte at `address` without anything watching the bus noticing,
+    /// for debuggers and tools
+    fn peek_byte(&mut self, address: u16) -> Result<u8, VmExit> {
+        self.read_byte(address)
+    }
+
+    /// Write `val` at `address` without anything watching the bus noticing,
+    /// for changes the CPU makes outside of instructions
+    fn poke_byte(&mut self, address: u16, val: u8) -> Result<(), VmExit> {
+        self.write_byte(address, val)
+    }
+
+    /// Advance every device on the bus by `cycles` clock cycles
+    fn tick(&mut self, cycles: usize);
+
+    /// The 16-bit increment unit put `address` on the address bus without
+    /// reading or writing, as INC rr and DEC rr do
+    fn idu_access(&mut self, _address: u16) {}
+
+    /// Read a little-endian word at `address`
+    fn read_word(&mut self, address: u16) -> Result<u16, VmExit> {
+        Ok(self.read_byte(address)? as u16
+            | (self.read_byte(address.wrapping_add(1))? as u16) << 8)
+    }
+
+    /// Write a little-endian word at `address`
+    fn write_word(&mut self, address: u16, val: u16) -> Result<(), VmExit> {
+        self.write_byte(address, (val & 0xFF) as u8)?;
+        self.write_byte(address.wrapping_add(1), (val >> 8) as u8)?;
+        Ok(())
+    }
+}
+
+/// 64 KiB of plain RAM with no devices mapped, used to run the CPU on its own
+pub struct FlatBus {
+    memory: Vec<u8>,
+
+    /// Number of clock cycles elapsed since creation
+    pub cycles: usize,
+}
+
+impl FlatBus {
+    pub fn new() -> FlatBus {
+        FlatBus {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+        }
+    }
+
+    /// Copy `data` in memory starting at `address`
+    pub fn load(&mut self, address: u16, data: &[u8]) {
+        let start = address as usize;
+        let end = (start + data.len()).min(self.memory.len());
+        self.memory[start..end].copy_from_slice(&data[..end - start]);
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for FlatBus {
+    fn read_byte(&mut self, address: u16) -> Result<u8, VmExit> {
+        Ok(self.memory[address as usize])
+    }
+
+    fn write_byte(&mut self, address: u16, val: u8) -> Result<(), VmExit> {
+        self.memory[address as usize] = val;
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+    }
+}
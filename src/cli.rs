@@ -0,0 +1,624 @@
+use gbemu::gpu::{Palette, GREEN_PALETTE, GREY_PALETTE, POCKET_PALETTE};
+use gbemu::trace::TraceFilter;
+use gbemu::Model;
+
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, SubCommand};
+
+/// Everything the frontend can be asked for on the command line
+pub struct Options {
+    pub rom: PathBuf,
+    pub bootrom: Option<PathBuf>,
+
+    /// Model to emulate, `None` to pick it from the cartridge header
+    pub model: Option<Model>,
+
+    /// Run without opening a window
+    pub headless: bool,
+
+    /// Start in the debugger
+    pub debug: bool,
+
+    /// Port to wait for GDB on before starting
+    pub gdb: Option<u16>,
+
+    /// Where to write the bytes the game sends over the link port
+    pub serial: Option<SerialOutput>,
+
+    /// Other instance to plug in the link port
+    pub link: Option<Link>,
+
+    /// Directory where the printer plugged in the link port writes
+    pub printer: Option<PathBuf>,
+
+    /// Number of frames to run before exiting, `None` to run forever in a
+    /// window or for the whole movie or hash log when headless
+    pub frames: Option<u64>,
+
+    /// Window size as a multiple of the screen resolution
+    pub scale: u32,
+
+    pub palette: Palette,
+
+    /// Directory for battery backed cartridge RAM
+    pub save_dir: Option<PathBuf>,
+
+    /// Where to write the final frame when running headless
+    pub screenshot: Option<PathBuf>,
+
+    /// Write a frame every so many frames in `screenshot_dir`
+    pub screenshot_every: Option<u64>,
+    pub screenshot_dir: Option<PathBuf>,
+
+    /// Script of buttons to press when running headless
+    pub input: Option<PathBuf>,
+
+    /// Screenshot the final frame must match when running headless
+    pub expect: Option<PathBuf>,
+
+    /// Where to write images of the video memory at the end of the run
+    pub vram_dump: Option<PathBuf>,
+
+    /// Where to write the hashes of the machine state after every frame
+    pub hash_log: Option<PathBuf>,
+
+    /// Hash log the run must match
+    pub check_hashes: Option<PathBuf>,
+
+    /// Where to write a trace of every instruction
+    pub trace: Option<PathBuf>,
+
+    /// Instructions traced
+    pub trace_filter: TraceFilter,
+
+    /// Print where cycles were spent at the end of the run
+    pub profile: bool,
+
+    /// Where to write the profiled call stacks, for flame graphs
+    pub profile_folded: Option<PathBuf>,
+
+    /// Where to write a movie of the run
+    pub record: Option<PathBuf>,
+
+    /// Movie to replay
+    pub play: Option<PathBuf>,
+
+    /// Frames between two rewind snapshots
+    pub rewind_interval: u32,
+
+    /// MiB kept for rewinding, 0 to disable it
+    pub rewind_memory: usize,
+
+    /// Snapshots stepped back per displayed frame while rewinding
+    pub rewind_speed: u32,
+
+    /// Speed multiplier, 1 for the hardware frame rate
+    pub speed: f64,
+
+    /// Frames skipped after every drawn one when fast-forwarding
+    pub frame_skip: u32,
+}
+
+/// Where bytes sent over the link port go
+pub enum SerialOutput {
+    Stdout,
+    File(PathBuf),
+}
+
+/// How to reach the other instance of a link cable, at a `HOST:PORT` or
+/// `unix:PATH` address
+pub enum Link {
+    Listen(String),
+    Connect(String),
+}
+
+/// What the command line asks for
+pub enum Action {
+    /// Run a cartridge
+    Run(Box<Options>),
+
+    /// Print `count` instructions of `rom` from `address` in `bank`
+    Disasm {
+        rom: PathBuf,
+        bank: usize,
+        address: u16,
+        count: usize,
+    },
+
+    /// Report the first line where `trace` differs from `reference`
+    TraceDiff { trace: PathBuf, reference: PathBuf },
+}
+
+impl Action {
+    /// Parse the command line, exiting with a usage message when it is
+    /// invalid
+    pub fn from_args() -> Action {
+        let matches = App::new("gbemu")
+            .version(env!("CARGO_PKG_VERSION"))
+            .about("Game Boy emulator")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .setting(AppSettings::ArgsNegateSubcommands)
+            .arg(
+                Arg::with_name("rom")
+                    .help("Cartridge ROM to run")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("bootrom")
+                    .long("bootrom")
+                    .value_name("PATH")
+                    .help(
+                        "Boot ROM to run before the cartridge, or directory \
+                         holding the boot ROM of every model",
+                    ),
+            )
+            .arg(
+                Arg::with_name("model")
+                    .long("model")
+                    .value_name("MODEL")
+                    .possible_values(&[
+                        "dmg0", "dmg", "mgb", "sgb", "sgb2", "cgb", "agb",
+                    ])
+                    .help("Hardware to emulate [default: from the header]"),
+            )
+            .arg(
+                Arg::with_name("headless")
+                    .long("headless")
+                    .help("Run without opening a window"),
+            )
+            .arg(
+                Arg::with_name("debug")
+                    .long("debug")
+                    .help("Start in the debugger, reading commands on stdin"),
+            )
+            .arg(
+                Arg::with_name("gdb")
+                    .long("gdb")
+                    .value_name("PORT")
+                    .conflicts_with_all(&["debug", "trace"])
+                    .validator(|v| parse_number::<u16>(&v).map(|_| ()))
+                    .help("Wait for GDB to connect on a local TCP port"),
+            )
+            .arg(
+                Arg::with_name("serial")
+                    .long("serial")
+                    .value_name("OUTPUT")
+                    .help("Write the link port output to stdout or a file"),
+            )
+            .arg(
+                Arg::with_name("link")
+                    .long("link")
+                    .value_name("listen:ADDR|connect:ADDR")
+                    .conflicts_with("serial")
+                    .validator(|v| parse_link(&v).map(|_| ()))
+                    .help("Link to another instance over a socket"),
+            )
+            .arg(
+                Arg::with_name("printer")
+                    .long("printer")
+                    .value_name("DIR")
+                    .conflicts_with_all(&["serial", "link"])
+                    .help("Plug a Game Boy Printer writing PNG files in DIR"),
+            )
+            .arg(
+                Arg::with_name("frames")
+                    .long("frames")
+                    .value_name("N")
+                    .validator(|v| parse_number::<u64>(&v).map(|_| ()))
+                    .help("Exit after N frames"),
+            )
+            .arg(
+                Arg::with_name("scale")
+                    .long("scale")
+                    .value_name("N")
+                    .default_value("3")
+                    .validator(|v| match parse_number::<u32>(&v)? {
+                        0 => Err("the scale can't be 0".to_string()),
+                        _ => Ok(()),
+                    })
+                    .help("Window size as a multiple of 160x144"),
+            )
+            .arg(
+                Arg::with_name("palette")
+                    .long("palette")
+                    .value_name("PALETTE")
+                    .default_value("grey")
+                    .validator(|v| parse_palette(&v).map(|_| ()))
+                    .help(
+                        "grey, green, pocket or four RRGGBB colours from \
+                         lightest to darkest, separated by commas",
+                    ),
+            )
+            .arg(
+                Arg::with_name("save-dir")
+                    .long("save-dir")
+                    .value_name("DIR")
+                    .help("Keep battery backed cartridge RAM in DIR"),
+            )
+            .arg(
+                Arg::with_name("screenshot")
+                    .long("screenshot")
+                    .value_name("FILE")
+                    .requires("headless")
+                    .help("Write the final frame as PNG"),
+            )
+            .arg(
+                Arg::with_name("screenshot-every")
+                    .long("screenshot-every")
+                    .value_name("N")
+                    .requires_all(&["headless", "screenshot-dir"])
+                    .validator(|v| match parse_number::<u64>(&v)? {
+                        0 => Err("the interval can't be 0".to_string()),
+                        _ => Ok(()),
+                    })
+                    .help("Write every Nth frame as PNG"),
+            )
+            .arg(
+                Arg::with_name("screenshot-dir")
+                    .long("screenshot-dir")
+                    .value_name("DIR")
+                    .requires("screenshot-every")
+                    .help("Where to write the frames of --screenshot-every"),
+            )
+            .arg(
+                Arg::with_name("input")
+                    .long("input")
+                    .value_name("FILE")
+                    .requires("headless")
+                    .help("Script of buttons to hold from given frames on"),
+            )
+            .arg(
+                Arg::with_name("expect")
+                    .long("expect")
+                    .value_name("FILE")
+                    .requires("headless")
+                    .help("Fail unless the final frame matches this PNG"),
+            )
+            .arg(
+                Arg::with_name("vram-dump")
+                    .long("vram-dump")
+                    .value_name("DIR")
+                    .requires("headless")
+                    .help("Write the tiles, maps and objects at the end"),
+            )
+            .arg(
+                Arg::with_name("hash-log")
+                    .long("hash-log")
+                    .value_name("FILE")
+                    .requires("headless")
+                    .help("Log hashes of the machine state every frame"),
+            )
+            .arg(
+                Arg::with_name("check-hashes")
+                    .long("check-hashes")
+                    .value_name("FILE")
+                    .requires("headless")
+                    .help("Report the first frame diverging from a hash log"),
+            )
+            .arg(
+                Arg::with_name("trace")
+                    .long("trace")
+                    .value_name("FILE")
+                    .requires("headless")
+                    .conflicts_with("debug")
+                    .help(
+                        "Log every instruction in Gameboy Doctor format, LY \
+                         reading 0x90",
+                    ),
+            )
+            .arg(
+                Arg::with_name("trace-range")
+                    .long("trace-range")
+                    .value_name("START-END")
+                    .requires("trace")
+                    .validator(|v| parse_range(&v).map(|_| ()))
+                    .help("Only trace instructions in this range of PCs"),
+            )
+            .arg(
+                Arg::with_name("trace-bank")
+                    .long("trace-bank")
+                    .value_name("BANK")
+                    .requires("trace")
+                    .validator(|v| parse_bank(&v).map(|_| ()))
+                    .help("Only trace instructions in this ROM bank"),
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .long("profile")
+                    .requires("headless")
+                    .conflicts_with_all(&["debug", "gdb", "trace"])
+                    .help("Report the functions and PCs taking most cycles"),
+            )
+            .arg(
+                Arg::with_name("profile-folded")
+                    .long("profile-folded")
+                    .value_name("FILE")
+                    .requires("headless")
+                    .conflicts_with_all(&["debug", "gdb", "trace"])
+                    .help("Write profiled call stacks for flame graphs"),
+            )
+            .arg(
+                Arg::with_name("record")
+                    .long("record")
+                    .value_name("FILE")
+                    .help("Record the input of every frame as a movie"),
+            )
+            .arg(
+                Arg::with_name("play")
+                    .long("play")
+                    .value_name("FILE")
+                    .conflicts_with("input")
+                    .help("Replay a movie, .vbm and .bk2 ones are imported"),
+            )
+            .arg(
+                Arg::with_name("rewind-interval")
+                    .long("rewind-interval")
+                    .value_name("N")
+                    .default_value("4")
+                    .validator(|v| match parse_number::<u32>(&v)? {
+                        0 => Err("the interval can't be 0".to_string()),
+                        _ => Ok(()),
+                    })
+                    .help("Frames between two rewind snapshots"),
+            )
+            .arg(
+                Arg::with_name("rewind-memory")
+                    .long("rewind-memory")
+                    .value_name("MIB")
+                    .default_value("32")
+                    .validator(|v| parse_number::<usize>(&v).map(|_| ()))
+                    .help("Memory kept for rewinding, 0 disables it"),
+            )
+            .arg(
+                Arg::with_name("rewind-speed")
+                    .long("rewind-speed")
+                    .value_name("N")
+                    .default_value("1")
+                    .validator(|v| match parse_number::<u32>(&v)? {
+                        0 => Err("the speed can't be 0".to_string()),
+                        _ => Ok(()),
+                    })
+                    .help("Snapshots stepped back per frame when rewinding"),
+            )
+            .arg(
+                Arg::with_name("speed")
+                    .long("speed")
+                    .value_name("X")
+                    .default_value("1")
+                    .validator(|v| match parse_number::<f64>(&v)? {
+                        x if x > 0.0 && x.is_finite() => Ok(()),
+                        _ => Err("the speed must be positive".to_string()),
+                    })
+                    .help("Speed multiplier, below 1 for slow motion"),
+            )
+            .arg(
+                Arg::with_name("frame-skip")
+                    .long("frame-skip")
+                    .value_name("N")
+                    .default_value("3")
+                    .validator(|v| parse_number::<u32>(&v).map(|_| ()))
+                    .help("Frames not drawn after each one in fast-forward"),
+            )
+            .subcommand(
+                SubCommand::with_name("disasm")
+                    .about("Disassemble a cartridge ROM")
+                    .arg(
+                        Arg::with_name("rom")
+                            .help("Cartridge ROM to read")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("location")
+                            .help("BANK:ADDR to start at, in hexadecimal")
+                            .required(true)
+                            .validator(|v| parse_location(&v).map(|_| ())),
+                    )
+                    .arg(
+                        Arg::with_name("count")
+                            .help("Number of instructions")
+                            .default_value("16")
+                            .validator(|v| {
+                                parse_number::<usize>(&v).map(|_| ())
+                            }),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("tracediff")
+                    .about("Compare an instruction trace with a reference one")
+                    .arg(
+                        Arg::with_name("trace")
+                            .help("Trace written by --trace")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("reference")
+                            .help("Trace of another emulator")
+                            .required(true),
+                    ),
+            )
+            .get_matches();
+
+        // Values were checked by the validators
+        if let Some(matches) = matches.subcommand_matches("disasm") {
+            let location = matches.value_of("location").unwrap();
+            let (bank, address) = parse_location(location).unwrap();
+            return Action::Disasm {
+                rom: matches.value_of("rom").unwrap().into(),
+                bank,
+                address,
+                count: parse_number(matches.value_of("count").unwrap())
+                    .unwrap(),
+            };
+        }
+        if let Some(matches) = matches.subcommand_matches("tracediff") {
+            return Action::TraceDiff {
+                trace: matches.value_of("trace").unwrap().into(),
+                reference: matches.value_of("reference").unwrap().into(),
+            };
+        }
+        // Headless runs must end on their own
+        if matches.is_present("headless")
+            && !["frames", "play", "check-hashes"]
+                .iter()
+                .any(|arg| matches.is_present(arg))
+        {
+            clap::Error::with_description(
+                "--headless needs --frames, unless --play or --check-hashes \
+                 gives the number of frames to run",
+                ErrorKind::MissingRequiredArgument,
+            )
+            .exit();
+        }
+        Action::Run(Box::new(Options::from_matches(&matches)))
+    }
+}
+
+impl Options {
+    fn from_matches(matches: &ArgMatches) -> Options {
+        Options {
+            rom: matches.value_of("rom").unwrap().into(),
+            bootrom: matches.value_of("bootrom").map(PathBuf::from),
+            model: matches.value_of("model").map(|v| v.parse().unwrap()),
+            headless: matches.is_present("headless"),
+            debug: matches.is_present("debug"),
+            gdb: matches.value_of("gdb").map(|v| parse_number(v).unwrap()),
+            serial: matches.value_of("serial").map(|v| match v {
+                "stdout" => SerialOutput::Stdout,
+                _ => SerialOutput::File(v.into()),
+            }),
+            link: matches.value_of("link").map(|v| parse_link(v).unwrap()),
+            printer: matches.value_of("printer").map(PathBuf::from),
+            frames: matches
+                .value_of("frames")
+                .map(|v| parse_number(v).unwrap()),
+            scale: parse_number(matches.value_of("scale").unwrap()).unwrap(),
+            palette: parse_palette(matches.value_of("palette").unwrap())
+                .unwrap(),
+            save_dir: matches.value_of("save-dir").map(PathBuf::from),
+            screenshot: matches.value_of("screenshot").map(PathBuf::from),
+            screenshot_every: matches
+                .value_of("screenshot-every")
+                .map(|v| parse_number(v).unwrap()),
+            screenshot_dir: matches
+                .value_of("screenshot-dir")
+                .map(PathBuf::from),
+            input: matches.value_of("input").map(PathBuf::from),
+            expect: matches.value_of("expect").map(PathBuf::from),
+            vram_dump: matches.value_of("vram-dump").map(PathBuf::from),
+            hash_log: matches.value_of("hash-log").map(PathBuf::from),
+            check_hashes: matches.value_of("check-hashes").map(PathBuf::from),
+            trace: matches.value_of("trace").map(PathBuf::from),
+            profile: matches.is_present("profile"),
+            profile_folded: matches
+                .value_of("profile-folded")
+                .map(PathBuf::from),
+            trace_filter: TraceFilter {
+                range: matches
+                    .value_of("trace-range")
+                    .map(|v| parse_range(v).unwrap()),
+                bank: matches
+                    .value_of("trace-bank")
+                    .map(|v| parse_bank(v).unwrap()),
+            },
+            record: matches.value_of("record").map(PathBuf::from),
+            play: matches.value_of("play").map(PathBuf::from),
+            rewind_interval: parse_number(
+                matches.value_of("rewind-interval").unwrap(),
+            )
+            .unwrap(),
+            rewind_memory: parse_number(
+                matches.value_of("rewind-memory").unwrap(),
+            )
+            .unwrap(),
+            speed: parse_number(matches.value_of("speed").unwrap()).unwrap(),
+            frame_skip: parse_number(matches.value_of("frame-skip").unwrap())
+                .unwrap(),
+            rewind_speed: parse_number(
+                matches.value_of("rewind-speed").unwrap(),
+            )
+            .unwrap(),
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} is not a valid number", value))
+}
+
+/// Parse a ROM location like `1:4000`, the bank being optional for
+/// addresses of the fixed bank
+pub fn parse_location(value: &str) -> Result<(usize, u16), String> {
+    let error = || format!("{} is not a BANK:ADDR location", value);
+    let hex = |v: &str| {
+        let v = v.trim_start_matches('$').trim_start_matches("0x");
+        usize::from_str_radix(v, 16).map_err(|_| error())
+    };
+    let (bank, address) = match value.split_once(':') {
+        Some((bank, address)) => (Some(hex(bank)?), hex(address)?),
+        None => (None, hex(value)?),
+    };
+    let address = u16::try_from(address).map_err(|_| error())?;
+    Ok((bank.unwrap_or((address >= 0x4000) as usize), address))
+}
+
+/// Parse a range of addresses like `C000-C0FF`, in hexadecimal
+fn parse_range(value: &str) -> Result<(u16, u16), String> {
+    let error = || format!("{} is not a START-END range", value);
+    let hex = |v: &str| {
+        let v = v.trim_start_matches('$').trim_start_matches("0x");
+        u16::from_str_radix(v, 16).map_err(|_| error())
+    };
+    let (start, end) = value.split_once('-').ok_or_else(error)?;
+    let (start, end) = (hex(start)?, hex(end)?);
+    if end < start {
+        return Err(format!("{} is an empty range", value));
+    }
+    Ok((start, end))
+}
+
+/// Parse a ROM bank number, in hexadecimal like in locations
+fn parse_bank(value: &str) -> Result<usize, String> {
+    let v = value.trim_start_matches('$').trim_start_matches("0x");
+    usize::from_str_radix(v, 16)
+        .map_err(|_| format!("{} is not a ROM bank", value))
+}
+
+/// Parse which side of a link cable to be, like `listen:127.0.0.1:8765`
+fn parse_link(value: &str) -> Result<Link, String> {
+    let (side, address) = value.split_once(':').unwrap_or((value, ""));
+    match (side, address) {
+        (_, "") => Err(format!("{} has no address", value)),
+        ("listen", _) => Ok(Link::Listen(address.to_string())),
+        ("connect", _) => Ok(Link::Connect(address.to_string())),
+        _ => Err(format!("{} is not listen:ADDR or connect:ADDR", value)),
+    }
+}
+
+fn parse_palette(value: &str) -> Result<Palette, String> {
+    match value {
+        "grey" => return Ok(GREY_PALETTE),
+        "green" => return Ok(GREEN_PALETTE),
+        "pocket" => return Ok(POCKET_PALETTE),
+        _ => {}
+    }
+
+    let colours: Vec<&str> = value.split(',').collect();
+    if colours.len() != 4 {
+        return Err(format!("{} is not a palette", value));
+    }
+    let mut palette = [[0xFF; 4]; 4];
+    for (shade, colour) in palette.iter_mut().zip(colours) {
+        let rgb = u32::from_str_radix(colour.trim_start_matches('#'), 16)
+            .ok()
+            .filter(|_| colour.trim_start_matches('#').len() == 6)
+            .ok_or_else(|| format!("{} is not a RRGGBB colour", colour))?;
+        shade[0] = (rgb >> 16) as u8;
+        shade[1] = (rgb >> 8) as u8;
+        shade[2] = rgb as u8;
+    }
+    Ok(palette)
+}
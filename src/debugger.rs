@@ -0,0 +1,666 @@
+//! Debugger commands: breakpoints, stepping and inspecting or changing the
+//! machine state
+//!
+//! Frontends read command lines, run them with `Debugger::execute` and
+//! show what it returns. Addresses and values are hexadecimal, optionally
+//! prefixed with `$` or `0x`, and breakpoints may be limited to a ROM bank
+//! with `BANK:ADDR`. Labels of the symbols loaded can be used instead of
+//! addresses. Watchpoints are kept by the MMU, which sees every access.
+
+use crate::disasm;
+use crate::emulator::{CpuFlag, Emulator, VmExit};
+use crate::symbols::Symbols;
+use crate::vram;
+use crate::watch::{WatchHit, WatchKind, Watchpoint};
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Help listing every command
+pub const HELP: &str = "\
+s, step [N]            execute N instructions, 1 by default
+n, next                step over calls and RST
+u, until ADDR          run until PC reaches ADDR
+c, continue            run until a breakpoint
+b, break [BANK:]ADDR   stop when PC reaches ADDR, in BANK only if given
+d, delete [BANK:]ADDR  remove a breakpoint
+bl, breakpoints        list breakpoints
+watch ADDR[-END] [KIND] [VAL]
+                       stop on accesses of KIND, write by default, read,
+                       change or access, only of VAL if given
+unwatch ADDR[-END]     remove watchpoints
+watches                list watchpoints
+r, regs                show registers and flags
+x ADDR [LEN]           dump LEN bytes of memory, 64 by default
+l, list [ADDR] [N]     disassemble N instructions from ADDR, PC by default
+set REG VAL            change a register, e.g. `set hl c000`
+w, write ADDR VAL...   write bytes in memory
+oam                    show the objects in OAM
+vram DIR               write the tiles, background maps and objects as PNG
+                       and the OAM table in DIR
+q, quit                stop the emulator
+h, help                show this help
+
+Addresses may also be labels of the symbols loaded, like Main.loop";
+
+/// Where a breakpoint stops
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// ROM bank that must be mapped at `address`, `None` for any
+    pub bank: Option<usize>,
+    pub address: u16,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.address),
+            None => write!(f, "{:04X}", self.address),
+        }
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Breakpoint, String> {
+        match s.split_once(':') {
+            Some((bank, address)) => Ok(Breakpoint {
+                bank: Some(parse_hex(bank)? as usize),
+                address: parse_hex(address)?,
+            }),
+            None => Ok(Breakpoint {
+                bank: None,
+                address: parse_hex(s)?,
+            }),
+        }
+    }
+}
+
+/// What to do after a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Read another command
+    Prompt,
+
+    /// Run until the next breakpoint
+    Continue,
+
+    /// Stop the emulator
+    Quit,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+
+    /// Address to stop at once, for `next` and `until`
+    target: Option<u16>,
+
+    /// Stop before the next instruction
+    interrupt: bool,
+
+    /// Why execution stopped, when not obvious from where
+    report: Option<String>,
+
+    /// Watchpoint execution stopped on
+    watch_hit: Option<WatchHit>,
+
+    symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Default::default()
+    }
+
+    /// Stop before the next instruction
+    pub fn interrupt(&mut self) {
+        self.interrupt = true;
+    }
+
+    /// Why execution last stopped, if not because of a breakpoint
+    pub fn take_report(&mut self) -> Option<String> {
+        self.report.take()
+    }
+
+    /// Use `symbols` for labels in addresses and output
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Watchpoint execution last stopped on, if any
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Remove a breakpoint, returning whether there was one
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|&b| b != breakpoint);
+        self.breakpoints.len() != count
+    }
+
+    /// Whether execution must stop before the instruction at PC
+    fn should_stop(&self, emulator: &Emulator) -> bool {
+        let pc = emulator.registers().pc;
+        let bank = emulator.memory.rom_bank(pc);
+        self.interrupt
+            || self.target == Some(pc)
+            || self.breakpoints.iter().any(|breakpoint| {
+                breakpoint.address == pc
+                    && (breakpoint.bank.is_none() || breakpoint.bank == bank)
+            })
+    }
+
+    /// Run until the GPU completes a frame, calling `on_break` whenever
+    /// execution stops. Returns false when `on_break` asks to quit.
+    pub fn run_frame<F>(
+        &mut self,
+        emulator: &mut Emulator,
+        mut on_break: F,
+    ) -> Result<bool, VmExit>
+    where
+        F: FnMut(&mut Debugger, &mut Emulator) -> Flow,
+    {
+        let frame = emulator.memory.gpu.frame_count();
+        while emulator.memory.gpu.frame_count() == frame {
+            if self.should_stop(emulator) {
+                self.interrupt = false;
+                self.target = None;
+                match on_break(self, emulator) {
+                    Flow::Quit => return Ok(false),
+                    // The prompt may have run the frame to its end
+                    _ if emulator.memory.gpu.frame_count() != frame => break,
+                    _ => {}
+                }
+            }
+            self.step(emulator)?;
+        }
+        Ok(true)
+    }
+
+    /// Run the command `line`, returning what to do next and what to show
+    pub fn execute(
+        &mut self,
+        emulator: &mut Emulator,
+        line: &str,
+    ) -> Result<(Flow, String), String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok((Flow::Prompt, String::new())),
+        };
+        let args: Vec<&str> = words.collect();
+        let arg = |i: usize| {
+            args.get(i)
+                .copied()
+                .ok_or_else(|| format!("{} needs more arguments", command))
+        };
+
+        let output = match command {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| format!("{} is not a count", count))?,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    self.step(emulator)
+                        .map_err(|e| self.stopped(emulator, e))?;
+                    if self.interrupt {
+                        break;
+                    }
+                }
+                self.interrupt = false;
+                match self.report.take() {
+                    Some(report) => {
+                        format!("{}\n{}", report, self.location(emulator))
+                    }
+                    None => self.location(emulator),
+                }
+            }
+            "n" | "next" => {
+                let pc = emulator.registers().pc;
+                let instruction = disassemble(emulator, pc);
+                let text = &instruction.text;
+                if text.starts_with("call") || text.starts_with("rst") {
+                    self.target = Some(pc.wrapping_add(instruction.length));
+                    return Ok((Flow::Continue, String::new()));
+                }
+                self.step(emulator).map_err(|e| self.stopped(emulator, e))?;
+                self.interrupt = false;
+                match self.report.take() {
+                    Some(report) => {
+                        format!("{}\n{}", report, self.location(emulator))
+                    }
+                    None => self.location(emulator),
+                }
+            }
+            "u" | "until" => {
+                self.target = Some(self.parse_address(arg(0)?)?);
+                return Ok((Flow::Continue, String::new()));
+            }
+            "c" | "continue" => return Ok((Flow::Continue, String::new())),
+            "b" | "break" => {
+                let breakpoint = self.parse_breakpoint(arg(0)?)?;
+                self.add_breakpoint(breakpoint);
+                format!("Breakpoint at {}", self.describe(breakpoint))
+            }
+            "d" | "delete" => {
+                let breakpoint = self.parse_breakpoint(arg(0)?)?;
+                if !self.remove_breakpoint(breakpoint) {
+                    return Err(format!("no breakpoint at {}", breakpoint));
+                }
+                format!("Deleted breakpoint at {}", breakpoint)
+            }
+            "bl" | "breakpoints" => match self.breakpoints.len() {
+                0 => "No breakpoints".to_string(),
+                _ => self
+                    .breakpoints
+                    .iter()
+                    .map(|&breakpoint| self.describe(breakpoint))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            },
+            "watch" => {
+                let (start, end) = self.parse_range(arg(0)?)?;
+                let kind = match args.get(1).copied() {
+                    None | Some("write") => WatchKind::Write,
+                    Some("read") => WatchKind::Read,
+                    Some("change") => WatchKind::Change,
+                    Some("access") => WatchKind::Access,
+                    Some(kind) => {
+                        return Err(format!("unknown watchpoint kind {}", kind))
+                    }
+                };
+                let value = match args.get(2) {
+                    Some(value) => Some(
+                        u8::try_from(parse_hex(value)?)
+                            .map_err(|_| format!("{} is not a byte", value))?,
+                    ),
+                    None => None,
+                };
+                let watchpoint = Watchpoint {
+                    start,
+                    end,
+                    kind,
+                    value,
+                };
+                emulator.memory.add_watchpoint(watchpoint);
+                format!("Watchpoint at {}", watchpoint)
+            }
+            "unwatch" => {
+                let (start, end) = self.parse_range(arg(0)?)?;
+                if !emulator.memory.remove_watchpoints(start, end) {
+                    return Err(format!("no watchpoint at {}", arg(0)?));
+                }
+                format!("Deleted watchpoints at {}", arg(0)?)
+            }
+            "watches" => match emulator.memory.watchpoints() {
+                [] => "No watchpoints".to_string(),
+                watchpoints => watchpoints
+                    .iter()
+                    .map(|watchpoint| watchpoint.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            },
+            "r" | "regs" => registers(emulator),
+            "x" => {
+                let address = self.parse_address(arg(0)?)?;
+                let length = match args.get(1) {
+                    Some(length) => parse_hex(length)?,
+                    None => 0x40,
+                };
+                dump(emulator, address, length)
+            }
+            "l" | "list" => {
+                let mut address = match args.first() {
+                    Some(address) => self.parse_address(address)?,
+                    None => emulator.registers().pc,
+                };
+                let count = match args.get(1) {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| format!("{} is not a count", count))?,
+                    None => 8,
+                };
+                let mut lines = Vec::new();
+                for _ in 0..count {
+                    let instruction = disassemble(emulator, address);
+                    let bank = emulator.memory.rom_bank(address);
+                    if let Some(symbol) = self.symbols.exact(bank, address) {
+                        lines.push(format!("{}:", symbol.name));
+                    }
+                    let text = self.annotate(emulator, &instruction);
+                    lines.push(format!("{:04X}  {}", address, text));
+                    address = address.wrapping_add(instruction.length);
+                }
+                lines.join("\n")
+            }
+            "set" => {
+                set_register(emulator, arg(0)?, parse_hex(arg(1)?)?)?;
+                registers(emulator)
+            }
+            "w" | "write" => {
+                let address = self.parse_address(arg(0)?)?;
+                arg(1)?;
+                for (i, val) in args[1..].iter().enumerate() {
+                    let val = u8::try_from(parse_hex(val)?)
+                        .map_err(|_| format!("{} is not a byte", val))?;
+                    emulator
+                        .write_memory(address.wrapping_add(i as u16), val)
+                        .map_err(|e| e.to_string())?;
+                }
+                dump(emulator, address, args.len() as u16 - 1)
+            }
+            "oam" => vram::oam_table(&emulator.memory.gpu),
+            "vram" => {
+                let dir = Path::new(arg(0)?);
+                vram::dump(&emulator.memory.gpu, dir).map_err(|e| {
+                    format!("can't write {}: {}", dir.display(), e)
+                })?;
+                format!("Wrote VRAM to {}", dir.display())
+            }
+            "q" | "quit" => return Ok((Flow::Quit, String::new())),
+            "h" | "help" => HELP.to_string(),
+            _ => return Err(format!("unknown command {}, try help", command)),
+        };
+        Ok((Flow::Prompt, output))
+    }
+
+    /// Execute an instruction, planning to stop after it when it hits a
+    /// watchpoint
+    fn step(&mut self, emulator: &mut Emulator) -> Result<(), VmExit> {
+        let pc = emulator.registers().pc;
+        // Forget accesses made outside of instructions, by the prompt
+        // writing memory for instance
+        emulator.memory.take_watch_hit();
+        emulator.step_instruction()?;
+        if let Some(hit) = emulator.memory.take_watch_hit() {
+            self.report = Some(format!("{} at PC {:04X}", hit, pc));
+            self.watch_hit = Some(hit);
+            self.interrupt = true;
+        }
+        Ok(())
+    }
+
+    /// Where execution stopped: the bank, PC, its label and the next
+    /// instruction
+    pub fn location(&self, emulator: &mut Emulator) -> String {
+        let pc = emulator.registers().pc;
+        let instruction = disassemble(emulator, pc);
+        let text = self.annotate(emulator, &instruction);
+        let label = match self.symbols.label_at(emulator, pc) {
+            Some(label) => format!(" <{}>", label),
+            None => String::new(),
+        };
+        match emulator.memory.rom_bank(pc) {
+            Some(bank) => format!("{:02X}:{:04X}{}  {}", bank, pc, label, text),
+            None => format!("{:04X}{}  {}", pc, label, text),
+        }
+    }
+
+    fn stopped(&self, emulator: &Emulator, e: VmExit) -> String {
+        format!(
+            "Emulator stopped: {}",
+            self.symbols.exit_report(emulator, &e)
+        )
+    }
+
+    /// Text of `instruction` with the symbol its operand points to
+    fn annotate(
+        &self,
+        emulator: &Emulator,
+        instruction: &disasm::Instruction,
+    ) -> String {
+        let bank = emulator.memory.rom_bank(0x4000);
+        self.symbols.annotate(instruction, bank)
+    }
+
+    /// Breakpoint with the label of its address
+    fn describe(&self, breakpoint: Breakpoint) -> String {
+        let label = self.symbols.label(breakpoint.bank, breakpoint.address);
+        match label {
+            Some(label) => format!("{} <{}>", breakpoint, label),
+            None => breakpoint.to_string(),
+        }
+    }
+
+    /// Parse a label or a hexadecimal address
+    fn parse_address(&self, value: &str) -> Result<u16, String> {
+        match self.symbols.find(value) {
+            Some(symbol) => Ok(symbol.address),
+            None => parse_hex(value),
+        }
+    }
+
+    /// Parse a label or a range of addresses
+    fn parse_range(&self, value: &str) -> Result<(u16, u16), String> {
+        match self.symbols.find(value) {
+            Some(symbol) => Ok((symbol.address, symbol.address)),
+            None => parse_range(value),
+        }
+    }
+
+    /// Parse a label, stopping in its bank only in the switchable one, or
+    /// a `[BANK:]ADDR` breakpoint
+    fn parse_breakpoint(&self, value: &str) -> Result<Breakpoint, String> {
+        match self.symbols.find(value) {
+            Some(symbol) => Ok(Breakpoint {
+                bank: match symbol.address {
+                    0x4000..=0x7FFF => Some(symbol.bank),
+                    _ => None,
+                },
+                address: symbol.address,
+            }),
+            None => value.parse(),
+        }
+    }
+}
+
+fn disassemble(emulator: &mut Emulator, address: u16) -> disasm::Instruction {
+    disasm::decode(|at| emulator.peek_memory(at).unwrap_or(0xFF), address)
+}
+
+fn registers(emulator: &Emulator) -> String {
+    let regs = emulator.registers();
+    let flag = |flag, name| if regs.flag(flag) { name } else { '-' };
+    format!(
+        "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X}\n\
+         Flags={}{}{}{} IME={} HALT={}",
+        regs.af(),
+        regs.bc(),
+        regs.de(),
+        regs.hl(),
+        regs.sp,
+        regs.pc,
+        flag(CpuFlag::Z, 'Z'),
+        flag(CpuFlag::N, 'N'),
+        flag(CpuFlag::H, 'H'),
+        flag(CpuFlag::C, 'C'),
+        emulator.ime() as u8,
+        emulator.halted() as u8,
+    )
+}
+
+/// Hexadecimal dump of `length` bytes from `address`, 16 per line
+fn dump(emulator: &mut Emulator, address: u16, length: u16) -> String {
+    let mut lines = Vec::new();
+    for start in (0..length).step_by(16) {
+        let line_address = address.wrapping_add(start);
+        let bytes: Vec<String> = (start..length.min(start.saturating_add(16)))
+            .map(|i| {
+                let val = emulator
+                    .peek_memory(address.wrapping_add(i))
+                    .unwrap_or(0xFF);
+                format!("{:02X}", val)
+            })
+            .collect();
+        lines.push(format!("{:04X}  {}", line_address, bytes.join(" ")));
+    }
+    lines.join("\n")
+}
+
+fn set_register(
+    emulator: &mut Emulator,
+    name: &str,
+    val: u16,
+) -> Result<(), String> {
+    let regs = emulator.registers_mut();
+    let byte =
+        || u8::try_from(val).map_err(|_| format!("{:X} is not a byte", val));
+    match name.to_ascii_lowercase().as_str() {
+        "a" => regs.a = byte()?,
+        "f" => regs.f = byte()? & 0xF0,
+        "b" => regs.b = byte()?,
+        "c" => regs.c = byte()?,
+        "d" => regs.d = byte()?,
+        "e" => regs.e = byte()?,
+        "h" => regs.h = byte()?,
+        "l" => regs.l = byte()?,
+        "af" => regs.set_af(val),
+        "bc" => regs.set_bc(val),
+        "de" => regs.set_de(val),
+        "hl" => regs.set_hl(val),
+        "sp" => regs.sp = val,
+        "pc" => regs.pc = val,
+        _ => return Err(format!("unknown register {}", name)),
+    }
+    Ok(())
+}
+
+/// Parse an address or a range of addresses like `C000-C0FF`
+fn parse_range(value: &str) -> Result<(u16, u16), String> {
+    match value.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse_hex(start)?, parse_hex(end)?);
+            if end < start {
+                return Err(format!("{} is an empty range", value));
+            }
+            Ok((start, end))
+        }
+        None => parse_hex(value).map(|address| (address, address)),
+    }
+}
+
+/// Parse a 16-bit hexadecimal number, optionally prefixed with `$` or `0x`
+fn parse_hex(value: &str) -> Result<u16, String> {
+    let digits = value.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16)
+        .map_err(|_| format!("{} is not a hexadecimal number", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Access;
+
+    #[test]
+    fn next_over_rst() {
+        let mut emulator = Emulator::with_code(&[
+            (0x08, &[0xC9]),  // RET
+            (0x100, &[0xCF]), // RST 0x08
+        ]);
+        let mut debugger = Debugger::new();
+        let (flow, _) = debugger.execute(&mut emulator, "n").unwrap();
+        assert!(matches!(flow, Flow::Continue));
+        let running = debugger.run_frame(&mut emulator, |_, _| Flow::Quit);
+        assert!(!running.unwrap());
+        assert_eq!(emulator.registers().pc, 0x101);
+    }
+
+    #[test]
+    fn prompt_reads_skip_watchpoints() {
+        let mut emulator = Emulator::with_program(&[
+            0x00, // NOP
+            0xFA, 0x00, 0xC0, // LD A, (0xC000)
+        ]);
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut emulator, "watch c000 read").unwrap();
+        debugger.execute(&mut emulator, "x c000 4").unwrap();
+        debugger.execute(&mut emulator, "l c000 2").unwrap();
+
+        debugger.step(&mut emulator).unwrap();
+        assert_eq!(debugger.take_watch_hit(), None);
+        debugger.step(&mut emulator).unwrap();
+        let hit = debugger.take_watch_hit().unwrap();
+        assert_eq!((hit.address, hit.access), (0xC000, Access::Read));
+    }
+
+    #[test]
+    fn dispatch_skips_watchpoints() {
+        let mut emulator = Emulator::with_program(&[
+            0xFB, // EI
+            0x00, // NOP
+            0x00, // NOP
+        ]);
+        emulator.write_memory(0xFFFF, 0x01).unwrap();
+        emulator.write_memory(0xFF0F, 0x01).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut emulator, "watch ff0f read").unwrap();
+        debugger.execute(&mut emulator, "watch ff0f write").unwrap();
+        debugger.execute(&mut emulator, "watch ffff read").unwrap();
+
+        for _ in 0..4 {
+            debugger.step(&mut emulator).unwrap();
+            assert_eq!(debugger.take_watch_hit(), None);
+        }
+        assert!((0x40..0x48).contains(&emulator.registers().pc));
+    }
+
+    #[test]
+    fn oam_dma_writes_watched() {
+        let mut emulator = Emulator::with_program(&[
+            0x3E, 0xC0, // LD A, 0xC0
+            0xE0, 0x46, // LDH (DMA), A
+        ]);
+        emulator.write_memory(0xC005, 0x42).unwrap();
+        let mut debugger = Debugger::new();
+        debugger
+            .execute(&mut emulator, "watch c000-c09f read")
+            .unwrap();
+        debugger
+            .execute(&mut emulator, "watch fe00-fe9f change")
+            .unwrap();
+
+        debugger.step(&mut emulator).unwrap();
+        assert_eq!(debugger.take_watch_hit(), None);
+        debugger.step(&mut emulator).unwrap();
+        let hit = debugger.take_watch_hit().unwrap();
+        assert_eq!(hit.address, 0xFE05);
+        assert_eq!((hit.access, hit.old, hit.new), (Access::Write, 0, 0x42));
+    }
+
+    #[test]
+    fn change_of_stored_value() {
+        let mut emulator = Emulator::with_program(&[
+            0x3E, 0x00, // LD A, 0x00
+            0xE0, 0x02, // LDH (SC), A
+            0x3E, 0x80, // LD A, 0x80
+            0xE0, 0x02, // LDH (SC), A
+        ]);
+        let mut debugger = Debugger::new();
+        debugger
+            .execute(&mut emulator, "watch ff02 change")
+            .unwrap();
+
+        // The unused bits of SC read as ones whatever is written
+        for _ in 0..3 {
+            debugger.step(&mut emulator).unwrap();
+            assert_eq!(debugger.take_watch_hit(), None);
+        }
+        debugger.step(&mut emulator).unwrap();
+        let hit = debugger.take_watch_hit().unwrap();
+        assert_eq!((hit.old, hit.new), (0x7E, 0x80));
+    }
+}
@@ -0,0 +1,309 @@
+//! SM83 disassembler, writing instructions in RGBDS syntax with their
+//! operands resolved, relative jumps to their target and `LDH` addresses
+//! to the full 16-bit one
+
+use std::fmt;
+
+/// Operands encoded in bits 0-2 or 3-5 of opcodes, and of CB-prefixed ones
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+
+/// Register pairs of 16-bit loads and arithmetic
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+
+/// Register pairs of PUSH and POP
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+
+/// Memory operands of `ld [r16], a` and `ld a, [r16]`
+const R16_MEMORY: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+
+/// Arithmetic and logic operations encoded in bits 3-5
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+
+/// Accumulator operations of opcodes 0x07 to 0x3F, in steps of 8
+const ACCUMULATOR: [&str; 8] =
+    ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+/// Rotations and shifts of CB-prefixed opcodes 0x00 to 0x3F
+const SHIFTS: [&str; 8] =
+    ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+/// Bit operations of CB-prefixed opcodes 0x40 to 0xFF
+const BIT_OPERATIONS: [&str; 3] = ["bit", "res", "set"];
+
+/// A decoded instruction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    /// Length in bytes, operands included
+    pub length: u16,
+
+    /// Mnemonic and operands, in RGBDS syntax
+    pub text: String,
+
+    /// Address operand of jumps, calls and memory accesses, as written in
+    /// `text`
+    pub operand: Option<u16>,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Decode the instruction at `address`, reading memory with `read`
+pub fn decode<F: FnMut(u16) -> u8>(mut read: F, address: u16) -> Instruction {
+    let opcode = read(address);
+    let n8 = read(address.wrapping_add(1));
+    let n16 = u16::from_le_bytes([n8, read(address.wrapping_add(2))]);
+    let e8 = n8 as i8;
+
+    let x = opcode >> 6;
+    let y = (opcode >> 3 & 0x07) as usize;
+    let z = (opcode & 0x07) as usize;
+    let p = y >> 1;
+
+    let (length, text) = match (x, z) {
+        _ if opcode == 0xCB => (2, decode_cb(n8)),
+
+        (0, 0) => match y {
+            0 => (1, "nop".to_string()),
+            1 => (3, format!("ld [${:04X}], sp", n16)),
+            2 => (2, "stop".to_string()),
+            3 => (2, format!("jr ${:04X}", relative(address, e8))),
+            _ => (
+                2,
+                format!(
+                    "jr {}, ${:04X}",
+                    CONDITIONS[y - 4],
+                    relative(address, e8)
+                ),
+            ),
+        },
+        (0, 1) if y & 1 == 0 => (3, format!("ld {}, ${:04X}", R16[p], n16)),
+        (0, 1) => (1, format!("add hl, {}", R16[p])),
+        (0, 2) if y & 1 == 0 => (1, format!("ld {}, a", R16_MEMORY[p])),
+        (0, 2) => (1, format!("ld a, {}", R16_MEMORY[p])),
+        (0, 3) if y & 1 == 0 => (1, format!("inc {}", R16[p])),
+        (0, 3) => (1, format!("dec {}", R16[p])),
+        (0, 4) => (1, format!("inc {}", R8[y])),
+        (0, 5) => (1, format!("dec {}", R8[y])),
+        (0, 6) => (2, format!("ld {}, ${:02X}", R8[y], n8)),
+        (0, _) => (1, ACCUMULATOR[y].to_string()),
+
+        (1, _) if opcode == 0x76 => (1, "halt".to_string()),
+        (1, _) => (1, format!("ld {}, {}", R8[y], R8[z])),
+
+        (2, _) => (1, format!("{} a, {}", ALU[y], R8[z])),
+
+        (3, 0) if y < 4 => (1, format!("ret {}", CONDITIONS[y])),
+        (3, 0) => match y {
+            4 => (2, format!("ldh [${:04X}], a", 0xFF00 | n8 as u16)),
+            5 => (2, format!("add sp, {}", e8)),
+            6 => (2, format!("ldh a, [${:04X}]", 0xFF00 | n8 as u16)),
+            _ => (2, format!("ld hl, sp {} {}", sign(e8), e8.unsigned_abs())),
+        },
+        (3, 1) if y & 1 == 0 => (1, format!("pop {}", R16_STACK[p])),
+        (3, 1) => match p {
+            0 => (1, "ret".to_string()),
+            1 => (1, "reti".to_string()),
+            2 => (1, "jp hl".to_string()),
+            _ => (1, "ld sp, hl".to_string()),
+        },
+        (3, 2) if y < 4 => (3, format!("jp {}, ${:04X}", CONDITIONS[y], n16)),
+        (3, 2) => match y {
+            4 => (1, "ldh [c], a".to_string()),
+            5 => (3, format!("ld [${:04X}], a", n16)),
+            6 => (1, "ldh a, [c]".to_string()),
+            _ => (3, format!("ld a, [${:04X}]", n16)),
+        },
+        (3, 3) => match y {
+            0 => (3, format!("jp ${:04X}", n16)),
+            6 => (1, "di".to_string()),
+            7 => (1, "ei".to_string()),
+            _ => invalid(opcode),
+        },
+        (3, 4) if y < 4 => (3, format!("call {}, ${:04X}", CONDITIONS[y], n16)),
+        (3, 5) if y & 1 == 0 => (1, format!("push {}", R16_STACK[p])),
+        (3, 5) if y == 1 => (3, format!("call ${:04X}", n16)),
+        (3, 6) => (2, format!("{} a, ${:02X}", ALU[y], n8)),
+        (3, 7) => (1, format!("rst ${:02X}", y * 8)),
+        _ => invalid(opcode),
+    };
+    let operand = match opcode {
+        0x08 | 0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | 0xD2 | 0xD4 | 0xDA
+        | 0xDC | 0xEA | 0xFA => Some(n16),
+        0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Some(relative(address, e8)),
+        0xE0 | 0xF0 => Some(0xFF00 | n8 as u16),
+        _ => None,
+    };
+    Instruction {
+        length,
+        text,
+        operand,
+    }
+}
+
+/// Decode the instruction at the start of `code`, which is at `address`.
+/// Bytes past the end of `code` read as 0.
+pub fn decode_bytes(code: &[u8], address: u16) -> Instruction {
+    decode(
+        |at| {
+            let offset = at.wrapping_sub(address) as usize;
+            code.get(offset).copied().unwrap_or(0)
+        },
+        address,
+    )
+}
+
+fn decode_cb(opcode: u8) -> String {
+    let operand = R8[(opcode & 0x07) as usize];
+    let y = opcode >> 3 & 0x07;
+    match opcode >> 6 {
+        0 => format!("{} {}", SHIFTS[y as usize], operand),
+        x => format!("{} {}, {}", BIT_OPERATIONS[x as usize - 1], y, operand),
+    }
+}
+
+/// Opcodes the CPU doesn't know, shown as data
+fn invalid(opcode: u8) -> (u16, String) {
+    (1, format!("db ${:02X}", opcode))
+}
+
+/// Target of a two bytes relative jump at `address`
+fn relative(address: u16, offset: i8) -> u16 {
+    address.wrapping_add(2).wrapping_add(offset as u16)
+}
+
+fn sign(val: i8) -> char {
+    if val < 0 {
+        '-'
+    } else {
+        '+'
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding() {
+        for &(code, address, text, length) in &[
+            (&[0x00][..], 0x0100, "nop", 1),
+            (&[0x08, 0x34, 0x12], 0x0100, "ld [$1234], sp", 3),
+            (&[0x10, 0x00], 0x0100, "stop", 2),
+            (&[0x01, 0x34, 0x12], 0x0100, "ld bc, $1234", 3),
+            (&[0x31, 0xFE, 0xFF], 0x0100, "ld sp, $FFFE", 3),
+            (&[0x29], 0x0100, "add hl, hl", 1),
+            (&[0x22], 0x0100, "ld [hl+], a", 1),
+            (&[0x3A], 0x0100, "ld a, [hl-]", 1),
+            (&[0x13], 0x0100, "inc de", 1),
+            (&[0x3B], 0x0100, "dec sp", 1),
+            (&[0x34], 0x0100, "inc [hl]", 1),
+            (&[0x3D], 0x0100, "dec a", 1),
+            (&[0x36, 0x42], 0x0100, "ld [hl], $42", 2),
+            (&[0x27], 0x0100, "daa", 1),
+            (&[0x3F], 0x0100, "ccf", 1),
+            (&[0x76], 0x0100, "halt", 1),
+            (&[0x78], 0x0100, "ld a, b", 1),
+            (&[0x9E], 0x0100, "sbc a, [hl]", 1),
+            (&[0xBF], 0x0100, "cp a, a", 1),
+            (&[0xD8], 0x0100, "ret c", 1),
+            (&[0xF1], 0x0100, "pop af", 1),
+            (&[0xD9], 0x0100, "reti", 1),
+            (&[0xE9], 0x0100, "jp hl", 1),
+            (&[0xF9], 0x0100, "ld sp, hl", 1),
+            (&[0xC2, 0x00, 0x40], 0x0100, "jp nz, $4000", 3),
+            (&[0xC3, 0x50, 0x01], 0x0100, "jp $0150", 3),
+            (&[0xDC, 0x00, 0x40], 0x0100, "call c, $4000", 3),
+            (&[0xCD, 0x00, 0x40], 0x0100, "call $4000", 3),
+            (&[0xC5], 0x0100, "push bc", 1),
+            (&[0xE6, 0x0F], 0x0100, "and a, $0F", 2),
+            (&[0xFF], 0x0100, "rst $38", 1),
+            (&[0xF3], 0x0100, "di", 1),
+            (&[0xFB], 0x0100, "ei", 1),
+            // High page and 16-bit accesses
+            (&[0xE0, 0x40], 0x0100, "ldh [$FF40], a", 2),
+            (&[0xF0, 0x44], 0x0100, "ldh a, [$FF44]", 2),
+            (&[0xE2], 0x0100, "ldh [c], a", 1),
+            (&[0xF2], 0x0100, "ldh a, [c]", 1),
+            (&[0xEA, 0x00, 0xC0], 0x0100, "ld [$C000], a", 3),
+            (&[0xFA, 0x00, 0xC0], 0x0100, "ld a, [$C000]", 3),
+            // Stack pointer offsets
+            (&[0xE8, 0xFB], 0x0100, "add sp, -5", 2),
+            (&[0xE8, 0x05], 0x0100, "add sp, 5", 2),
+            (&[0xF8, 0xFB], 0x0100, "ld hl, sp - 5", 2),
+            (&[0xF8, 0x80], 0x0100, "ld hl, sp - 128", 2),
+            (&[0xF8, 0x7F], 0x0100, "ld hl, sp + 127", 2),
+            // Relative jumps, wrapping around the address space
+            (&[0x18, 0xFE], 0x0100, "jr $0100", 2),
+            (&[0x20, 0x10], 0x0100, "jr nz, $0112", 2),
+            (&[0x38, 0xFB], 0x0000, "jr c, $FFFD", 2),
+            (&[0x18, 0x05], 0xFFFE, "jr $0005", 2),
+            // CB-prefixed operations
+            (&[0xCB, 0x00], 0x0100, "rlc b", 2),
+            (&[0xCB, 0x1E], 0x0100, "rr [hl]", 2),
+            (&[0xCB, 0x37], 0x0100, "swap a", 2),
+            (&[0xCB, 0x3F], 0x0100, "srl a", 2),
+            (&[0xCB, 0x7C], 0x0100, "bit 7, h", 2),
+            (&[0xCB, 0x86], 0x0100, "res 0, [hl]", 2),
+            (&[0xCB, 0xFF], 0x0100, "set 7, a", 2),
+        ] {
+            let instruction = decode_bytes(code, address);
+            assert_eq!(instruction.text, text, "{:02X?}", code);
+            assert_eq!(instruction.length, length, "{:02X?}", code);
+        }
+    }
+
+    #[test]
+    fn illegal_opcodes() {
+        for &opcode in &[
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ] {
+            let instruction = decode_bytes(&[opcode, 0x12, 0x34], 0x0100);
+            assert_eq!(instruction.text, format!("db ${:02X}", opcode));
+            assert_eq!(instruction.length, 1);
+            assert_eq!(instruction.operand, None);
+        }
+    }
+
+    #[test]
+    fn operands() {
+        for &(code, address, operand) in &[
+            (&[0xC3, 0x50, 0x01][..], 0x0100, Some(0x0150)),
+            (&[0xCA, 0x00, 0x40], 0x0100, Some(0x4000)),
+            (&[0xCD, 0x34, 0x12], 0x0100, Some(0x1234)),
+            (&[0xD4, 0x34, 0x12], 0x0100, Some(0x1234)),
+            (&[0x08, 0x00, 0xC0], 0x0100, Some(0xC000)),
+            (&[0xFA, 0x00, 0xC0], 0x0100, Some(0xC000)),
+            (&[0x18, 0xFE], 0x0150, Some(0x0150)),
+            (&[0x30, 0x80], 0x0000, Some(0xFF82)),
+            (&[0xE0, 0x80], 0x0100, Some(0xFF80)),
+            (&[0xF0, 0x00], 0x0100, Some(0xFF00)),
+            (&[0x21, 0x00, 0xC0], 0x0100, None),
+            (&[0xE9], 0x0100, None),
+            (&[0xC9], 0x0100, None),
+            (&[0xCB, 0xCD], 0x0100, None),
+        ] {
+            let instruction = decode_bytes(code, address);
+            assert_eq!(instruction.operand, operand, "{:02X?}", code);
+        }
+    }
+
+    #[test]
+    fn reads_through_the_address_space() {
+        let instruction = decode(
+            |address| match address {
+                0xFFFF => 0xCD,
+                0x0000 => 0x34,
+                0x0001 => 0x12,
+                _ => 0x00,
+            },
+            0xFFFF,
+        );
+        assert_eq!(instruction.text, "call $1234");
+        assert_eq!(instruction.operand, Some(0x1234));
+    }
+}
@@ -1,15 +1,11 @@
 use std::fmt;
 
+use crate::boot;
+use crate::bus::Bus;
+use crate::joypad::Buttons;
 use crate::mmu::Mmu;
-
-/// Function that makes a closure use same lifetime elision rules as a function
-/// cf. https://users.rust-lang.org/t/unhelpful-mismatched-types-error-message/48394/2
-fn identity<T, U, F>(f: F) -> F
-where
-    F: FnMut(&mut T) -> &mut U,
-{
-    f
-}
+use crate::model::Model;
+use crate::state::{self, StateError, StateReader, StateWriter};
 
 pub enum CpuFlag {
     C = 0b00010000,
@@ -18,18 +14,18 @@ pub enum CpuFlag {
     Z = 0b10000000,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct Registers {
-    a: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-    f: u8,
-    h: u8,
-    l: u8,
-    sp: u16,
-    pc: u16,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
 }
 
 impl Registers {
@@ -87,10 +83,7 @@ impl Registers {
     }
 
     pub fn flag(&self, flag: CpuFlag) -> bool {
-        match self.f & flag as u8 {
-            0 => false,
-            _ => true,
-        }
+        self.f & flag as u8 != 0
     }
 
     pub fn clear_flags(&mut self) {
@@ -98,15 +91,47 @@ impl Registers {
     }
 }
 
-pub struct Emulator {
+pub struct Emulator<B: Bus = Mmu> {
     /// Memory
-    pub memory: Mmu,
+    pub memory: B,
 
     /// All SM83 registers
     regs: Registers,
+
+    /// Interrupt master enable flag
+    ime: bool,
+
+    /// Instructions left before EI takes effect
+    ei_delay: u8,
+
+    /// Whether the CPU is waiting for an interrupt after a HALT
+    halted: bool,
+
+    /// Whether PC fails to move past the next opcode, after a HALT with an
+    /// interrupt pending but disabled
+    halt_bug: bool,
+
+    /// Model chosen at construction, `None` to pick it from the cartridge
+    model: Option<Model>,
+}
+
+/// Address of the IF - Interrupt Flag register
+const IF_ADDRESS: u16 = 0xFF0F;
+
+/// Address of the IE - Interrupt Enable register
+const IE_ADDRESS: u16 = 0xFFFF;
+
+/// Kind of memory access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
 }
 
 /// Reasons why the VM exited
+///
+/// Exits raised by the memory bus do not know which instruction was running,
+/// their `pc` is filled in by the CPU before the exit is returned.
 #[derive(Debug)]
 pub enum VmExit {
     /// VM exited cleanly
@@ -115,928 +140,1313 @@ pub enum VmExit {
     /// VM exited after a STOP instruction
     Stop,
 
-    /// VM exited after a HALT instruction
+    /// VM exited after a HALT instruction nothing can wake up from
     Halt,
 
     /// VM exited after an out of bounds read
     OobRead,
 
-    /// VM exited after SP overflowed
-    SpOverflow,
+    /// VM exited after fetching an opcode that does not exist on the SM83
+    IllegalOpcode { pc: u16, opcode: u8 },
+
+    /// VM exited after fetching an opcode that is not emulated yet,
+    /// CB-prefixed opcodes are reported as 0xCBxx
+    UnimplementedOpcode { pc: u16, opcode: u16 },
+}
+
+impl VmExit {
+    /// Attach the address of the instruction that caused the exit
+    pub fn with_pc(mut self, instr_pc: u16) -> VmExit {
+        match &mut self {
+            VmExit::IllegalOpcode { pc, .. }
+            | VmExit::UnimplementedOpcode { pc, .. } => *pc = instr_pc,
+            _ => (),
+        }
+        self
+    }
+
+    /// Address of the instruction that caused the exit, if known
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            VmExit::IllegalOpcode { pc, .. }
+            | VmExit::UnimplementedOpcode { pc, .. } => Some(pc),
+            _ => None,
+        }
+    }
 }
 
-impl fmt::Display for Emulator {
+impl fmt::Display for VmExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmExit::Exit => write!(f, "exited"),
+            VmExit::Stop => write!(f, "stopped by a STOP instruction"),
+            VmExit::Halt => write!(f, "halted with no interrupt enabled"),
+            VmExit::OobRead => write!(f, "out of bounds read"),
+            VmExit::IllegalOpcode { pc, opcode } => {
+                write!(f, "illegal opcode 0x{:02x} at PC 0x{:04x}", opcode, pc)
+            }
+            VmExit::UnimplementedOpcode { pc, opcode } => write!(
+                f,
+                "unimplemented opcode 0x{:02x} at PC 0x{:04x}",
+                opcode, pc
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VmExit {}
+
+impl<B: Bus> fmt::Display for Emulator<B> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:#x?}", self.regs)
     }
 }
 
 impl Emulator {
+    /// Create an emulator behaving like the model each cartridge is made for
     pub fn new() -> Emulator {
+        Emulator::with_bus(Mmu::new())
+    }
+
+    /// Create an emulator behaving like `model` whatever the cartridge
+    pub fn with_model(model: Model) -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.model = Some(model);
+        emulator.memory.set_model(model);
+        emulator
+    }
+
+    /// Hardware model currently emulated
+    pub fn model(&self) -> Model {
+        self.memory.model()
+    }
+
+    /// Behave like `model` from the next reset on, or like the cartridge
+    /// header says with `None`
+    pub fn set_model(&mut self, model: Option<Model>) {
+        self.model = model;
+    }
+
+    /// Insert the cartridge whose content is `rom` and power on
+    pub fn load_rom_from_bytes(&mut self, rom: Vec<u8>) {
+        self.memory.load_rom_from_bytes(rom);
+        self.reset();
+    }
+
+    /// Use `bootrom` to boot the cartridge instead of starting at 0x0100
+    /// with the state the boot ROM would leave, and power on
+    pub fn load_bootrom(&mut self, bootrom: Vec<u8>) {
+        self.memory.load_bootrom(bootrom);
+        self.reset();
+    }
+
+    /// Power cycle the machine
+    pub fn reset(&mut self) {
+        let model = self
+            .model
+            .unwrap_or_else(|| Model::from_header(self.memory.rom()));
+        self.memory.set_model(model);
+        self.memory.reset();
+        self.regs = Registers::default();
+        self.ime = false;
+        self.ei_delay = 0;
+        self.halted = false;
+        self.halt_bug = false;
+
+        if !self.memory.has_bootrom() {
+            boot::skip_boot(self);
+        }
+    }
+
+    /// Run until the GPU completes a frame
+    pub fn run_frame(&mut self) -> Result<(), VmExit> {
+        let frame = self.memory.gpu.frame_count();
+        while self.memory.gpu.frame_count() == frame {
+            self.step_instruction()?;
+        }
+        Ok(())
+    }
+
+    /// Last frame rendered by the GPU, as RGBA pixels
+    pub fn framebuffer(&self) -> &[u8] {
+        self.memory.gpu.frame()
+    }
+
+    /// Set which buttons are currently pressed
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.memory.joypad.set_buttons(buttons);
+    }
+
+    /// Snapshot the whole machine, see `state` for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        state::write_header(&mut writer, self.memory.rom());
+        let regs = &self.regs;
+        for &register in
+            [regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l]
+                .iter()
+        {
+            writer.write_u8(register);
+        }
+        writer.write_u16(regs.sp);
+        writer.write_u16(regs.pc);
+        writer.write_bool(self.ime);
+        writer.write_u8(self.ei_delay);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.halt_bug);
+        self.memory.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// Restore a snapshot taken by `save_state`. The machine is left as it
+    /// was when the snapshot can't be used.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        self.read_state(data).inspect_err(|_| {
+            let _ = self.read_state(&backup);
+        })
+    }
+
+    fn read_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data);
+        state::read_header(&mut reader, self.memory.rom())?;
+        let regs = &mut self.regs;
+        for register in [
+            &mut regs.a,
+            &mut regs.f,
+            &mut regs.b,
+            &mut regs.c,
+            &mut regs.d,
+            &mut regs.e,
+            &mut regs.h,
+            &mut regs.l,
+        ] {
+            *register = reader.read_u8()?;
+        }
+        regs.f &= 0xF0;
+        regs.sp = reader.read_u16()?;
+        regs.pc = reader.read_u16()?;
+        self.ime = reader.read_bool()?;
+        self.ei_delay = reader.read_u8()?;
+        self.halted = reader.read_bool()?;
+        self.halt_bug = reader.read_bool()?;
+        self.memory.load_state(&mut reader)
+    }
+
+    /// Take the audio samples produced since the last call. The APU is not
+    /// emulated yet so there never are any.
+    pub fn audio_samples(&mut self) -> Vec<i16> {
+        Vec::new()
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl Emulator {
+    /// An emulator past the boot ROM, about to run `program` from 0x0100 of
+    /// an otherwise zeroed 32 KiB cartridge
+    pub(crate) fn with_program(program: &[u8]) -> Emulator {
+        Emulator::with_code(&[(0x100, program)])
+    }
+
+    /// Like `with_program`, with every `(address, bytes)` of `code` in the
+    /// cartridge
+    pub(crate) fn with_code(code: &[(usize, &[u8])]) -> Emulator {
+        let mut rom = vec![0; 0x8000];
+        for &(address, bytes) in code {
+            rom[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        let mut emulator = Emulator::new();
+        emulator.load_rom_from_bytes(rom);
+        emulator
+    }
+}
+
+impl<B: Bus> Emulator<B> {
+    /// Create an emulator whose CPU runs against `bus`
+    pub fn with_bus(bus: B) -> Emulator<B> {
         Emulator {
-            memory: Mmu::new(),
-            regs: Registers {
-                a: 0,
-                b: 0,
-                c: 0,
-                d: 0,
-                e: 0,
-                f: 0,
-                h: 0,
-                l: 0,
-                sp: 0,
-                pc: 0,
-            },
+            memory: bus,
+            regs: Registers::default(),
+            ime: false,
+            ei_delay: 0,
+            halted: false,
+            halt_bug: false,
+            model: None,
         }
     }
 
     pub fn run(&mut self) -> Result<(), VmExit> {
         loop {
-            let instr = self.memory.read_byte(self.regs.pc)?;
+            self.step_instruction()?;
+        }
+    }
 
-            // print!("0x{:04x}\n", self.regs.pc);
+    /// Execute a single instruction, or service an interrupt, and return the
+    /// number of clock cycles it took
+    pub fn step_instruction(&mut self) -> Result<usize, VmExit> {
+        let pc = self.regs.pc;
+        self.step().map_err(|e| e.with_pc(pc))
+    }
 
-            // Decode the instruction and return number of bytes read
-            let (bytes_read, machine_cycles) = match instr {
-                0x00 => (1, 1), // NOP
-                0x01 => {
-                    // LD BC, d16
-                    self.regs.set_bc(self.memory.read_word(self.regs.pc + 1)?);
-                    (3, 3)
-                }
-                0x02 => {
-                    // LD (BC), A
-                    self.memory.write_byte(self.regs.bc(), self.regs.a)?;
-                    (1, 2)
-                }
-                0x03 => {
-                    // INC BC
-                    self.regs.set_bc(self.regs.bc().wrapping_add(1));
-                    (1, 2)
-                }
-                0x04 => {
-                    // INC B
-                    self.regs.b = self.alu_inc8(self.regs.b);
-                    (1, 1)
-                }
-                0x05 => {
-                    // DEC B
-                    self.regs.b = self.alu_dec8(self.regs.b);
-                    (1, 1)
-                }
-                0x06 => {
-                    // LD B, d8
-                    self.regs.b = self.memory.read_byte(self.regs.pc + 1)?;
-                    (2, 2)
-                }
-                0x07 => {
-                    // RLCA
-                    let tmp = self.regs.a;
-                    let carry = (0x80 & tmp) == 0x80;
-                    self.regs.a = tmp << 1 | if carry { 1 } else { 0 };
-                    self.regs.clear_flags();
-                    self.regs.set_flag(CpuFlag::C, carry);
-                    (1, 1)
-                }
-                0x08 => {
-                    // LD (a16), SP
-                    self.regs.sp = self.memory.read_word(self.regs.pc + 1)?;
-                    (3, 5)
-                }
-                0x09 => {
-                    // ADD HL, BC
-                    self.alu_add_hl(self.regs.bc());
-                    (1, 2)
-                }
-                0x0A => {
-                    // LD A, (BC)
-                    self.regs.a = self.memory.read_byte(self.regs.bc())?;
-                    (1, 2)
-                }
-                0x0B => {
-                    // DEC BC
-                    self.regs.set_bc(self.regs.bc().wrapping_sub(1));
-                    (1, 2)
-                }
-                0x0C => {
-                    // INC C
-                    self.regs.c = self.alu_inc8(self.regs.c);
-                    (1, 1)
-                }
-                0x0D => {
-                    // DEC C
-                    self.regs.c = self.alu_dec8(self.regs.c);
-                    (1, 1)
-                }
-                0x0E => {
-                    // LD C, d8
-                    self.regs.c = self.memory.read_byte(self.regs.pc + 1)?;
-                    (2, 2)
-                }
-                0x0F => {
-                    // RRCA
-                    let tmp = self.regs.a;
-                    let carry = (0x01 & tmp) == 0x01;
-                    self.regs.a = tmp >> 1 | if carry { 0x80 } else { 0 };
-                    self.regs.clear_flags();
-                    self.regs.set_flag(CpuFlag::C, carry);
-                    (1, 1)
-                }
-                0x10 => {
-                    // STOP
-                    return Err(VmExit::Stop);
-                }
-                0x11 => {
-                    // LD DE, d16
-                    self.regs.set_de(self.memory.read_word(self.regs.pc + 1)?);
-                    (3, 3)
-                }
-                0x12 => {
-                    // LD (DE), A
-                    self.memory.write_byte(self.regs.de(), self.regs.a)?;
-                    (1, 2)
-                }
-                0x13 => {
-                    // INC DE
-                    self.regs.set_de(self.regs.de().wrapping_add(1));
-                    (1, 2)
-                }
-                0x14 => {
-                    // INC D
-                    self.regs.d = self.alu_inc8(self.regs.d);
-                    (1, 1)
-                }
-                0x15 => {
-                    // DEC D
-                    self.regs.d = self.alu_dec8(self.regs.d);
-                    (1, 1)
-                }
-                0x16 => {
-                    // LD D, d8
-                    self.regs.d = self.memory.read_byte(self.regs.pc + 1)?;
+    pub fn registers(&self) -> &Registers {
+        &self.regs
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.regs
+    }
+
+    /// Whether interrupts are enabled
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    /// Whether the CPU is waiting for an interrupt after a HALT
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether the next step executes an opcode, rather than dispatching an
+    /// interrupt or waiting for one after a HALT
+    pub fn executes_opcode(&mut self) -> bool {
+        let (enabled, requested) = match self.interrupt_registers() {
+            Ok(registers) => registers,
+            Err(_) => return true,
+        };
+        let pending = enabled & requested != 0;
+        if self.halted {
+            pending && !self.ime
+        } else {
+            !(pending && self.ime)
+        }
+    }
+
+    /// Read a byte as the CPU would see it
+    pub fn read_memory(&mut self, address: u16) -> Result<u8, VmExit> {
+        self.memory.read_byte(address)
+    }
+
+    /// Read a byte as the CPU would see it, without stopping on watchpoints
+    pub fn peek_memory(&mut self, address: u16) -> Result<u8, VmExit> {
+        self.memory.peek_byte(address)
+    }
+
+    /// Write a byte as the CPU would
+    pub fn write_memory(
+        &mut self,
+        address: u16,
+        val: u8,
+    ) -> Result<(), VmExit> {
+        self.memory.write_byte(address, val)
+    }
+
+    fn step(&mut self) -> Result<usize, VmExit> {
+        if let Some(machine_cycles) = self.handle_interrupts()? {
+            self.memory.tick(machine_cycles * 4);
+            return Ok(machine_cycles * 4);
+        }
+
+        let instr = self.memory.read_byte(self.regs.pc)?;
+        if self.halt_bug {
+            // The opcode is read again as the first byte after it
+            self.halt_bug = false;
+            self.regs.pc = self.regs.pc.wrapping_sub(1);
+        }
+
+        // print!("0x{:04x}\n", self.regs.pc);
+
+        // Decode the instruction and return number of bytes read
+        let (bytes_read, machine_cycles) = match instr {
+            0x00 => (1, 1), // NOP
+            0x01 => {
+                // LD BC, d16
+                self.regs.set_bc(self.memory.read_word(self.regs.pc + 1)?);
+                (3, 3)
+            }
+            0x02 => {
+                // LD (BC), A
+                self.memory.write_byte(self.regs.bc(), self.regs.a)?;
+                (1, 2)
+            }
+            0x03 => {
+                // INC BC
+                self.memory.idu_access(self.regs.bc());
+                self.regs.set_bc(self.regs.bc().wrapping_add(1));
+                (1, 2)
+            }
+            0x04 => {
+                // INC B
+                self.regs.b = self.alu_inc8(self.regs.b);
+                (1, 1)
+            }
+            0x05 => {
+                // DEC B
+                self.regs.b = self.alu_dec8(self.regs.b);
+                (1, 1)
+            }
+            0x06 => {
+                // LD B, d8
+                self.regs.b = self.memory.read_byte(self.regs.pc + 1)?;
+                (2, 2)
+            }
+            0x07 => {
+                // RLCA
+                let tmp = self.regs.a;
+                let carry = (0x80 & tmp) == 0x80;
+                self.regs.a = tmp << 1 | if carry { 1 } else { 0 };
+                self.regs.clear_flags();
+                self.regs.set_flag(CpuFlag::C, carry);
+                (1, 1)
+            }
+            0x08 => {
+                // LD (a16), SP
+                self.regs.sp = self.memory.read_word(self.regs.pc + 1)?;
+                (3, 5)
+            }
+            0x09 => {
+                // ADD HL, BC
+                self.alu_add_hl(self.regs.bc());
+                (1, 2)
+            }
+            0x0A => {
+                // LD A, (BC)
+                self.regs.a = self.memory.read_byte(self.regs.bc())?;
+                (1, 2)
+            }
+            0x0B => {
+                // DEC BC
+                self.memory.idu_access(self.regs.bc());
+                self.regs.set_bc(self.regs.bc().wrapping_sub(1));
+                (1, 2)
+            }
+            0x0C => {
+                // INC C
+                self.regs.c = self.alu_inc8(self.regs.c);
+                (1, 1)
+            }
+            0x0D => {
+                // DEC C
+                self.regs.c = self.alu_dec8(self.regs.c);
+                (1, 1)
+            }
+            0x0E => {
+                // LD C, d8
+                self.regs.c = self.memory.read_byte(self.regs.pc + 1)?;
+                (2, 2)
+            }
+            0x0F => {
+                // RRCA
+                let tmp = self.regs.a;
+                let carry = (0x01 & tmp) == 0x01;
+                self.regs.a = tmp >> 1 | if carry { 0x80 } else { 0 };
+                self.regs.clear_flags();
+                self.regs.set_flag(CpuFlag::C, carry);
+                (1, 1)
+            }
+            0x10 => {
+                // STOP
+                return Err(VmExit::Stop);
+            }
+            0x11 => {
+                // LD DE, d16
+                self.regs.set_de(self.memory.read_word(self.regs.pc + 1)?);
+                (3, 3)
+            }
+            0x12 => {
+                // LD (DE), A
+                self.memory.write_byte(self.regs.de(), self.regs.a)?;
+                (1, 2)
+            }
+            0x13 => {
+                // INC DE
+                self.memory.idu_access(self.regs.de());
+                self.regs.set_de(self.regs.de().wrapping_add(1));
+                (1, 2)
+            }
+            0x14 => {
+                // INC D
+                self.regs.d = self.alu_inc8(self.regs.d);
+                (1, 1)
+            }
+            0x15 => {
+                // DEC D
+                self.regs.d = self.alu_dec8(self.regs.d);
+                (1, 1)
+            }
+            0x16 => {
+                // LD D, d8
+                self.regs.d = self.memory.read_byte(self.regs.pc + 1)?;
+                (2, 2)
+            }
+            0x17 => {
+                // RLA
+                self.regs.a = self.alu_rl(self.regs.a);
+                self.regs.set_flag(CpuFlag::Z, false);
+                (1, 1)
+            }
+            0x18 => {
+                // JR r8
+                let tmp = self.memory.read_byte(self.regs.pc + 1)?;
+                self.regs.pc = self.regs.pc.wrapping_add(tmp as i8 as u16);
+                (2, 3)
+            }
+            0x19 => {
+                // ADD HL, DE
+                self.alu_add_hl(self.regs.de());
+                (1, 2)
+            }
+            0x1A => {
+                // LD A, (DE)
+                self.regs.a = self.memory.read_byte(self.regs.de())?;
+                (1, 2)
+            }
+            0x1B => {
+                // DEC DE
+                self.memory.idu_access(self.regs.de());
+                self.regs.set_de(self.regs.de().wrapping_sub(1));
+                (1, 2)
+            }
+            0x1C => {
+                // INC E
+                self.regs.e = self.alu_inc8(self.regs.e);
+                (1, 1)
+            }
+            0x1D => {
+                // DEC E
+                self.regs.e = self.alu_dec8(self.regs.e);
+                (1, 1)
+            }
+            0x1E => {
+                // LD E, d8
+                self.regs.e = self.memory.read_byte(self.regs.pc + 1)?;
+                (2, 2)
+            }
+            0x1F => {
+                // RRA
+                let tmp = self.regs.a;
+                let carry = (0x01 & tmp) == 0x01;
+                self.regs.a = tmp >> 1;
+                self.regs.clear_flags();
+                self.regs.set_flag(CpuFlag::C, carry);
+                (1, 1)
+            }
+            0x20 => {
+                // JR NZ,r8
+                if self.regs.flag(CpuFlag::Z) {
                     (2, 2)
-                }
-                0x17 => {
-                    // RLA
-                    self.alu_rl(|emu: &mut Emulator| &mut emu.regs.a);
-                    self.regs.set_flag(CpuFlag::Z, false);
-                    (1, 1)
-                }
-                0x18 => {
-                    // JR r8
+                } else {
                     let tmp = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.regs.pc = self.regs.pc.wrapping_add(tmp as i8 as u16);
+                    self.regs.pc =
+                        self.regs.pc.wrapping_add(tmp as i8 as u16);
                     (2, 3)
                 }
-                0x19 => {
-                    // ADD HL, DE
-                    self.alu_add_hl(self.regs.de());
-                    (1, 2)
-                }
-                0x1A => {
-                    // LD A, (DE)
-                    self.regs.a = self.memory.read_byte(self.regs.de())?;
-                    (1, 2)
-                }
-                0x1B => {
-                    // DEC DE
-                    self.regs.set_de(self.regs.de().wrapping_sub(1));
-                    (1, 2)
-                }
-                0x1C => {
-                    // INC E
-                    self.regs.e = self.alu_inc8(self.regs.e);
-                    (1, 1)
-                }
-                0x1D => {
-                    // DEC E
-                    self.regs.e = self.alu_dec8(self.regs.e);
-                    (1, 1)
-                }
-                0x1E => {
-                    // LD E, d8
-                    self.regs.e = self.memory.read_byte(self.regs.pc + 1)?;
+            }
+            0x21 => {
+                // LD HL, d16
+                self.regs.set_hl(self.memory.read_word(self.regs.pc + 1)?);
+                (3, 3)
+            }
+            0x22 => {
+                // LD (HL+), A
+                self.memory.write_byte(self.regs.hl(), self.regs.a)?;
+                self.regs.set_hl(self.regs.hl().wrapping_add(1));
+                (1, 2)
+            }
+            0x23 => {
+                // INC HL
+                self.memory.idu_access(self.regs.hl());
+                self.regs.set_hl(self.regs.hl().wrapping_add(1));
+                (1, 2)
+            }
+            0x24 => {
+                // INC H
+                self.regs.h = self.alu_inc8(self.regs.h);
+                (1, 1)
+            }
+            0x25 => {
+                // DEC H
+                self.regs.h = self.alu_dec8(self.regs.h);
+                (1, 1)
+            }
+            0x26 => {
+                // LD H, d8
+                self.regs.h = self.memory.read_byte(self.regs.pc + 1)?;
+                (2, 2)
+            }
+            0x27 => {
+                // DAA
+                // TODO handle this instruction
+                return Err(VmExit::UnimplementedOpcode {
+                    pc: self.regs.pc,
+                    opcode: instr as u16,
+                });
+            }
+            0x28 => {
+                // JR Z,r8
+                if !self.regs.flag(CpuFlag::Z) {
                     (2, 2)
+                } else {
+                    let tmp = self.memory.read_byte(self.regs.pc + 1)?;
+                    self.regs.pc =
+                        self.regs.pc.wrapping_add(tmp as i8 as u16);
+                    (2, 3)
                 }
-                0x1F => {
-                    // RRA
-                    let tmp = self.regs.a;
-                    let carry = (0x01 & tmp) == 0x01;
-                    self.regs.a = tmp >> 1;
-                    self.regs.clear_flags();
-                    self.regs.set_flag(CpuFlag::C, carry);
-                    (1, 1)
-                }
-                0x20 => {
-                    // JR NZ,r8
-                    if self.regs.flag(CpuFlag::Z) {
-                        (2, 2)
-                    } else {
-                        let tmp = self.memory.read_byte(self.regs.pc + 1)?;
-                        self.regs.pc =
-                            self.regs.pc.wrapping_add(tmp as i8 as u16);
-                        (2, 3)
-                    }
-                }
-                0x21 => {
-                    // LD HL, d16
-                    self.regs.set_hl(self.memory.read_word(self.regs.pc + 1)?);
-                    (3, 3)
-                }
-                0x22 => {
-                    // LD (HL+), A
-                    self.memory.write_byte(self.regs.hl(), self.regs.a)?;
-                    self.regs.set_hl(self.regs.hl().wrapping_add(1));
-                    (1, 2)
-                }
-                0x23 => {
-                    // INC HL
-                    self.regs.set_hl(self.regs.hl().wrapping_add(1));
-                    (1, 2)
-                }
-                0x24 => {
-                    // INC H
-                    self.regs.h = self.alu_inc8(self.regs.h);
-                    (1, 1)
-                }
-                0x25 => {
-                    // DEC H
-                    self.regs.h = self.alu_dec8(self.regs.h);
-                    (1, 1)
-                }
-                0x26 => {
-                    // LD H, d8
-                    self.regs.h = self.memory.read_byte(self.regs.pc + 1)?;
-                    (2, 2)
-                }
-                0x27 => {
-                    // DAA
-                    panic!("DAA :o");
-                    // TODO handle this instruction
-                }
-                0x28 => {
-                    // JR Z,r8
-                    if !self.regs.flag(CpuFlag::Z) {
-                        (2, 2)
-                    } else {
-                        let tmp = self.memory.read_byte(self.regs.pc + 1)?;
-                        self.regs.pc =
-                            self.regs.pc.wrapping_add(tmp as i8 as u16);
-                        (2, 3)
-                    }
-                }
-                0x29 => {
-                    // ADD HL, HL
-                    self.alu_add_hl(self.regs.hl());
-                    (1, 2)
-                }
-                0x2A => {
-                    // LD A, (HL+)
-                    self.regs.a = self.memory.read_byte(self.regs.hl())?;
-                    self.regs.set_hl(self.regs.hl().wrapping_add(1));
-                    (1, 2)
-                }
-                0x2B => {
-                    // DEC HL
-                    self.regs.set_hl(self.regs.hl().wrapping_sub(1));
-                    (1, 2)
-                }
-                0x2C => {
-                    // INC L
-                    self.regs.l = self.alu_inc8(self.regs.l);
-                    (1, 1)
-                }
-                0x2D => {
-                    // DEC L
-                    self.regs.l = self.alu_dec8(self.regs.l);
-                    (1, 1)
-                }
-                0x2E => {
-                    // LD L, d8
-                    self.regs.l = self.memory.read_byte(self.regs.pc + 1)?;
+            }
+            0x29 => {
+                // ADD HL, HL
+                self.alu_add_hl(self.regs.hl());
+                (1, 2)
+            }
+            0x2A => {
+                // LD A, (HL+)
+                self.regs.a = self.memory.read_byte(self.regs.hl())?;
+                self.regs.set_hl(self.regs.hl().wrapping_add(1));
+                (1, 2)
+            }
+            0x2B => {
+                // DEC HL
+                self.memory.idu_access(self.regs.hl());
+                self.regs.set_hl(self.regs.hl().wrapping_sub(1));
+                (1, 2)
+            }
+            0x2C => {
+                // INC L
+                self.regs.l = self.alu_inc8(self.regs.l);
+                (1, 1)
+            }
+            0x2D => {
+                // DEC L
+                self.regs.l = self.alu_dec8(self.regs.l);
+                (1, 1)
+            }
+            0x2E => {
+                // LD L, d8
+                self.regs.l = self.memory.read_byte(self.regs.pc + 1)?;
+                (2, 2)
+            }
+            0x2F => {
+                // CPL
+                self.regs.a = !self.regs.a;
+                self.regs.set_flag(CpuFlag::N, true);
+                self.regs.set_flag(CpuFlag::H, true);
+                (1, 1)
+            }
+            0x30 => {
+                // JR NC,r8
+                if self.regs.flag(CpuFlag::C) {
                     (2, 2)
-                }
-                0x2F => {
-                    // CPL
-                    self.regs.a = !self.regs.a;
-                    self.regs.set_flag(CpuFlag::N, true);
-                    self.regs.set_flag(CpuFlag::H, true);
-                    (1, 1)
-                }
-                0x30 => {
-                    // JR NC,r8
-                    if self.regs.flag(CpuFlag::C) {
-                        (2, 2)
-                    } else {
-                        let tmp = self.memory.read_byte(self.regs.pc + 1)?;
-                        self.regs.pc =
-                            self.regs.pc.wrapping_add(tmp as i8 as u16);
-                        (2, 3)
-                    }
-                }
-                0x31 => {
-                    // LD SP, d16
-                    self.regs.sp = self.memory.read_word(self.regs.pc + 1)?;
-                    (3, 3)
-                }
-                0x32 => {
-                    // LD (HL-), A
-                    self.memory.write_byte(self.regs.hl(), self.regs.a)?;
-                    self.regs.set_hl(self.regs.hl().wrapping_sub(1));
-                    (1, 2)
-                }
-                0x33 => {
-                    // INC SP
-                    self.regs.sp = self.regs.sp.wrapping_add(1);
-                    (1, 2)
-                }
-                0x34 => {
-                    // INC (HL)
-                    let tmp = self.memory.read_byte(self.regs.hl())?;
-                    let tmp = self.alu_inc8(tmp);
-                    self.memory.write_byte(self.regs.hl(), tmp)?;
-                    (1, 3)
-                }
-                0x35 => {
-                    // DEC (HL)
-                    let tmp = self.memory.read_byte(self.regs.hl())?;
-                    let tmp = self.alu_dec8(tmp);
-                    self.memory.write_byte(self.regs.hl(), tmp)?;
-                    (1, 3)
-                }
-                0x36 => {
-                    // LD (HL), d8
+                } else {
                     let tmp = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.memory.write_byte(self.regs.hl(), tmp)?;
+                    self.regs.pc =
+                        self.regs.pc.wrapping_add(tmp as i8 as u16);
                     (2, 3)
                 }
-                0x37 => {
-                    // SCF
-                    self.regs.set_flag(CpuFlag::N, false);
-                    self.regs.set_flag(CpuFlag::H, false);
-                    self.regs.set_flag(CpuFlag::C, true);
-                    (1, 1)
-                }
-                0x38 => {
-                    // JR C,r8
-                    if !self.regs.flag(CpuFlag::C) {
-                        (2, 2)
-                    } else {
-                        let tmp = self.memory.read_byte(self.regs.pc + 1)?;
-                        self.regs.pc =
-                            self.regs.pc.wrapping_add(tmp as i8 as u16);
-                        (2, 3)
-                    }
-                }
-                0x39 => {
-                    // ADD HL, SP
-                    self.alu_add_hl(self.regs.sp);
-                    (1, 2)
-                }
-                0x3A => {
-                    // LD A, (HL-)
-                    self.regs.a = self.memory.read_byte(self.regs.hl())?;
-                    self.regs.set_hl(self.regs.hl().wrapping_sub(1));
-                    (1, 2)
-                }
-                0x3B => {
-                    // DEC SP
-                    self.regs.sp = self.regs.sp.wrapping_sub(1);
-                    (1, 2)
-                }
-                0x3C => {
-                    // INC A
-                    self.regs.a = self.alu_inc8(self.regs.a);
-                    (1, 1)
-                }
-                0x3D => {
-                    // DEC A
-                    self.regs.a = self.alu_dec8(self.regs.a);
-                    (1, 1)
-                }
-                0x3E => {
-                    // LD A, d8
-                    self.regs.a = self.memory.read_byte(self.regs.pc + 1)?;
+            }
+            0x31 => {
+                // LD SP, d16
+                self.regs.sp = self.memory.read_word(self.regs.pc + 1)?;
+                (3, 3)
+            }
+            0x32 => {
+                // LD (HL-), A
+                self.memory.write_byte(self.regs.hl(), self.regs.a)?;
+                self.regs.set_hl(self.regs.hl().wrapping_sub(1));
+                (1, 2)
+            }
+            0x33 => {
+                // INC SP
+                self.memory.idu_access(self.regs.sp);
+                self.regs.sp = self.regs.sp.wrapping_add(1);
+                (1, 2)
+            }
+            0x34 => {
+                // INC (HL)
+                let tmp = self.memory.read_byte(self.regs.hl())?;
+                let tmp = self.alu_inc8(tmp);
+                self.memory.write_byte(self.regs.hl(), tmp)?;
+                (1, 3)
+            }
+            0x35 => {
+                // DEC (HL)
+                let tmp = self.memory.read_byte(self.regs.hl())?;
+                let tmp = self.alu_dec8(tmp);
+                self.memory.write_byte(self.regs.hl(), tmp)?;
+                (1, 3)
+            }
+            0x36 => {
+                // LD (HL), d8
+                let tmp = self.memory.read_byte(self.regs.pc + 1)?;
+                self.memory.write_byte(self.regs.hl(), tmp)?;
+                (2, 3)
+            }
+            0x37 => {
+                // SCF
+                self.regs.set_flag(CpuFlag::N, false);
+                self.regs.set_flag(CpuFlag::H, false);
+                self.regs.set_flag(CpuFlag::C, true);
+                (1, 1)
+            }
+            0x38 => {
+                // JR C,r8
+                if !self.regs.flag(CpuFlag::C) {
                     (2, 2)
+                } else {
+                    let tmp = self.memory.read_byte(self.regs.pc + 1)?;
+                    self.regs.pc =
+                        self.regs.pc.wrapping_add(tmp as i8 as u16);
+                    (2, 3)
                 }
-                0x3F => {
-                    // CCF
-                    self.regs.set_flag(CpuFlag::N, false);
-                    self.regs.set_flag(CpuFlag::H, false);
-                    self.regs.set_flag(CpuFlag::C, !self.regs.flag(CpuFlag::C));
-                    (1, 1)
-                }
-                0x40..=0x6F | 0x78..=0x7F => {
-                    // LD r8, r8
-                    // Match on the first three bytes
-                    let src = match instr & 0x7 {
-                        0x0 => self.regs.b,
-                        0x1 => self.regs.c,
-                        0x2 => self.regs.d,
-                        0x3 => self.regs.e,
-                        0x4 => self.regs.h,
-                        0x5 => self.regs.l,
-                        0x6 => self.memory.read_byte(self.regs.hl())?,
-                        0x7 => self.regs.a,
-                        _ => unreachable!(),
-                    };
-                    let dest = match instr & 0b11111000 {
-                        0x40 => &mut self.regs.b,
-                        0x48 => &mut self.regs.c,
-                        0x50 => &mut self.regs.d,
-                        0x58 => &mut self.regs.e,
-                        0x60 => &mut self.regs.h,
-                        0x68 => &mut self.regs.l,
-                        0x78 => &mut self.regs.a,
-                        _ => unreachable!(),
-                    };
-                    *dest = src;
-                    (1, if instr & 0x7 == 0x6 { 2 } else { 1 })
-                }
-                0x70..=0x77 => {
-                    // LD (HL), r8
-                    let src = match instr & 0x7 {
-                        0x0 => self.regs.b,
-                        0x1 => self.regs.c,
-                        0x2 => self.regs.d,
-                        0x3 => self.regs.e,
-                        0x4 => self.regs.h,
-                        0x5 => self.regs.l,
-                        0x6 => return Err(VmExit::Halt),
-                        0x7 => self.regs.a,
-                        _ => unreachable!(),
-                    };
-                    self.memory.write_byte(self.regs.hl(), src)?;
+            }
+            0x39 => {
+                // ADD HL, SP
+                self.alu_add_hl(self.regs.sp);
+                (1, 2)
+            }
+            0x3A => {
+                // LD A, (HL-)
+                self.regs.a = self.memory.read_byte(self.regs.hl())?;
+                self.regs.set_hl(self.regs.hl().wrapping_sub(1));
+                (1, 2)
+            }
+            0x3B => {
+                // DEC SP
+                self.memory.idu_access(self.regs.sp);
+                self.regs.sp = self.regs.sp.wrapping_sub(1);
+                (1, 2)
+            }
+            0x3C => {
+                // INC A
+                self.regs.a = self.alu_inc8(self.regs.a);
+                (1, 1)
+            }
+            0x3D => {
+                // DEC A
+                self.regs.a = self.alu_dec8(self.regs.a);
+                (1, 1)
+            }
+            0x3E => {
+                // LD A, d8
+                self.regs.a = self.memory.read_byte(self.regs.pc + 1)?;
+                (2, 2)
+            }
+            0x3F => {
+                // CCF
+                self.regs.set_flag(CpuFlag::N, false);
+                self.regs.set_flag(CpuFlag::H, false);
+                self.regs.set_flag(CpuFlag::C, !self.regs.flag(CpuFlag::C));
+                (1, 1)
+            }
+            0x40..=0x6F | 0x78..=0x7F => {
+                // LD r8, r8
+                // Match on the first three bytes
+                let src = match instr & 0x7 {
+                    0x0 => self.regs.b,
+                    0x1 => self.regs.c,
+                    0x2 => self.regs.d,
+                    0x3 => self.regs.e,
+                    0x4 => self.regs.h,
+                    0x5 => self.regs.l,
+                    0x6 => self.memory.read_byte(self.regs.hl())?,
+                    0x7 => self.regs.a,
+                    _ => unreachable!(),
+                };
+                let dest = match instr & 0b11111000 {
+                    0x40 => &mut self.regs.b,
+                    0x48 => &mut self.regs.c,
+                    0x50 => &mut self.regs.d,
+                    0x58 => &mut self.regs.e,
+                    0x60 => &mut self.regs.h,
+                    0x68 => &mut self.regs.l,
+                    0x78 => &mut self.regs.a,
+                    _ => unreachable!(),
+                };
+                *dest = src;
+                (1, if instr & 0x7 == 0x6 { 2 } else { 1 })
+            }
+            0x76 => {
+                // HALT
+                let (enabled, requested) = self.interrupt_registers()?;
+                if !self.ime && enabled & requested != 0 {
+                    // The CPU doesn't halt and the HALT bug kicks in
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+                (1, 1)
+            }
+            0x70..=0x77 => {
+                // LD (HL), r8
+                let src = match instr & 0x7 {
+                    0x0 => self.regs.b,
+                    0x1 => self.regs.c,
+                    0x2 => self.regs.d,
+                    0x3 => self.regs.e,
+                    0x4 => self.regs.h,
+                    0x5 => self.regs.l,
+                    0x7 => self.regs.a,
+                    _ => unreachable!(),
+                };
+                self.memory.write_byte(self.regs.hl(), src)?;
+                (1, 2)
+            }
+            0x80..=0xBF => {
+                // Match on the first three bytes
+                let src = match instr & 0x7 {
+                    0x0 => self.regs.b,
+                    0x1 => self.regs.c,
+                    0x2 => self.regs.d,
+                    0x3 => self.regs.e,
+                    0x4 => self.regs.h,
+                    0x5 => self.regs.l,
+                    0x6 => self.memory.read_byte(self.regs.hl())?,
+                    0x7 => self.regs.a,
+                    _ => unreachable!(),
+                };
+                match instr & 0b11111000 {
+                    0x80 => self.alu_add(src),
+                    0x88 => self.alu_adc(src),
+                    0x90 => self.alu_sub(src),
+                    0x98 => self.alu_sbc(src),
+                    0xA0 => self.alu_and(src),
+                    0xA8 => self.alu_xor(src),
+                    0xB0 => self.alu_or(src),
+                    0xB8 => self.alu_cp(src),
+                    _ => unreachable!(),
+                };
+                (1, if instr & 0x7 == 0x6 { 2 } else { 1 })
+            }
+            0xC0 => {
+                // RET NZ
+                if self.regs.flag(CpuFlag::Z) {
                     (1, 2)
-                }
-                0x80..=0xBF => {
-                    // Match on the first three bytes
-                    let src = match instr & 0x7 {
-                        0x0 => self.regs.b,
-                        0x1 => self.regs.c,
-                        0x2 => self.regs.d,
-                        0x3 => self.regs.e,
-                        0x4 => self.regs.h,
-                        0x5 => self.regs.l,
-                        0x6 => self.memory.read_byte(self.regs.hl())?,
-                        0x7 => self.regs.a,
-                        _ => unreachable!(),
-                    };
-                    match instr & 0b11111000 {
-                        0x80 => self.alu_add(src),
-                        0x88 => self.alu_adc(src),
-                        0x90 => self.alu_sub(src),
-                        0x98 => self.alu_sbc(src),
-                        0xA0 => self.alu_and(src),
-                        0xA8 => self.alu_xor(src),
-                        0xB0 => self.alu_or(src),
-                        0xB8 => self.alu_cp(src),
-                        _ => unreachable!(),
-                    };
-                    (1, if instr & 0x7 == 0x6 { 2 } else { 1 })
-                }
-                0xC0 => {
-                    // RET NZ
-                    if self.regs.flag(CpuFlag::Z) {
-                        (1, 2)
-                    } else {
-                        self.regs.pc = self.pop16()?;
-                        (0, 5)
-                    }
-                }
-                0xC1 => {
-                    // POP BC
-                    let bc = self.pop16()?;
-                    self.regs.set_bc(bc);
-                    (1, 3)
-                }
-                0xC2 => {
-                    // JP NZ, a16
-                    if self.regs.flag(CpuFlag::Z) {
-                        (3, 3)
-                    } else {
-                        self.regs.pc =
-                            self.memory.read_word(self.regs.pc + 1)?;
-                        (0, 4)
-                    }
-                }
-                0xC3 => {
-                    // JP a16
-                    self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
-                    (0, 4)
-                }
-                0xC4 => {
-                    // CALL NZ, a16
-                    if self.regs.flag(CpuFlag::Z) {
-                        (3, 3)
-                    } else {
-                        self.push16(self.regs.pc + 3);
-                        self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
-                        (0, 6)
-                    }
-                }
-                0xC5 => {
-                    // PUSH BC
-                    self.push16(self.regs.bc());
-                    (1, 4)
-                }
-                0xC6 => {
-                    // ADD A, d8
-                    let src = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.alu_add(src);
-                    (2, 2)
-                }
-                0xC7 => {
-                    // RST 00h
-                    self.regs.pc = 0;
-                    (1, 4)
-                }
-                0xC8 => {
-                    // RET Z
-                    if !self.regs.flag(CpuFlag::Z) {
-                        (1, 2)
-                    } else {
-                        self.regs.pc = self.pop16()?;
-                        (0, 5)
-                    }
-                }
-                0xC9 => {
-                    // RET
+                } else {
                     self.regs.pc = self.pop16()?;
+                    (0, 5)
+                }
+            }
+            0xC1 => {
+                // POP BC
+                let bc = self.pop16()?;
+                self.regs.set_bc(bc);
+                (1, 3)
+            }
+            0xC2 => {
+                // JP NZ, a16
+                if self.regs.flag(CpuFlag::Z) {
+                    (3, 3)
+                } else {
+                    self.regs.pc =
+                        self.memory.read_word(self.regs.pc + 1)?;
                     (0, 4)
                 }
-                0xCA => {
-                    // JP Z,a16
-                    if !self.regs.flag(CpuFlag::Z) {
-                        (3, 3)
-                    } else {
-                        self.regs.pc =
-                            self.memory.read_word(self.regs.pc + 1)?;
-                        (0, 4)
-                    }
-                }
-                0xCB => {
-                    // PREFIX CB
-                    let subinstr = self.memory.read_byte(self.regs.pc + 1)?;
-
-                    let get_src_reg = match subinstr & 0x7 {
-                        0x0 => identity(|emu: &mut Emulator| &mut emu.regs.b),
-                        0x1 => identity(|emu: &mut Emulator| &mut emu.regs.c),
-                        0x2 => identity(|emu: &mut Emulator| &mut emu.regs.d),
-                        0x3 => identity(|emu: &mut Emulator| &mut emu.regs.e),
-                        0x4 => identity(|emu: &mut Emulator| &mut emu.regs.h),
-                        0x5 => identity(|emu: &mut Emulator| &mut emu.regs.l),
-                        0x6 => identity(|emu: &mut Emulator| {
-                            emu.memory.get_mut_ref_byte(emu.regs.hl()).unwrap()
-                        }),
-                        0x7 => identity(|emu: &mut Emulator| &mut emu.regs.a),
-                        _ => unreachable!(),
-                    };
-
-                    match subinstr & 0b11111000 {
-                        // 0x00 => self.alu_rlc(src),
-                        0x78 => {
-                            let tmp = *get_src_reg(self);
-                            self.bit(tmp, 7)
-                        }
-                        0x10 => self.alu_rl(get_src_reg),
-                        0x30 => self.alu_swap(get_src_reg),
-                        0x38 => self.alu_srl(get_src_reg),
-                        0xF8 => *get_src_reg(self) |= 0b10000000,
-                        /*
-                        0x08 => rrc,
-                        0x10 => rl,
-                        0x18 => rr,
-                        0x20 => sla,
-                        0x28 => sra,
-                        0x40 => bit0,
-                        0x48 => bit1,
-                        0x50 => bit2,
-                        0x58 => bit3,
-                        0x60 => bit4,
-                        0x68 => bit5,
-                        0x70 => bit6,
-                        0x78 => bit7,
-                        0x80 => res0,
-                        0x88 => res1,
-                        0x90 => res2,
-                        0x98 => res3,
-                        0xA0 => res4,
-                        0xA8 => res5,
-                        0xB0 => res6,
-                        0xB8 => res7,
-                        0xC0 => set0,
-                        0xC8 => set1,
-                        0xD0 => set2,
-                        0xD8 => set3,
-                        0xE0 => set4,
-                        0xE8 => set5,
-                        0xF0 => set6,
-                        0xF8 => set7,
-                        */
-                        _ => panic!(
-                            "Unimplemented for now {:04x} {:02x}",
-                            self.regs.pc,
-                            self.memory.read_byte(self.regs.pc + 1)?
-                        ),
-                    }
-
-                    (2, if subinstr & 0x7 == 0x6 { 4 } else { 2 })
-                }
-                0xCC => {
-                    // CALL Z,a16
-                    if !self.regs.flag(CpuFlag::Z) {
-                        (3, 3)
-                    } else {
-                        self.push16(self.regs.pc + 3);
-                        self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
-                        (0, 6)
-                    }
-                }
-                0xCD => {
-                    // CALL a16
-                    self.push16(self.regs.pc + 3);
+            }
+            0xC3 => {
+                // JP a16
+                self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
+                (0, 4)
+            }
+            0xC4 => {
+                // CALL NZ, a16
+                if self.regs.flag(CpuFlag::Z) {
+                    (3, 3)
+                } else {
+                    self.push16(self.regs.pc + 3)?;
                     self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
                     (0, 6)
                 }
-                0xCE => {
-                    // ADC A,d8
-                    let src = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.alu_adc(src);
-                    (2, 2)
-                }
-                0xCF => {
-                    // RST 08h
-                    self.regs.pc = 0x08;
-                    (1, 4)
-                }
-                0xD0 => {
-                    // RET NC
-                    if self.regs.flag(CpuFlag::C) {
-                        (1, 2)
-                    } else {
-                        self.regs.pc = self.pop16()?;
-                        (0, 5)
-                    }
-                }
-                0xD1 => {
-                    // POP DE
-                    let de = self.pop16()?;
-                    self.regs.set_de(de);
-                    (1, 3)
-                }
-                0xD2 => {
-                    // JP NC, a16
-                    if self.regs.flag(CpuFlag::C) {
-                        (3, 3)
-                    } else {
-                        self.regs.pc =
-                            self.memory.read_word(self.regs.pc + 1)?;
-                        (0, 4)
-                    }
-                }
-                0xD4 => {
-                    // CALL NC, a16
-                    if self.regs.flag(CpuFlag::C) {
-                        (3, 3)
-                    } else {
-                        self.push16(self.regs.pc + 3);
-                        self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
-                        (0, 6)
-                    }
-                }
-                0xD5 => {
-                    // PUSH DE
-                    self.push16(self.regs.de());
-                    (1, 4)
-                }
-                0xD6 => {
-                    // SUB d8
-                    let src = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.alu_sub(src);
-                    (2, 2)
-                }
-                0xD7 => {
-                    // RST 10h
-                    self.regs.pc = 0x10;
-                    (1, 4)
-                }
-                0xD8 => {
-                    // RET C
-                    if !self.regs.flag(CpuFlag::C) {
-                        (1, 2)
-                    } else {
-                        self.regs.pc = self.pop16()?;
-                        (0, 5)
-                    }
-                }
-                0xD9 => {
-                    // RETI
+            }
+            0xC5 => {
+                // PUSH BC
+                self.push16(self.regs.bc())?;
+                (1, 4)
+            }
+            0xC6 => {
+                // ADD A, d8
+                let src = self.memory.read_byte(self.regs.pc + 1)?;
+                self.alu_add(src);
+                (2, 2)
+            }
+            0xC7 => {
+                // RST 00h
+                self.push16(self.regs.pc + 1)?;
+                self.regs.pc = 0x00;
+                (0, 4)
+            }
+            0xC8 => {
+                // RET Z
+                if !self.regs.flag(CpuFlag::Z) {
+                    (1, 2)
+                } else {
                     self.regs.pc = self.pop16()?;
-                    // TODO enable interrupts
+                    (0, 5)
+                }
+            }
+            0xC9 => {
+                // RET
+                self.regs.pc = self.pop16()?;
+                (0, 4)
+            }
+            0xCA => {
+                // JP Z,a16
+                if !self.regs.flag(CpuFlag::Z) {
+                    (3, 3)
+                } else {
+                    self.regs.pc =
+                        self.memory.read_word(self.regs.pc + 1)?;
                     (0, 4)
                 }
-                0xDA => {
-                    // JP C,a16
-                    if !self.regs.flag(CpuFlag::C) {
-                        (3, 3)
-                    } else {
-                        self.regs.pc =
-                            self.memory.read_word(self.regs.pc + 1)?;
-                        (0, 4)
+            }
+            0xCB => {
+                // PREFIX CB
+                let subinstr = self.memory.read_byte(self.regs.pc + 1)?;
+
+                let src = self.read_r8(subinstr)?;
+
+                match subinstr & 0b11111000 {
+                    // 0x00 => self.alu_rlc(src),
+                    0x78 => self.bit(src, 7),
+                    0x10 => {
+                        let res = self.alu_rl(src);
+                        self.write_r8(subinstr, res)?;
                     }
-                }
-                0xDC => {
-                    // CALL C,a16
-                    if !self.regs.flag(CpuFlag::C) {
-                        (3, 3)
-                    } else {
-                        self.push16(self.regs.pc + 3);
-                        self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
-                        (0, 6)
+                    0x30 => {
+                        let res = self.alu_swap(src);
+                        self.write_r8(subinstr, res)?;
                     }
-                }
-                0xDE => {
-                    // SBC A,d8
-                    let src = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.alu_sbc(src);
-                    (2, 2)
-                }
-                0xDF => {
-                    // RST 18h
-                    self.regs.pc = 0x18;
-                    (1, 4)
-                }
-                0xE0 => {
-                    // LDH (a8),A
-                    let address = self.memory.read_byte(self.regs.pc + 1)?
-                        as u16
-                        | 0xFF00;
-                    self.memory.write_byte(address, self.regs.a)?;
-                    (2, 3)
-                }
-                0xE1 => {
-                    // POP HL
-                    let hl = self.pop16()?;
-                    self.regs.set_hl(hl);
-                    (1, 3)
-                }
-                0xE2 => {
-                    // LD (C), A
-                    let address = self.regs.c as u16 | 0xFF00;
-                    self.memory.write_byte(address, self.regs.a)?;
-                    (1, 2)
-                }
-                0xE5 => {
-                    // PUSH HL
-                    self.push16(self.regs.hl());
-                    (1, 4)
-                }
-                0xE6 => {
-                    // AND d8
-                    let src = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.alu_and(src);
-                    (2, 2)
-                }
-                0xE7 => {
-                    // RST 20h
-                    self.regs.pc = 0x20;
-                    (1, 4)
-                }
-                0xE8 => {
-                    // ADD SP,r8 add signed
-                    // TODO check if add signed changes smth
-                    self.regs.set_flag(CpuFlag::N, false);
-                    self.regs.set_flag(CpuFlag::Z, false);
-                    let val = self.memory.read_byte(self.regs.pc + 1)?;
-                    if (val as usize + self.regs.sp as usize) >= 2usize.pow(8) {
-                        self.regs.set_flag(CpuFlag::C, true);
-                    } else {
-                        self.regs.set_flag(CpuFlag::C, false);
+                    0x38 => {
+                        let res = self.alu_srl(src);
+                        self.write_r8(subinstr, res)?;
                     }
-                    if (val as usize + self.regs.sp as usize) >= 2usize.pow(4) {
-                        self.regs.set_flag(CpuFlag::H, true);
-                    } else {
-                        self.regs.set_flag(CpuFlag::H, false);
+                    0xF8 => self.write_r8(subinstr, src | 0b10000000)?,
+                    /*
+                    0x08 => rrc,
+                    0x10 => rl,
+                    0x18 => rr,
+                    0x20 => sla,
+                    0x28 => sra,
+                    0x40 => bit0,
+                    0x48 => bit1,
+                    0x50 => bit2,
+                    0x58 => bit3,
+                    0x60 => bit4,
+                    0x68 => bit5,
+                    0x70 => bit6,
+                    0x78 => bit7,
+                    0x80 => res0,
+                    0x88 => res1,
+                    0x90 => res2,
+                    0x98 => res3,
+                    0xA0 => res4,
+                    0xA8 => res5,
+                    0xB0 => res6,
+                    0xB8 => res7,
+                    0xC0 => set0,
+                    0xC8 => set1,
+                    0xD0 => set2,
+                    0xD8 => set3,
+                    0xE0 => set4,
+                    0xE8 => set5,
+                    0xF0 => set6,
+                    0xF8 => set7,
+                    */
+                    _ => {
+                        return Err(VmExit::UnimplementedOpcode {
+                            pc: self.regs.pc,
+                            opcode: 0xCB00 | subinstr as u16,
+                        })
                     }
-                    self.regs.sp = self.regs.sp.wrapping_add(val as u16);
-                    (2, 4)
-                }
-                0xE9 => {
-                    // JP (HL)
-                    self.regs.pc = self.memory.read_word(self.regs.hl())?;
-                    (0, 1)
-                }
-                0xEA => {
-                    // LD (a16), A
-                    let address = self.memory.read_word(self.regs.pc + 1)?;
-                    self.memory.write_byte(address, self.regs.a)?;
-                    (3, 4)
-                }
-                0xEE => {
-                    // XOR d8
-                    let src = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.alu_xor(src);
-                    (2, 2)
-                }
-                0xEF => {
-                    // RST 28h
-                    self.regs.pc = 0x28;
-                    (1, 4)
-                }
-                0xF0 => {
-                    // LDH A, (a8)
-                    let tmp = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.regs.a = self.memory.read_byte(tmp as u16 | 0xFF00)?;
-                    (2, 3)
                 }
-                0xF1 => {
-                    // POP AF
-                    let af = self.pop16()?;
-                    self.regs.set_af(af);
-                    (1, 3)
+
+                (2, if subinstr & 0x7 == 0x6 { 4 } else { 2 })
+            }
+            0xCC => {
+                // CALL Z,a16
+                if !self.regs.flag(CpuFlag::Z) {
+                    (3, 3)
+                } else {
+                    self.push16(self.regs.pc + 3)?;
+                    self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
+                    (0, 6)
                 }
-                0xF2 => {
-                    // LD A,(C)
-                    let address = self.regs.c as u16 | 0xFF00;
-                    self.regs.a = self.memory.read_byte(address)?;
+            }
+            0xCD => {
+                // CALL a16
+                self.push16(self.regs.pc + 3)?;
+                self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
+                (0, 6)
+            }
+            0xCE => {
+                // ADC A,d8
+                let src = self.memory.read_byte(self.regs.pc + 1)?;
+                self.alu_adc(src);
+                (2, 2)
+            }
+            0xCF => {
+                // RST 08h
+                self.push16(self.regs.pc + 1)?;
+                self.regs.pc = 0x08;
+                (0, 4)
+            }
+            0xD0 => {
+                // RET NC
+                if self.regs.flag(CpuFlag::C) {
                     (1, 2)
+                } else {
+                    self.regs.pc = self.pop16()?;
+                    (0, 5)
+                }
+            }
+            0xD1 => {
+                // POP DE
+                let de = self.pop16()?;
+                self.regs.set_de(de);
+                (1, 3)
+            }
+            0xD2 => {
+                // JP NC, a16
+                if self.regs.flag(CpuFlag::C) {
+                    (3, 3)
+                } else {
+                    self.regs.pc =
+                        self.memory.read_word(self.regs.pc + 1)?;
+                    (0, 4)
                 }
-                0xF3 => {
-                    // DI
-                    // TODO DI
-                    (1, 1)
-                }
-                0xF5 => {
-                    // PUSH AF
-                    self.push16(self.regs.af());
-                    (1, 4)
-                }
-                0xF6 => {
-                    // OR d8
-                    let src = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.alu_or(src);
-                    (2, 2)
-                }
-                0xF7 => {
-                    // RST 30h
-                    self.regs.pc = 0x30;
-                    (1, 4)
-                }
-                0xF8 => {
-                    // LD HL, SP+r8
-                    self.regs.set_flag(CpuFlag::N, false);
-                    self.regs.set_flag(CpuFlag::Z, false);
-                    let val = self.memory.read_byte(self.regs.pc + 1)?;
-                    if (val as usize + self.regs.sp as usize) >= 2usize.pow(8) {
-                        self.regs.set_flag(CpuFlag::C, true);
-                    } else {
-                        self.regs.set_flag(CpuFlag::C, false);
-                    }
-                    if (val as usize + self.regs.sp as usize) >= 2usize.pow(4) {
-                        self.regs.set_flag(CpuFlag::H, true);
-                    } else {
-                        self.regs.set_flag(CpuFlag::H, false);
-                    }
-                    self.regs.set_hl(self.regs.sp.wrapping_add(val as u16));
-                    (2, 3)
+            }
+            0xD4 => {
+                // CALL NC, a16
+                if self.regs.flag(CpuFlag::C) {
+                    (3, 3)
+                } else {
+                    self.push16(self.regs.pc + 3)?;
+                    self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
+                    (0, 6)
                 }
-                0xF9 => {
-                    // LD SP, HL
-                    self.regs.sp = self.regs.hl();
+            }
+            0xD5 => {
+                // PUSH DE
+                self.push16(self.regs.de())?;
+                (1, 4)
+            }
+            0xD6 => {
+                // SUB d8
+                let src = self.memory.read_byte(self.regs.pc + 1)?;
+                self.alu_sub(src);
+                (2, 2)
+            }
+            0xD7 => {
+                // RST 10h
+                self.push16(self.regs.pc + 1)?;
+                self.regs.pc = 0x10;
+                (0, 4)
+            }
+            0xD8 => {
+                // RET C
+                if !self.regs.flag(CpuFlag::C) {
                     (1, 2)
+                } else {
+                    self.regs.pc = self.pop16()?;
+                    (0, 5)
+                }
+            }
+            0xD9 => {
+                // RETI
+                self.regs.pc = self.pop16()?;
+                self.ime = true;
+                (0, 4)
+            }
+            0xDA => {
+                // JP C,a16
+                if !self.regs.flag(CpuFlag::C) {
+                    (3, 3)
+                } else {
+                    self.regs.pc =
+                        self.memory.read_word(self.regs.pc + 1)?;
+                    (0, 4)
                 }
-                0xFA => {
-                    // LD A,(a16)
-                    let address = self.memory.read_word(self.regs.pc + 1)?;
-                    self.regs.a = self.memory.read_byte(address)?;
-                    (3, 4)
+            }
+            0xDC => {
+                // CALL C,a16
+                if !self.regs.flag(CpuFlag::C) {
+                    (3, 3)
+                } else {
+                    self.push16(self.regs.pc + 3)?;
+                    self.regs.pc = self.memory.read_word(self.regs.pc + 1)?;
+                    (0, 6)
                 }
-                0xFB => {
-                    // EI
-                    // TODO EI
-                    (1, 1)
+            }
+            0xDE => {
+                // SBC A,d8
+                let src = self.memory.read_byte(self.regs.pc + 1)?;
+                self.alu_sbc(src);
+                (2, 2)
+            }
+            0xDF => {
+                // RST 18h
+                self.push16(self.regs.pc + 1)?;
+                self.regs.pc = 0x18;
+                (0, 4)
+            }
+            0xE0 => {
+                // LDH (a8),A
+                let address = self.memory.read_byte(self.regs.pc + 1)?
+                    as u16
+                    | 0xFF00;
+                self.memory.write_byte(address, self.regs.a)?;
+                (2, 3)
+            }
+            0xE1 => {
+                // POP HL
+                let hl = self.pop16()?;
+                self.regs.set_hl(hl);
+                (1, 3)
+            }
+            0xE2 => {
+                // LD (C), A
+                let address = self.regs.c as u16 | 0xFF00;
+                self.memory.write_byte(address, self.regs.a)?;
+                (1, 2)
+            }
+            0xE5 => {
+                // PUSH HL
+                self.push16(self.regs.hl())?;
+                (1, 4)
+            }
+            0xE6 => {
+                // AND d8
+                let src = self.memory.read_byte(self.regs.pc + 1)?;
+                self.alu_and(src);
+                (2, 2)
+            }
+            0xE7 => {
+                // RST 20h
+                self.push16(self.regs.pc + 1)?;
+                self.regs.pc = 0x20;
+                (0, 4)
+            }
+            0xE8 => {
+                // ADD SP,r8 add signed
+                // TODO check if add signed changes smth
+                self.regs.set_flag(CpuFlag::N, false);
+                self.regs.set_flag(CpuFlag::Z, false);
+                let val = self.memory.read_byte(self.regs.pc + 1)?;
+                if (val as usize + self.regs.sp as usize) >= 2usize.pow(8) {
+                    self.regs.set_flag(CpuFlag::C, true);
+                } else {
+                    self.regs.set_flag(CpuFlag::C, false);
                 }
-                0xFE => {
-                    // CP d8
-                    let src = self.memory.read_byte(self.regs.pc + 1)?;
-                    self.alu_cp(src);
-                    (2, 2)
+                if (val as usize + self.regs.sp as usize) >= 2usize.pow(4) {
+                    self.regs.set_flag(CpuFlag::H, true);
+                } else {
+                    self.regs.set_flag(CpuFlag::H, false);
                 }
-                0xFF => {
-                    // RST 38h
-                    self.regs.pc = 0x38;
-                    (1, 4)
+                self.regs.sp = self.regs.sp.wrapping_add(val as u16);
+                (2, 4)
+            }
+            0xE9 => {
+                // JP (HL)
+                self.regs.pc = self.memory.read_word(self.regs.hl())?;
+                (0, 1)
+            }
+            0xEA => {
+                // LD (a16), A
+                let address = self.memory.read_word(self.regs.pc + 1)?;
+                self.memory.write_byte(address, self.regs.a)?;
+                (3, 4)
+            }
+            0xEE => {
+                // XOR d8
+                let src = self.memory.read_byte(self.regs.pc + 1)?;
+                self.alu_xor(src);
+                (2, 2)
+            }
+            0xEF => {
+                // RST 28h
+                self.push16(self.regs.pc + 1)?;
+                self.regs.pc = 0x28;
+                (0, 4)
+            }
+            0xF0 => {
+                // LDH A, (a8)
+                let tmp = self.memory.read_byte(self.regs.pc + 1)?;
+                self.regs.a = self.memory.read_byte(tmp as u16 | 0xFF00)?;
+                (2, 3)
+            }
+            0xF1 => {
+                // POP AF
+                let af = self.pop16()?;
+                self.regs.set_af(af);
+                (1, 3)
+            }
+            0xF2 => {
+                // LD A,(C)
+                let address = self.regs.c as u16 | 0xFF00;
+                self.regs.a = self.memory.read_byte(address)?;
+                (1, 2)
+            }
+            0xF3 => {
+                // DI
+                self.ime = false;
+                self.ei_delay = 0;
+                (1, 1)
+            }
+            0xF5 => {
+                // PUSH AF
+                self.push16(self.regs.af())?;
+                (1, 4)
+            }
+            0xF6 => {
+                // OR d8
+                let src = self.memory.read_byte(self.regs.pc + 1)?;
+                self.alu_or(src);
+                (2, 2)
+            }
+            0xF7 => {
+                // RST 30h
+                self.push16(self.regs.pc + 1)?;
+                self.regs.pc = 0x30;
+                (0, 4)
+            }
+            0xF8 => {
+                // LD HL, SP+r8
+                self.regs.set_flag(CpuFlag::N, false);
+                self.regs.set_flag(CpuFlag::Z, false);
+                let val = self.memory.read_byte(self.regs.pc + 1)?;
+                if (val as usize + self.regs.sp as usize) >= 2usize.pow(8) {
+                    self.regs.set_flag(CpuFlag::C, true);
+                } else {
+                    self.regs.set_flag(CpuFlag::C, false);
                 }
-                _ => unreachable!("Unknown instruction {:02x}", instr),
-            };
+                if (val as usize + self.regs.sp as usize) >= 2usize.pow(4) {
+                    self.regs.set_flag(CpuFlag::H, true);
+                } else {
+                    self.regs.set_flag(CpuFlag::H, false);
+                }
+                self.regs.set_hl(self.regs.sp.wrapping_add(val as u16));
+                (2, 3)
+            }
+            0xF9 => {
+                // LD SP, HL
+                self.regs.sp = self.regs.hl();
+                (1, 2)
+            }
+            0xFA => {
+                // LD A,(a16)
+                let address = self.memory.read_word(self.regs.pc + 1)?;
+                self.regs.a = self.memory.read_byte(address)?;
+                (3, 4)
+            }
+            0xFB => {
+                // EI
+                self.ei_delay = 2;
+                (1, 1)
+            }
+            0xFE => {
+                // CP d8
+                let src = self.memory.read_byte(self.regs.pc + 1)?;
+                self.alu_cp(src);
+                (2, 2)
+            }
+            0xFF => {
+                // RST 38h
+                self.push16(self.regs.pc + 1)?;
+                self.regs.pc = 0x38;
+                (0, 4)
+            }
+            _ => {
+                return Err(VmExit::IllegalOpcode {
+                    pc: self.regs.pc,
+                    opcode: instr,
+                })
+            }
+        };
+
+        self.regs.pc = self.regs.pc.wrapping_add(bytes_read);
+        self.memory.tick(machine_cycles * 4);
 
-            self.regs.pc += bytes_read;
-            self.memory.gpu.step(machine_cycles * 4);
+        // EI only enables interrupts after the following instruction
+        if self.ei_delay > 0 {
+            self.ei_delay -= 1;
+            if self.ei_delay == 0 {
+                self.ime = true;
+            }
+        }
+
+        Ok(machine_cycles * 4)
+    }
+
+    /// Wake the CPU up and dispatch a pending interrupt if needed. Returns the
+    /// number of machine cycles spent if no instruction must be executed.
+    fn handle_interrupts(&mut self) -> Result<Option<usize>, VmExit> {
+        let (enabled, requested) = self.interrupt_registers()?;
+        let pending = enabled & requested;
+
+        if self.halted {
+            if pending != 0 {
+                self.halted = false;
+            } else if enabled == 0 {
+                // Nothing will ever wake the CPU up
+                return Err(VmExit::Halt);
+            } else {
+                return Ok(Some(1));
+            }
+        }
 
-            self.memory.interrupt_flags |= self.memory.gpu.interrupt_flags;
-            self.memory.gpu.interrupt_flags = 0;
+        if !self.ime || pending == 0 {
+            return Ok(None);
         }
+
+        // Lowest bit has the highest priority
+        let interrupt = pending.trailing_zeros() as u16;
+        self.memory
+            .poke_byte(IF_ADDRESS, requested & !(1 << interrupt))?;
+        self.ime = false;
+        self.push16(self.regs.pc)?;
+        self.regs.pc = 0x40 + interrupt * 8;
+        Ok(Some(5))
+    }
+
+    /// Interrupts enabled in IE and requested in IF
+    fn interrupt_registers(&mut self) -> Result<(u8, u8), VmExit> {
+        let enabled = self.memory.peek_byte(IE_ADDRESS)? & 0x1F;
+        let requested = self.memory.peek_byte(IF_ADDRESS)? & 0x1F;
+        Ok((enabled, requested))
+    }
+
+    /// Read the 8-bit operand encoded in the low three bits of `instr`
+    fn read_r8(&mut self, instr: u8) -> Result<u8, VmExit> {
+        Ok(match instr & 0x7 {
+            0x0 => self.regs.b,
+            0x1 => self.regs.c,
+            0x2 => self.regs.d,
+            0x3 => self.regs.e,
+            0x4 => self.regs.h,
+            0x5 => self.regs.l,
+            0x6 => self.memory.read_byte(self.regs.hl())?,
+            0x7 => self.regs.a,
+            _ => unreachable!(),
+        })
+    }
+
+    /// Write the 8-bit operand encoded in the low three bits of `instr`
+    fn write_r8(&mut self, instr: u8, val: u8) -> Result<(), VmExit> {
+        match instr & 0x7 {
+            0x0 => self.regs.b = val,
+            0x1 => self.regs.c = val,
+            0x2 => self.regs.d = val,
+            0x3 => self.regs.e = val,
+            0x4 => self.regs.h = val,
+            0x5 => self.regs.l = val,
+            0x6 => self.memory.write_byte(self.regs.hl(), val)?,
+            0x7 => self.regs.a = val,
+            _ => unreachable!(),
+        }
+        Ok(())
     }
 
     fn alu_inc8(&mut self, val: u8) -> u8 {
@@ -1192,73 +1602,41 @@ impl Emulator {
         }
     }
 
-    fn alu_rl<'a, F: FnMut(&mut Emulator) -> &mut u8>(
-        &'a mut self,
-        mut get_reg: F,
-    ) {
-        let zero_flag: bool;
+    fn alu_rl(&mut self, val: u8) -> u8 {
         let old_carry = if self.regs.flag(CpuFlag::C) { 1 } else { 0 };
-        let carry: bool;
-        {
-            let val = get_reg(self);
-            carry = (0x80 & *val) == 0x80;
-            *val = (*val << 1) | old_carry;
-            zero_flag = if *val == 0 { true } else { false };
-        }
+        let carry = (0x80 & val) == 0x80;
+        let res = (val << 1) | old_carry;
         self.regs.clear_flags();
         self.regs.set_flag(CpuFlag::C, carry);
-        self.regs.set_flag(CpuFlag::Z, zero_flag);
+        self.regs.set_flag(CpuFlag::Z, res == 0);
+        res
     }
 
-    fn alu_swap<'a, F: FnMut(&mut Emulator) -> &mut u8>(
-        &'a mut self,
-        mut get_reg: F,
-    ) {
-        let zero_flag: bool;
-        let val = get_reg(self);
-        {
-            let lower = *val & 0x0f;
-            *val = (*val >> 4) | (lower << 4);
-            zero_flag = if *val == 0 { true } else { false };
-        }
+    fn alu_swap(&mut self, val: u8) -> u8 {
+        let res = val.rotate_left(4);
         self.regs.clear_flags();
-        self.regs.set_flag(CpuFlag::Z, zero_flag);
+        self.regs.set_flag(CpuFlag::Z, res == 0);
+        res
     }
 
-    fn alu_srl<'a, F: FnMut(&mut Emulator) -> &mut u8>(
-        &'a mut self,
-        mut get_reg: F,
-    ) {
-        let zero_flag: bool;
-        let carry: bool;
-        {
-            let val = get_reg(self);
-            carry = (0x01 & *val) == 0x01;
-            *val = *val >> 1;
-            zero_flag = if *val == 0 { true } else { false };
-        }
+    fn alu_srl(&mut self, val: u8) -> u8 {
+        let carry = (0x01 & val) == 0x01;
+        let res = val >> 1;
         self.regs.clear_flags();
         self.regs.set_flag(CpuFlag::C, carry);
-        self.regs.set_flag(CpuFlag::Z, zero_flag);
+        self.regs.set_flag(CpuFlag::Z, res == 0);
+        res
     }
 
     fn pop16(&mut self) -> Result<u16, VmExit> {
         let res = self.memory.read_word(self.regs.sp)?;
-
-        // self.regs.sp = self.regs.sp.wrapping_add(2);
-        // Ok(res)
-        match self.regs.sp.checked_add(2) {
-            Some(x) => {
-                self.regs.sp = x;
-                Ok(res)
-            },
-            None => Err(VmExit::SpOverflow),
-        }
+        self.regs.sp = self.regs.sp.wrapping_add(2);
+        Ok(res)
     }
 
-    fn push16(&mut self, val: u16) {
-        self.regs.sp -= 2;
-        self.memory.write_word(self.regs.sp, val).unwrap();
+    fn push16(&mut self, val: u16) -> Result<(), VmExit> {
+        self.regs.sp = self.regs.sp.wrapping_sub(2);
+        self.memory.write_word(self.regs.sp, val)
     }
 
     fn bit(&mut self, val: u8, n: u8) {
@@ -1272,3 +1650,238 @@ impl Emulator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+
+    /// An emulator about to run `program` from 0x0000, with the stack at
+    /// 0xFFFE and every register and flag at 0
+    fn emulator(program: &[u8]) -> Emulator<FlatBus> {
+        let mut bus = FlatBus::new();
+        bus.load(0, program);
+        let mut emulator = Emulator::with_bus(bus);
+        emulator.registers_mut().sp = 0xFFFE;
+        emulator
+    }
+
+    /// Clock cycles of the next `count` steps
+    fn cycles(emulator: &mut Emulator<FlatBus>, count: usize) -> Vec<usize> {
+        (0..count)
+            .map(|_| emulator.step_instruction().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn timings() {
+        let mut emulator = emulator(&[
+            0x00, // NOP
+            0x01, 0x34, 0x12, // LD BC, 0x1234
+            0xC5, // PUSH BC
+            0xD1, // POP DE
+            0xCD, 0x10, 0x00, // CALL 0x0010
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0xC9, // RET
+        ]);
+        assert_eq!(cycles(&mut emulator, 6), [4, 12, 16, 12, 24, 16]);
+        assert_eq!(emulator.memory.cycles, 84);
+
+        let regs = emulator.registers();
+        assert_eq!(regs.pc, 0x0009);
+        assert_eq!(regs.sp, 0xFFFE);
+        assert_eq!(regs.de(), 0x1234);
+        assert_eq!(emulator.memory.read_word(0xFFFC).unwrap(), 0x0009);
+    }
+
+    #[test]
+    fn conditional_jumps() {
+        let mut emulator = emulator(&[
+            0xAF, // XOR A
+            0x20, 0x10, // JR NZ, +16
+            0x28, 0x02, // JR Z, +2
+            0x00, 0x00, //
+            0xC4, 0x00, 0x10, // CALL NZ, 0x1000
+            0xCC, 0x00, 0x10, // CALL Z, 0x1000
+        ]);
+        assert_eq!(cycles(&mut emulator, 5), [4, 8, 12, 12, 24]);
+        assert_eq!(emulator.registers().pc, 0x1000);
+        assert_eq!(emulator.memory.read_word(0xFFFC).unwrap(), 0x000D);
+    }
+
+    #[test]
+    fn flags() {
+        let mut emulator = emulator(&[
+            0x3E, 0x42, // LD A, 0x42
+            0xFE, 0x42, // CP 0x42
+            0xFE, 0x43, // CP 0x43
+            0x06, 0x01, // LD B, 0x01
+            0x05, // DEC B
+            0xF6, 0x00, // OR 0x00
+            0xE6, 0x00, // AND 0x00
+        ]);
+        emulator.step_instruction().unwrap();
+
+        emulator.step_instruction().unwrap();
+        let regs = emulator.registers();
+        assert!(regs.flag(CpuFlag::Z) && regs.flag(CpuFlag::N));
+        assert!(!regs.flag(CpuFlag::C));
+        assert_eq!(regs.a, 0x42);
+
+        emulator.step_instruction().unwrap();
+        let regs = emulator.registers();
+        assert!(!regs.flag(CpuFlag::Z) && regs.flag(CpuFlag::C));
+
+        emulator.step_instruction().unwrap();
+        emulator.step_instruction().unwrap();
+        let regs = emulator.registers();
+        assert_eq!(regs.b, 0);
+        assert!(regs.flag(CpuFlag::Z) && regs.flag(CpuFlag::N));
+
+        emulator.step_instruction().unwrap();
+        assert_eq!(emulator.registers().f, 0x00);
+
+        emulator.step_instruction().unwrap();
+        assert_eq!(emulator.registers().f, 0xA0);
+    }
+
+    #[test]
+    fn prefix_cb() {
+        let mut emulator = emulator(&[
+            0x3E, 0xF0, // LD A, 0xF0
+            0xCB, 0x37, // SWAP A
+            0xCB, 0x7F, // BIT 7, A
+            0x06, 0x01, // LD B, 0x01
+            0xCB, 0x38, // SRL B
+        ]);
+        emulator.step_instruction().unwrap();
+
+        emulator.step_instruction().unwrap();
+        assert_eq!(emulator.registers().a, 0x0F);
+        assert_eq!(emulator.registers().f, 0x00);
+
+        emulator.step_instruction().unwrap();
+        assert!(emulator.registers().flag(CpuFlag::Z));
+
+        emulator.step_instruction().unwrap();
+        emulator.step_instruction().unwrap();
+        let regs = emulator.registers();
+        assert_eq!(regs.b, 0);
+        assert!(regs.flag(CpuFlag::Z) && regs.flag(CpuFlag::C));
+        assert_eq!(regs.pc, 0x000A);
+    }
+
+    #[test]
+    fn rst() {
+        let mut program = [0; 0x19];
+        program[0x01] = 0xDF; // RST 0x18
+        program[0x18] = 0xC9; // RET
+        let mut emulator = emulator(&program);
+        assert_eq!(cycles(&mut emulator, 2), [4, 16]);
+        assert_eq!(emulator.registers().pc, 0x18);
+        assert_eq!(emulator.registers().sp, 0xFFFC);
+        assert_eq!(emulator.memory.read_word(0xFFFC).unwrap(), 0x0002);
+
+        assert_eq!(cycles(&mut emulator, 1), [16]);
+        assert_eq!(emulator.registers().pc, 0x02);
+        assert_eq!(emulator.registers().sp, 0xFFFE);
+    }
+
+    #[test]
+    fn stack_wraps() {
+        let mut emulator = emulator(&[
+            0x31, 0x01, 0x00, // LD SP, 0x0001
+            0x01, 0x34, 0x12, // LD BC, 0x1234
+            0xC5, // PUSH BC
+            0xD1, // POP DE
+        ]);
+        cycles(&mut emulator, 3);
+        assert_eq!(emulator.registers().sp, 0xFFFF);
+        assert_eq!(emulator.memory.read_byte(0xFFFF).unwrap(), 0x34);
+        assert_eq!(emulator.memory.read_byte(0x0000).unwrap(), 0x12);
+
+        cycles(&mut emulator, 1);
+        assert_eq!(emulator.registers().sp, 0x0001);
+        assert_eq!(emulator.registers().de(), 0x1234);
+    }
+
+    #[test]
+    fn pc_wraps() {
+        let mut emulator = emulator(&[
+            0x18, 0xFE, // JR -2
+        ]);
+        cycles(&mut emulator, 2);
+        assert_eq!(emulator.registers().pc, 0x0000);
+    }
+
+    #[test]
+    fn interrupt_dispatch() {
+        let mut emulator = emulator(&[
+            0xFB, // EI
+            0x00, // NOP
+            0x00, // NOP
+        ]);
+        emulator.memory.write_byte(IE_ADDRESS, 0x05).unwrap();
+        emulator.memory.write_byte(IF_ADDRESS, 0x04).unwrap();
+
+        // EI only takes effect after the next instruction
+        emulator.step_instruction().unwrap();
+        assert!(!emulator.ime());
+        emulator.step_instruction().unwrap();
+        assert!(emulator.ime());
+
+        assert_eq!(emulator.step_instruction().unwrap(), 20);
+        assert_eq!(emulator.registers().pc, 0x0050);
+        assert!(!emulator.ime());
+        assert_eq!(emulator.memory.read_byte(IF_ADDRESS).unwrap(), 0x00);
+        assert_eq!(emulator.memory.read_word(0xFFFC).unwrap(), 0x0002);
+    }
+
+    #[test]
+    fn halt_until_interrupt() {
+        let mut emulator = emulator(&[
+            0x76, // HALT
+            0x3C, // INC A
+        ]);
+        emulator.memory.write_byte(IE_ADDRESS, 0x01).unwrap();
+
+        emulator.step_instruction().unwrap();
+        assert!(emulator.halted());
+        assert_eq!(cycles(&mut emulator, 3), [4, 4, 4]);
+        assert_eq!(emulator.registers().pc, 0x0001);
+
+        // With interrupts disabled the CPU wakes up without dispatching
+        emulator.memory.write_byte(IF_ADDRESS, 0x01).unwrap();
+        emulator.step_instruction().unwrap();
+        assert!(!emulator.halted());
+        assert_eq!(emulator.registers().a, 1);
+        assert_eq!(emulator.registers().pc, 0x0002);
+    }
+
+    #[test]
+    fn halt_without_interrupt_enabled() {
+        let mut emulator = emulator(&[0x76]);
+        emulator.step_instruction().unwrap();
+        assert!(matches!(emulator.step_instruction(), Err(VmExit::Halt)));
+    }
+
+    #[test]
+    fn halt_bug() {
+        let mut emulator = emulator(&[
+            0x76, // HALT
+            0x3C, // INC A
+            0x00, // NOP
+        ]);
+        emulator.memory.write_byte(IE_ADDRESS, 0x01).unwrap();
+        emulator.memory.write_byte(IF_ADDRESS, 0x01).unwrap();
+
+        // An interrupt is pending but disabled, INC A runs twice
+        emulator.step_instruction().unwrap();
+        assert!(!emulator.halted());
+        emulator.step_instruction().unwrap();
+        assert_eq!(emulator.registers().pc, 0x0001);
+        emulator.step_instruction().unwrap();
+        assert_eq!(emulator.registers().pc, 0x0002);
+        assert_eq!(emulator.registers().a, 2);
+    }
+}
@@ -0,0 +1,669 @@
+//! GDB remote serial protocol server, letting GDB compatible frontends
+//! debug the emulated program over TCP
+//!
+//! The stub answers GDB whenever the `Debugger` stops, and is used as its
+//! `on_break` callback. Registers are described to GDB by a target
+//! description: A, F, B, C, D, E, H and L of 8 bits then SP and PC of 16.
+//! Software and hardware breakpoints are both debugger breakpoints, and
+//! watchpoints are kept by the MMU.
+
+use crate::debugger::{Breakpoint, Debugger, Flow};
+use crate::emulator::Emulator;
+use crate::watch::{WatchKind, Watchpoint};
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// Sent by GDB to stop execution, outside of packets
+const INTERRUPT: u8 = 0x03;
+
+/// Largest packet GDB may send, in bytes
+const PACKET_SIZE: usize = 0x4000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.sm83.core">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="f" bitsize="8"/>
+    <reg name="b" bitsize="8"/>
+    <reg name="c" bitsize="8"/>
+    <reg name="d" bitsize="8"/>
+    <reg name="e" bitsize="8"/>
+    <reg name="h" bitsize="8"/>
+    <reg name="l" bitsize="8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Number of registers of the target description
+const REGISTERS: usize = 10;
+
+/// Connection to GDB
+pub struct GdbStub {
+    stream: TcpStream,
+
+    /// GDB resumed execution and waits for it to stop
+    running: bool,
+
+    /// GDB detached or the connection ended, execution goes on without it
+    detached: bool,
+}
+
+/// What to do with a packet
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Packet(String),
+
+    /// Resume execution, stepping a single instruction if `step`
+    Resume {
+        step: bool,
+    },
+
+    /// Detach after replying
+    Detach(String),
+
+    Kill,
+}
+
+impl GdbStub {
+    /// Wait for GDB to connect on `address`
+    pub fn listen<A: ToSocketAddrs>(address: A) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            running: false,
+            detached: false,
+        })
+    }
+
+    /// Whether GDB asked to stop execution since the last call, without
+    /// blocking
+    pub fn poll_interrupt(&mut self) -> bool {
+        if self.detached {
+            return false;
+        }
+        let mut byte = [0];
+        let read = self
+            .stream
+            .set_nonblocking(true)
+            .and_then(|_| self.stream.read(&mut byte));
+        let _ = self.stream.set_nonblocking(false);
+        match read {
+            Ok(1) => byte[0] == INTERRUPT,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            _ => {
+                self.detached = true;
+                false
+            }
+        }
+    }
+
+    /// Tell GDB where execution stopped and serve its requests until it
+    /// resumes execution, detaches or kills the program
+    pub fn on_break(
+        &mut self,
+        debugger: &mut Debugger,
+        emulator: &mut Emulator,
+    ) -> Flow {
+        if self.detached {
+            return Flow::Continue;
+        }
+        debugger.take_report();
+        let stop = stop_reply(debugger);
+        match self.serve(debugger, emulator, &stop) {
+            Ok(flow) => flow,
+            Err(_) => {
+                // Nobody to report to anymore
+                self.detached = true;
+                Flow::Continue
+            }
+        }
+    }
+
+    fn serve(
+        &mut self,
+        debugger: &mut Debugger,
+        emulator: &mut Emulator,
+        stop: &str,
+    ) -> io::Result<Flow> {
+        if self.running {
+            self.running = false;
+            self.send(stop)?;
+        }
+        loop {
+            let packet = self.receive()?;
+            match handle(&packet, debugger, emulator, stop) {
+                Reply::Packet(reply) => self.send(&reply)?,
+                Reply::Resume { step } => {
+                    if step {
+                        debugger.interrupt();
+                    }
+                    self.running = true;
+                    return Ok(Flow::Continue);
+                }
+                Reply::Detach(reply) => {
+                    self.send(&reply)?;
+                    self.detached = true;
+                    return Ok(Flow::Continue);
+                }
+                Reply::Kill => return Ok(Flow::Quit),
+            }
+        }
+    }
+
+    /// Read the next packet, acknowledging it
+    fn receive(&mut self) -> io::Result<String> {
+        loop {
+            // Skip acknowledgements and interrupts while stopped
+            while self.read_byte()? != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte if data.len() < PACKET_SIZE => data.push(byte),
+                    _ => {}
+                }
+            }
+            let digits = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    /// Send a packet until GDB acknowledges it
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let data = escape(data);
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            match self.read_byte()? {
+                b'-' => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Err(ErrorKind::UnexpectedEof.into()),
+            _ => Ok(byte[0]),
+        }
+    }
+}
+
+/// Answer a packet
+fn handle(
+    packet: &str,
+    debugger: &mut Debugger,
+    emulator: &mut Emulator,
+    stop: &str,
+) -> Reply {
+    let reply = |text: &str| Reply::Packet(text.to_string());
+    let command = packet.chars().next().unwrap_or_default();
+    let args = &packet[command.len_utf8().min(packet.len())..];
+    match command {
+        '?' => reply(stop),
+        'g' => Reply::Packet(
+            (0..REGISTERS)
+                .map(|n| read_register(emulator, n).unwrap_or_default())
+                .collect(),
+        ),
+        'G' => {
+            let mut args = args;
+            for n in 0..REGISTERS {
+                let width = register_width(n) * 2;
+                if args.len() < width {
+                    break;
+                }
+                let (value, rest) = args.split_at(width);
+                if write_register(emulator, n, value).is_none() {
+                    return reply("E01");
+                }
+                args = rest;
+            }
+            reply("OK")
+        }
+        'p' => usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|n| read_register(emulator, n))
+            .map_or_else(|| reply("E01"), Reply::Packet),
+        'P' => {
+            let written = args.split_once('=').and_then(|(n, value)| {
+                let n = usize::from_str_radix(n, 16).ok()?;
+                write_register(emulator, n, value)
+            });
+            reply(if written.is_some() { "OK" } else { "E01" })
+        }
+        'm' => match parse_memory_range(args) {
+            Some((address, length)) => Reply::Packet(
+                (0..length)
+                    .map(|i| {
+                        let address = address.wrapping_add(i);
+                        let val = emulator.peek_memory(address).unwrap_or(0xFF);
+                        format!("{:02x}", val)
+                    })
+                    .collect(),
+            ),
+            None => reply("E01"),
+        },
+        'M' => {
+            let written = args.split_once(':').and_then(|(range, data)| {
+                let (address, length) = parse_memory_range(range)?;
+                let bytes = parse_bytes(data)?;
+                if bytes.len() != length as usize {
+                    return None;
+                }
+                for (i, &byte) in bytes.iter().enumerate() {
+                    let address = address.wrapping_add(i as u16);
+                    emulator.write_memory(address, byte).ok()?;
+                }
+                Some(())
+            });
+            reply(if written.is_some() { "OK" } else { "E01" })
+        }
+        'c' | 's' => {
+            if let Ok(address) = u16::from_str_radix(args, 16) {
+                emulator.registers_mut().pc = address;
+            }
+            Reply::Resume {
+                step: command == 's',
+            }
+        }
+        'Z' | 'z' => match parse_point(args) {
+            Some(point) => {
+                set_point(debugger, emulator, point, command == 'Z');
+                reply("OK")
+            }
+            // Unsupported kinds of breakpoints
+            None => reply(""),
+        },
+        'D' => Reply::Detach("OK".to_string()),
+        'k' => Reply::Kill,
+        'H' => reply("OK"),
+        'q' => query(packet),
+        _ => reply(""),
+    }
+}
+
+/// Answer a general query, an empty reply meaning it isn't supported
+fn query(packet: &str) -> Reply {
+    let reply = |text: &str| Reply::Packet(text.to_string());
+    if packet.starts_with("qSupported") {
+        return Reply::Packet(format!(
+            "PacketSize={:x};qXfer:features:read+",
+            PACKET_SIZE
+        ));
+    }
+    if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        return match parse_memory_range(args) {
+            Some((offset, length)) => {
+                let start = (offset as usize).min(TARGET_XML.len());
+                let end = (start + length as usize).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                Reply::Packet(format!("{}{}", more, &TARGET_XML[start..end]))
+            }
+            None => reply("E01"),
+        };
+    }
+    match packet {
+        "qAttached" => reply("1"),
+        "qC" => reply("QC1"),
+        "qfThreadInfo" => reply("m1"),
+        "qsThreadInfo" => reply("l"),
+        _ => reply(""),
+    }
+}
+
+/// Stop reply for where execution stopped, naming the watchpoint hit
+fn stop_reply(debugger: &mut Debugger) -> String {
+    match debugger.take_watch_hit() {
+        Some(hit) => {
+            let kind = match hit.watchpoint.kind {
+                WatchKind::Read => "rwatch",
+                WatchKind::Write | WatchKind::Change => "watch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T05{}:{:04x};", kind, hit.address)
+        }
+        None => "S05".to_string(),
+    }
+}
+
+/// Breakpoint or watchpoint of a `Z` or `z` packet
+enum Point {
+    Breakpoint(u16),
+    Watchpoint(Watchpoint),
+}
+
+/// Parse the `TYPE,ADDR,KIND` arguments of `Z` and `z` packets
+fn parse_point(args: &str) -> Option<Point> {
+    let mut fields = args.split(',');
+    let kind = fields.next()?;
+    let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let length = u16::from_str_radix(fields.next()?, 16).ok()?.max(1);
+    let watchpoint = |kind| Watchpoint {
+        start: address,
+        end: address.saturating_add(length - 1),
+        kind,
+        value: None,
+    };
+    match kind {
+        // Software and hardware breakpoints
+        "0" | "1" => Some(Point::Breakpoint(address)),
+        "2" => Some(Point::Watchpoint(watchpoint(WatchKind::Write))),
+        "3" => Some(Point::Watchpoint(watchpoint(WatchKind::Read))),
+        "4" => Some(Point::Watchpoint(watchpoint(WatchKind::Access))),
+        _ => None,
+    }
+}
+
+fn set_point(
+    debugger: &mut Debugger,
+    emulator: &mut Emulator,
+    point: Point,
+    insert: bool,
+) {
+    match point {
+        Point::Breakpoint(address) => {
+            let breakpoint = Breakpoint {
+                bank: None,
+                address,
+            };
+            if insert {
+                debugger.add_breakpoint(breakpoint);
+            } else {
+                debugger.remove_breakpoint(breakpoint);
+            }
+        }
+        Point::Watchpoint(watchpoint) => {
+            if insert {
+                emulator.memory.add_watchpoint(watchpoint);
+            } else {
+                emulator.memory.remove_watchpoint(watchpoint);
+            }
+        }
+    }
+}
+
+/// Size of register `n` in bytes
+fn register_width(n: usize) -> usize {
+    if n < 8 {
+        1
+    } else {
+        2
+    }
+}
+
+/// Register `n` in hexadecimal, in target byte order
+fn read_register(emulator: &Emulator, n: usize) -> Option<String> {
+    let regs = emulator.registers();
+    let bytes = [regs.a, regs.f, regs.b, regs.c, regs.d, regs.e];
+    let value = match n {
+        0..=5 => bytes[n] as u16,
+        6 => regs.h as u16,
+        7 => regs.l as u16,
+        8 => regs.sp,
+        9 => regs.pc,
+        _ => return None,
+    };
+    Some(match register_width(n) {
+        1 => format!("{:02x}", value),
+        _ => format!("{:02x}{:02x}", value as u8, value >> 8),
+    })
+}
+
+/// Set register `n` from hexadecimal in target byte order
+fn write_register(
+    emulator: &mut Emulator,
+    n: usize,
+    value: &str,
+) -> Option<()> {
+    let bytes = parse_bytes(value)?;
+    if bytes.len() != register_width(n) {
+        return None;
+    }
+    let regs = emulator.registers_mut();
+    match n {
+        0 => regs.a = bytes[0],
+        1 => regs.f = bytes[0] & 0xF0,
+        2 => regs.b = bytes[0],
+        3 => regs.c = bytes[0],
+        4 => regs.d = bytes[0],
+        5 => regs.e = bytes[0],
+        6 => regs.h = bytes[0],
+        7 => regs.l = bytes[0],
+        8 => regs.sp = u16::from_le_bytes([bytes[0], bytes[1]]),
+        9 => regs.pc = u16::from_le_bytes([bytes[0], bytes[1]]),
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Parse the `ADDR,LENGTH` arguments of memory packets
+fn parse_memory_range(args: &str) -> Option<(u16, u16)> {
+    let (address, length) = args.split_once(',')?;
+    let address = u32::from_str_radix(address, 16).ok()?;
+    let length = u32::from_str_radix(length, 16).ok()?;
+    // GDB may ask for addresses past the 16-bit address space
+    let length = length.min(0x1_0000u32.saturating_sub(address));
+    Some((address as u16, length.min(PACKET_SIZE as u32 / 2) as u16))
+}
+
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Escape the characters packets can't contain
+fn escape(data: &str) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for c in data.chars() {
+        match c {
+            '$' | '#' | '}' | '*' => {
+                escaped.push('}');
+                escaped.push((c as u8 ^ 0x20) as char);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(text: &str) -> Reply {
+        Reply::Packet(text.to_string())
+    }
+
+    #[test]
+    fn access_watchpoint() {
+        let mut emulator = Emulator::with_program(&[
+            0xFA, 0x00, 0xC0, // LD A, (0xC000)
+            0xEA, 0x01, 0xC0, // LD (0xC001), A
+        ]);
+        let mut debugger = Debugger::new();
+        let reply = handle("Z4,c000,2", &mut debugger, &mut emulator, "S05");
+        assert_eq!(reply, packet("OK"));
+
+        debugger.execute(&mut emulator, "s").unwrap();
+        assert_eq!(stop_reply(&mut debugger), "T05awatch:c000;");
+        debugger.execute(&mut emulator, "s").unwrap();
+        assert_eq!(stop_reply(&mut debugger), "T05awatch:c001;");
+
+        let reply = handle("z4,c000,2", &mut debugger, &mut emulator, "S05");
+        assert_eq!(reply, packet("OK"));
+        assert!(emulator.memory.watchpoints().is_empty());
+    }
+    #[test]
+    fn registers() {
+        let mut emulator = Emulator::with_program(&[]);
+        let mut debugger = Debugger::new();
+        let mut handle =
+            |text: &str| handle(text, &mut debugger, &mut emulator, "");
+        assert_eq!(handle("g"), packet("0180001300d8014dfeff0001"));
+        assert_eq!(handle("p8"), packet("feff"));
+        assert_eq!(handle("pa"), packet("E01"));
+        assert_eq!(handle("P1=ff"), packet("OK"));
+        assert_eq!(handle("P9=3412"), packet("OK"));
+        assert_eq!(handle("P9=34"), packet("E01"));
+        assert_eq!(handle("Pa=00"), packet("E01"));
+        assert_eq!(handle("g"), packet("01f0001300d8014dfeff3412"));
+        assert_eq!(handle("G0203"), packet("OK"));
+        assert_eq!(handle("g"), packet("0200001300d8014dfeff3412"));
+        assert_eq!(handle("Gzz"), packet("E01"));
+        assert_eq!(handle("c0150"), Reply::Resume { step: false });
+        assert_eq!(handle("p9"), packet("5001"));
+    }
+
+    #[test]
+    fn memory() {
+        let mut emulator = Emulator::with_program(&[0x12, 0x34]);
+        let mut debugger = Debugger::new();
+        let mut handle =
+            |text: &str| handle(text, &mut debugger, &mut emulator, "");
+        assert_eq!(handle("m100,3"), packet("123400"));
+        assert_eq!(handle("mfffe,4"), packet("0000"));
+        assert_eq!(handle("m100"), packet("E01"));
+        assert_eq!(handle("Mc000,2:abCD"), packet("OK"));
+        assert_eq!(handle("mc000,2"), packet("abcd"));
+        assert_eq!(handle("Mc000,2:ab"), packet("E01"));
+        assert_eq!(handle("Mc000,1:a"), packet("E01"));
+        assert_eq!(handle("Mc000,1:zz"), packet("E01"));
+
+        assert_eq!(parse_memory_range("c000,10"), Some((0xC000, 0x10)));
+        assert_eq!(parse_memory_range("fff0,100"), Some((0xFFF0, 0x10)));
+        assert_eq!(parse_memory_range("10000,1"), Some((0, 0)));
+        assert_eq!(
+            parse_memory_range("0,ffff"),
+            Some((0, PACKET_SIZE as u16 / 2))
+        );
+        assert_eq!(parse_memory_range("c000"), None);
+        assert_eq!(parse_memory_range("x,1"), None);
+        assert_eq!(parse_bytes(""), Some(Vec::new()));
+        assert_eq!(parse_bytes("00fF7a"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(parse_bytes("0"), None);
+        assert_eq!(parse_bytes("é0"), None);
+    }
+
+    #[test]
+    fn points() {
+        assert!(matches!(
+            parse_point("0,150,1"),
+            Some(Point::Breakpoint(0x150))
+        ));
+        assert!(matches!(
+            parse_point("1,4000,1"),
+            Some(Point::Breakpoint(0x4000))
+        ));
+        for (args, kind, start, end) in [
+            ("2,c000,1", WatchKind::Write, 0xC000, 0xC000),
+            ("3,c000,4", WatchKind::Read, 0xC000, 0xC003),
+            ("4,c000,0", WatchKind::Access, 0xC000, 0xC000),
+            ("2,fffe,10", WatchKind::Write, 0xFFFE, 0xFFFF),
+        ] {
+            match parse_point(args) {
+                Some(Point::Watchpoint(watchpoint)) => {
+                    assert_eq!(watchpoint.kind, kind);
+                    assert_eq!(
+                        (watchpoint.start, watchpoint.end),
+                        (start, end)
+                    );
+                    assert_eq!(watchpoint.value, None);
+                }
+                _ => panic!("{}", args),
+            }
+        }
+        assert!(parse_point("5,c000,1").is_none());
+        assert!(parse_point("0,c000").is_none());
+        assert!(parse_point("0,g,1").is_none());
+
+        let mut emulator = Emulator::with_program(&[]);
+        let mut debugger = Debugger::new();
+        let reply = handle("Z5,c000,1", &mut debugger, &mut emulator, "");
+        assert_eq!(reply, packet(""));
+    }
+
+    #[test]
+    fn queries() {
+        let mut emulator = Emulator::with_program(&[]);
+        let mut debugger = Debugger::new();
+        let mut handle =
+            |text: &str| handle(text, &mut debugger, &mut emulator, "S05");
+        assert_eq!(
+            handle("qSupported:multiprocess+;swbreak+"),
+            packet("PacketSize=4000;qXfer:features:read+")
+        );
+        assert_eq!(handle("?"), packet("S05"));
+        assert_eq!(handle("qAttached"), packet("1"));
+        assert_eq!(handle("qUnknown"), packet(""));
+        assert_eq!(handle(""), packet(""));
+
+        let mut xml = String::new();
+        loop {
+            let args =
+                format!("qXfer:features:read:target.xml:{:x},40", xml.len());
+            match handle(&args) {
+                Reply::Packet(part) if part.starts_with('m') => {
+                    xml.push_str(&part[1..])
+                }
+                Reply::Packet(part) if part.starts_with('l') => {
+                    xml.push_str(&part[1..]);
+                    break;
+                }
+                reply => panic!("{:?}", reply),
+            }
+        }
+        assert_eq!(xml, TARGET_XML);
+        assert_eq!(handle("qXfer:features:read:target.xml:0"), packet("E01"));
+    }
+
+    #[test]
+    fn framing() {
+        assert_eq!(escape("a$b#c}d*e"), "a}\x04b}\x03c}]d}\x0ae");
+        assert_eq!(checksum(b"OK"), 0x9A);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut gdb =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stub = GdbStub {
+            stream: listener.accept().unwrap().0,
+            running: false,
+            detached: false,
+        };
+        gdb.write_all(b"+\x03$m0,1#00$m0,1#fa").unwrap();
+        assert_eq!(stub.receive().unwrap(), "m0,1");
+        let mut acks = [0; 2];
+        gdb.read_exact(&mut acks).unwrap();
+        assert_eq!(&acks, b"-+");
+
+        // The first copy is rejected
+        gdb.write_all(b"-+").unwrap();
+        stub.send("$").unwrap();
+        let mut sent = [0; 12];
+        gdb.read_exact(&mut sent).unwrap();
+        assert_eq!(&sent, b"$}\x04#81$}\x04#81");
+    }
+}
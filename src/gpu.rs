@@ -1,12 +1,45 @@
 use crate::emulator::VmExit;
-
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Condvar, Mutex};
+use crate::model::Model;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::triple_buffer;
 
 pub const WIDTH: u32 = 160;
 pub const HEIGHT: u32 = 144;
 pub const FRAME_LENGTH: usize = WIDTH as usize * HEIGHT as usize * 4;
 
+/// Writing end of the triple buffer completed frames are published in
+pub type FrameWriter = triple_buffer::Writer<[u8; FRAME_LENGTH]>;
+
+/// Reading end of the triple buffer completed frames are published in
+pub type FrameReader = triple_buffer::Reader<[u8; FRAME_LENGTH]>;
+
+/// RGBA colours of the four shades, from lightest to darkest
+pub type Palette = [[u8; 4]; 4];
+
+pub const GREY_PALETTE: Palette = [
+    [0xFF, 0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA, 0xFF],
+    [0x55, 0x55, 0x55, 0xFF],
+    [0x00, 0x00, 0x00, 0xFF],
+];
+
+/// Colours of the original DMG screen
+pub const GREEN_PALETTE: Palette = [
+    [0x9B, 0xBC, 0x0F, 0xFF],
+    [0x8B, 0xAC, 0x0F, 0xFF],
+    [0x30, 0x62, 0x30, 0xFF],
+    [0x0F, 0x38, 0x0F, 0xFF],
+];
+
+/// Colours of the Game Boy Pocket screen
+pub const POCKET_PALETTE: Palette = [
+    [0xC4, 0xCF, 0xA1, 0xFF],
+    [0x8B, 0x95, 0x6D, 0xFF],
+    [0x4D, 0x53, 0x3C, 0xFF],
+    [0x1F, 0x1F, 0x1F, 0xFF],
+];
+
+#[derive(Clone, Copy)]
 enum GpuMode {
     /// Horizontal blanking
     HBlank = 0,
@@ -22,33 +55,75 @@ enum GpuMode {
 }
 
 pub struct Gpu {
-    /// Channel to send pixel data in
-    channel: Option<Sender<Box<[u8; FRAME_LENGTH]>>>,
-    pair: Option<Arc<(Mutex<bool>, Condvar)>>,
+    /// Where completed frames are published for the frontend
+    output: Option<FrameWriter>,
+
+    /// Whether lines are drawn and frames sent, cleared to skip frames
+    rendering: bool,
+
+    model: Model,
+    palette: Palette,
 
     frame: [u8; FRAME_LENGTH],
+
+    /// Number of frames completed since power on
+    frames: u64,
+
     mode: GpuMode,
     modeclock: usize,
     line: u8,
+
+    /// What LY reads as whatever the line, `None` for the line
+    fixed_ly: Option<u8>,
+
     graphics_ram: Vec<u8>,
+    oam: Vec<u8>,
+    lcd_control: u8,
+    status: u8,
     scroll_x: u8,
     scroll_y: u8,
+    line_compare: u8,
+    bg_palette: u8,
+    obj_palette0: u8,
+    obj_palette1: u8,
+    window_y: u8,
+    window_x: u8,
     pub interrupt_flags: u8,
 }
 
+impl Default for Gpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Gpu {
     pub fn new() -> Gpu {
         Gpu {
-            channel: None,
-            pair: None,
+            output: None,
+            rendering: true,
+
+            model: Model::Dmg,
+            palette: GREY_PALETTE,
 
             frame: [0; WIDTH as usize * HEIGHT as usize * 4],
+            frames: 0,
             mode: GpuMode::HBlank,
             modeclock: 0,
             line: 0,
+            fixed_ly: None,
             graphics_ram: vec![0; 8192],
+            oam: vec![0; 160],
+            lcd_control: 0,
+            status: 0,
             scroll_x: 0,
             scroll_y: 0,
+            line_compare: 0,
+            bg_palette: 0,
+            obj_palette0: 0,
+            obj_palette1: 0,
+            window_y: 0,
+            window_x: 0,
             interrupt_flags: 0,
         }
     }
@@ -56,31 +131,64 @@ impl Gpu {
     pub fn read_byte(&mut self, address: usize) -> Result<u8, VmExit> {
         match address {
             0x8000..=0x9FFF => Ok(self.graphics_ram[address - 0x8000]),
-            0xFE00..=0xFE9F => panic!("sprite data"),
-            0xFF40 => { // LCDC - LCD Control (R/W)
-                Ok(0)
+            0xFE00..=0xFE9F => Ok(self.oam[address - 0xFE00]),
+            0xFF40 => {
+                // LCDC - LCD Control (R/W)
+                Ok(self.lcd_control)
             }
             0xFF41 => {
                 // STAT - LCDC Status (R/W)
-                let mut res: u8 = 0;
                 let mode = match self.mode {
                     GpuMode::HBlank => 0,
                     GpuMode::VBlank => 1,
                     GpuMode::OAMAccess => 2,
                     GpuMode::VRAMAccess => 3,
                 };
-                res |= mode & 0b11;
-                Ok(res)
+                let coincidence = if self.line == self.line_compare {
+                    0b100
+                } else {
+                    0
+                };
+                Ok(0x80 | self.status | coincidence | mode)
             }
             0xFF42 => {
                 // SCY - Scroll Y (R/W)
                 Ok(self.scroll_y)
             }
+            0xFF43 => {
+                // SCX - Scroll X (R/W)
+                Ok(self.scroll_x)
+            }
             0xFF44 => {
                 // LY - LCDC Y-Coordinate (R)
-                Ok(self.line)
+                Ok(self.fixed_ly.unwrap_or(self.line))
+            }
+            0xFF45 => {
+                // LYC - LY Compare (R/W)
+                Ok(self.line_compare)
+            }
+            0xFF47 => {
+                // BGP - BG Palette Data (R/W) - Non CGB Mode Only
+                Ok(self.bg_palette)
+            }
+            0xFF48 => {
+                // OBP0 - Object Palette 0 Data (R/W)
+                Ok(self.obj_palette0)
+            }
+            0xFF49 => {
+                // OBP1 - Object Palette 1 Data (R/W)
+                Ok(self.obj_palette1)
             }
-            _ => panic!("Trying to read at GPU I/O 0x{:04x}", address),
+            0xFF4A => {
+                // WY - Window Y Position (R/W)
+                Ok(self.window_y)
+            }
+            0xFF4B => {
+                // WX - Window X Position minus 7 (R/W)
+                Ok(self.window_x)
+            }
+            // Not a GPU register, the MMU doesn't send those here
+            _ => Ok(0xFF),
         }
     }
 
@@ -93,67 +201,239 @@ impl Gpu {
             0x8000..=0x9FFF => {
                 //print!("Writing 0x{:02x} at 0x{:04x}\n", val, address);
                 self.graphics_ram[address - 0x8000] = val;
-                Ok(())
+            }
+            0xFE00..=0xFE9F => {
+                // OAM - Sprite Attribute Table
+                self.oam[address - 0xFE00] = val;
             }
             0xFF40 => {
                 // LCDC - LCD Control (R/W)
-                // print!("LCD Control = 0b{:08b}\n", val);
-                Ok(())
+                self.lcd_control = val;
             }
             0xFF41 => {
                 // STAT - LCDC Status (R/W)
-                if val == 0 {
-                    Ok(())
-                } else {
-                    panic!("unhandled write to LCDC status")
-                }
+                // Only the interrupt selection bits are writable
+                self.status = val & 0x78;
             }
             0xFF42 => {
                 // SCY - Scroll Y (R/W)
                 self.scroll_y = val;
-                Ok(())
             }
             0xFF43 => {
                 // SCX - Scroll X (R/W)
                 self.scroll_x = val;
-                Ok(())
+            }
+            0xFF44 => {
+                // LY - LCDC Y-Coordinate (R)
+            }
+            0xFF45 => {
+                // LYC - LY Compare (R/W)
+                self.line_compare = val;
             }
             0xFF47 => {
                 // BGP - BG Palette Data (R/W) - Non CGB Mode Only
-                print!("BG Palette Data = 0b{:08b}\n", val);
-                Ok(())
+                self.bg_palette = val;
             }
             0xFF48 => {
                 // OBP0 - Object Palette 0 Data (R/W)
-                print!("Object Palette 0 Data = 0b{:08b}\n", val);
-                Ok(())
+                self.obj_palette0 = val;
             }
             0xFF49 => {
                 // OBP1 - Object Palette 1 Data (R/W)
-                print!("Object Palette 1 Data = 0b{:08b}\n", val);
-                Ok(())
+                self.obj_palette1 = val;
             }
             0xFF4A => {
                 // WY - Window Y Position (R/W)
-                print!("Window Y Position = 0b{:08b}\n", val);
-                Ok(())
+                self.window_y = val;
             }
             0xFF4B => {
                 // WX - Window X Position minus 7 (R/W)
-                print!("Window X Position minus 7 = 0b{:08b}\n", val);
-                Ok(())
+                self.window_x = val;
+            }
+            _ => {
+                // Not a GPU register, the MMU doesn't send those here
             }
-            _ =>  panic!("Trying to write at GPU I/O 0x{:04x}", address),
         }
+        Ok(())
+    }
+
+    /// Power cycle the GPU, staying in sync with the frontend
+    pub fn reset(&mut self) {
+        *self = Gpu {
+            output: self.output.take(),
+            rendering: self.rendering,
+            model: self.model,
+            palette: self.palette,
+            frames: self.frames,
+            ..Gpu::new()
+        };
+    }
+
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
     }
 
-    pub fn sync(
+    /// Colours used to render the four shades
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    /// Make LY read as `ly` whatever line is drawn, or as the line again
+    /// with `None`
+    pub fn set_fixed_ly(&mut self, ly: Option<u8>) {
+        self.fixed_ly = ly;
+    }
+
+    /// Corrupt OAM like the DMG does when the increment unit puts an OAM
+    /// address on the bus while the PPU scans it
+    pub fn oam_bug(&mut self) {
+        if !self.model.has_oam_bug() {
+            return;
+        }
+        if let GpuMode::OAMAccess = self.mode {
+            // The PPU reads one 8-byte row per machine cycle, the first row
+            // is never corrupted
+            let row = self.modeclock / 4;
+            if row == 0 || row >= self.oam.len() / 8 {
+                return;
+            }
+            let word =
+                |oam: &[u8], i: usize| oam[i] as u16 | (oam[i + 1] as u16) << 8;
+            let current = row * 8;
+            let previous = current - 8;
+            let a = word(&self.oam, current);
+            let b = word(&self.oam, previous);
+            let c = word(&self.oam, previous + 4);
+            let glitched = ((a ^ c) & (b ^ c)) ^ c;
+            self.oam[current] = glitched as u8;
+            self.oam[current + 1] = (glitched >> 8) as u8;
+            // The rest of the row is copied from the previous one
+            for i in 2..8 {
+                self.oam[current + i] = self.oam[previous + i];
+            }
+        }
+    }
+
+    /// Last rendered frame, as RGBA pixels
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Content of the video RAM, tiles and tile maps
+    pub fn vram(&self) -> &[u8] {
+        &self.graphics_ram
+    }
+
+    /// Content of the object attribute memory
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    /// Colours used to render the four shades
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// LCDC - LCD Control
+    pub fn lcd_control(&self) -> u8 {
+        self.lcd_control
+    }
+
+    /// SCX and SCY, where the viewport starts in the background map
+    pub fn scroll(&self) -> (u8, u8) {
+        (self.scroll_x, self.scroll_y)
+    }
+
+    /// BGP, OBP0 and OBP1, mapping colour numbers to shades
+    pub fn dmg_palettes(&self) -> [u8; 3] {
+        [self.bg_palette, self.obj_palette0, self.obj_palette1]
+    }
+
+    /// Number of frames completed since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Save everything but the frontend synchronisation and the palette
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.frame);
+        writer.write_u64(self.frames);
+        writer.write_u8(self.mode as u8);
+        writer.write_u64(self.modeclock as u64);
+        writer.write_u8(self.line);
+        writer.write_bytes(&self.graphics_ram);
+        writer.write_bytes(&self.oam);
+        for &register in [
+            self.lcd_control,
+            self.status,
+            self.scroll_x,
+            self.scroll_y,
+            self.line_compare,
+            self.bg_palette,
+            self.obj_palette0,
+            self.obj_palette1,
+            self.window_y,
+            self.window_x,
+            self.interrupt_flags,
+        ]
+        .iter()
+        {
+            writer.write_u8(register);
+        }
+    }
+
+    pub fn load_state(
         &mut self,
-        channel: Sender<Box<[u8; FRAME_LENGTH]>>,
-        pair: Arc<(Mutex<bool>, Condvar)>,
-    ) {
-        self.pair = Some(pair);
-        self.channel = Some(channel);
+        reader: &mut StateReader,
+    ) -> Result<(), StateError> {
+        reader.read_into(&mut self.frame, "frame buffer")?;
+        self.frames = reader.read_u64()?;
+        self.mode = match reader.read_u8()? {
+            0 => GpuMode::HBlank,
+            1 => GpuMode::VBlank,
+            2 => GpuMode::OAMAccess,
+            3 => GpuMode::VRAMAccess,
+            _ => return Err(StateError::Invalid("GPU mode")),
+        };
+        self.modeclock = reader.read_u64()? as usize;
+        self.line = reader.read_u8()?;
+        if self.line > 153 {
+            return Err(StateError::Invalid("LY"));
+        }
+        reader.read_into(&mut self.graphics_ram, "VRAM")?;
+        reader.read_into(&mut self.oam, "OAM")?;
+        for register in [
+            &mut self.lcd_control,
+            &mut self.status,
+            &mut self.scroll_x,
+            &mut self.scroll_y,
+            &mut self.line_compare,
+            &mut self.bg_palette,
+            &mut self.obj_palette0,
+            &mut self.obj_palette1,
+            &mut self.window_y,
+            &mut self.window_x,
+            &mut self.interrupt_flags,
+        ] {
+            *register = reader.read_u8()?;
+        }
+        Ok(())
+    }
+
+    /// Stop publishing frames for the frontend
+    pub fn unsync(&mut self) {
+        self.output = None;
+    }
+
+    /// Publish every completed frame in `output`
+    pub fn sync(&mut self, output: FrameWriter) {
+        self.output = Some(output);
+    }
+
+    /// Skip drawing the next frames while `rendering` is false, e.g. when
+    /// fast-forwarding
+    pub fn set_rendering(&mut self, rendering: bool) {
+        self.rendering = rendering;
     }
 
     pub fn step(&mut self, cycle_nb: usize) {
@@ -169,7 +449,9 @@ impl Gpu {
                 if self.modeclock >= 172 {
                     self.modeclock = 0;
                     self.mode = GpuMode::HBlank;
-                    self.render_line(self.line);
+                    if self.rendering {
+                        self.render_line(self.line);
+                    }
                     // Write a scanlime to the framebuffer
                 }
             }
@@ -181,16 +463,10 @@ impl Gpu {
                     if self.line == 143 {
                         self.interrupt_flags |= 0x01;
                         self.mode = GpuMode::VBlank;
-                        // Block thread until previous frame is rendered
-                        if let Some(pair) = &self.pair {
-                            let (lock, cvar) = &**pair;
-                            let mut drawn = lock.lock().unwrap();
-                            while !*drawn {
-                                drawn = cvar.wait(drawn).unwrap();
-                            }
+                        self.frames += 1;
+                        if self.rendering {
+                            self.present();
                         }
-                        // Render full buffer
-                        self.render_frame();
                     } else {
                         self.mode = GpuMode::OAMAccess;
                     }
@@ -212,18 +488,32 @@ impl Gpu {
     fn render_line(&mut self, line: u8) {
         let position_y = line.wrapping_add(self.scroll_y) as usize;
         let tile_row = (position_y / 8) * 32;
+        // LCDC bit 3 selects the tile map at 0x9C00 instead of 0x9800
+        let tile_map = if self.lcd_control & 0x08 == 0x08 {
+            0x1C00
+        } else {
+            0x1800
+        };
         for pixel in 0..160u8 {
             let position_x = pixel.wrapping_add(self.scroll_x) as usize;
             let tile_col = position_x / 8;
-            let tile_address = 0x1800 + tile_row + tile_col;
-            let tile_id = self.graphics_ram[tile_address] as usize;
-            let tile_location = tile_id * 16;
+            let tile_address = tile_map + tile_row + tile_col;
+            let tile_id = self.graphics_ram[tile_address];
+            // LCDC bit 4 selects unsigned tile ids from 0x8000 instead of
+            // signed ones from 0x9000
+            let tile_location = if self.lcd_control & 0x10 == 0x10 {
+                tile_id as usize * 16
+            } else {
+                (0x1000 + tile_id as i8 as isize * 16) as usize
+            };
             let line_in_tile = (position_y % 8) * 2;
-            let data = self.graphics_ram[tile_location + line_in_tile];
+            let low = self.graphics_ram[tile_location + line_in_tile];
+            let high = self.graphics_ram[tile_location + line_in_tile + 1];
             let color_bit = 7 - (position_x % 8);
-            let val = (data >> color_bit) & 0b1;
-            let val = val * 255;
-            let val = [val, val, val, 0xff];
+            let color =
+                ((high >> color_bit) & 0b1) << 1 | (low >> color_bit) & 0b1;
+            let shade = (self.bg_palette >> (color * 2)) & 0b11;
+            let val = self.palette[shade as usize];
 
             let offset = (line as usize * WIDTH as usize + pixel as usize) * 4;
             let pixel_in_frame = &mut self.frame[offset..offset + 4];
@@ -231,14 +521,11 @@ impl Gpu {
         }
     }
 
-    fn render_frame(&mut self) {
-        if let Some(sender) = &self.channel {
-            if let Some(pair) = &self.pair {
-                let (lock, _) = &**pair;
-                let mut drawn = lock.lock().unwrap();
-                *drawn = false;
-            }
-            let _ = sender.send(Box::new(self.frame));
+    /// Publish the current frame for the frontend
+    pub fn present(&mut self) {
+        if let Some(output) = &mut self.output {
+            output.buffer().copy_from_slice(&self.frame);
+            output.publish();
         }
     }
 }
@@ -0,0 +1,200 @@
+//! Hashes of the machine state after every frame, to check that changes to
+//! the emulator don't change its behaviour
+//!
+//! A hash log has one line per frame: the frame number and the hash of
+//! every component, in hexadecimal. Lines starting with `#` are comments.
+
+use crate::emulator::Emulator;
+
+use std::fmt;
+
+/// First line of hash logs, naming the columns
+pub const LOG_HEADER: &str = "# frame cpu wram vram oam framebuffer";
+
+/// Parts of the machine hashed separately, to tell which one diverged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    Cpu,
+    WorkRam,
+    VideoRam,
+    Oam,
+    Framebuffer,
+}
+
+pub const COMPONENTS: [Component; 5] = [
+    Component::Cpu,
+    Component::WorkRam,
+    Component::VideoRam,
+    Component::Oam,
+    Component::Framebuffer,
+];
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Component::Cpu => "CPU registers",
+            Component::WorkRam => "WRAM",
+            Component::VideoRam => "VRAM",
+            Component::Oam => "OAM",
+            Component::Framebuffer => "framebuffer",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Hash of every component, in the order of `COMPONENTS`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHashes([u64; 5]);
+
+impl FrameHashes {
+    pub fn of(emulator: &Emulator) -> FrameHashes {
+        let regs = emulator.registers();
+        let mut cpu = vec![regs.a, regs.f, regs.b, regs.c, regs.d, regs.e];
+        cpu.extend_from_slice(&[regs.h, regs.l]);
+        cpu.extend_from_slice(&regs.sp.to_le_bytes());
+        cpu.extend_from_slice(&regs.pc.to_le_bytes());
+
+        let memory = &emulator.memory;
+        FrameHashes([
+            fnv1a(&cpu),
+            fnv1a(memory.work_ram()),
+            fnv1a(memory.gpu.vram()),
+            fnv1a(memory.gpu.oam()),
+            fnv1a(emulator.framebuffer()),
+        ])
+    }
+
+    /// First component whose hash differs from `other`
+    pub fn diverging(&self, other: &FrameHashes) -> Option<Component> {
+        (0..COMPONENTS.len())
+            .find(|&i| self.0[i] != other.0[i])
+            .map(|i| COMPONENTS[i])
+    }
+
+    /// Line of a hash log for `frame`
+    pub fn to_line(&self, frame: u64) -> String {
+        let mut line = frame.to_string();
+        for hash in &self.0 {
+            line.push_str(&format!(" {:016x}", hash));
+        }
+        line
+    }
+}
+
+/// Parse a hash log, sorted by frame
+pub fn parse_log(text: &str) -> Result<Vec<(u64, FrameHashes)>, String> {
+    let mut log = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = || format!("line {}: invalid hashes", number + 1);
+
+        let mut fields = line.split_whitespace();
+        let frame = fields
+            .next()
+            .and_then(|frame| frame.parse().ok())
+            .ok_or_else(error)?;
+        let mut hashes = [0; 5];
+        for hash in hashes.iter_mut() {
+            *hash = fields
+                .next()
+                .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+                .ok_or_else(error)?;
+        }
+        if fields.next().is_some() {
+            return Err(error());
+        }
+        log.push((frame, FrameHashes(hashes)));
+    }
+    log.sort_by_key(|&(frame, _)| frame);
+    Ok(log)
+}
+
+/// 64-bit FNV-1a hash of `data`
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_vectors() {
+        assert_eq!(fnv1a(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xAF63_DC4C_8601_EC8C);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_F739_67E8);
+    }
+
+    #[test]
+    fn log_round_trip() {
+        let first = FrameHashes([0, 1, 0xFFFF_FFFF_FFFF_FFFF, 0xABC, 42]);
+        let second = FrameHashes([5, 4, 3, 2, 1]);
+        let text = format!(
+            "{}\n{}\n\n  # comment\n{}\n",
+            LOG_HEADER,
+            second.to_line(12),
+            first.to_line(3)
+        );
+        assert_eq!(parse_log(&text), Ok(vec![(3, first), (12, second)]));
+        assert_eq!(
+            first.to_line(3),
+            "3 0000000000000000 0000000000000001 ffffffffffffffff \
+             0000000000000abc 000000000000002a"
+        );
+    }
+
+    #[test]
+    fn log_errors() {
+        let line = FrameHashes([1, 2, 3, 4, 5]).to_line(0);
+        for bad in [
+            "x 1 2 3 4 5",
+            "-1 1 2 3 4 5",
+            "0 1 2 3 4",
+            "0 1 2 3 4 5 6",
+            "0 1 2 3 4 g",
+        ] {
+            assert_eq!(
+                parse_log(&format!("{}\n{}\n", line, bad)),
+                Err("line 2: invalid hashes".to_string()),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn diverging() {
+        let hashes = FrameHashes([1, 2, 3, 4, 5]);
+        assert_eq!(hashes.diverging(&hashes), None);
+        for (i, &component) in COMPONENTS.iter().enumerate() {
+            let mut other = hashes;
+            other.0[i] = 0;
+            assert_eq!(hashes.diverging(&other), Some(component));
+        }
+        assert_eq!(
+            hashes.diverging(&FrameHashes([1, 0, 3, 0, 0])),
+            Some(Component::WorkRam)
+        );
+    }
+
+    #[test]
+    fn components_of_emulator() {
+        let mut emulator = Emulator::with_program(&[0x18, 0xFE]); // JR -2
+        let before = FrameHashes::of(&emulator);
+        assert_eq!(FrameHashes::of(&emulator), before);
+
+        emulator.write_memory(0xC123, 0x45).unwrap();
+        let after = FrameHashes::of(&emulator);
+        assert_eq!(before.diverging(&after), Some(Component::WorkRam));
+        emulator.write_memory(0xFE00, 0x10).unwrap();
+        assert_eq!(
+            after.diverging(&FrameHashes::of(&emulator)),
+            Some(Component::Oam)
+        );
+    }
+}
@@ -0,0 +1,329 @@
+//! Running without a window, driven by an input script or a movie, for
+//! screenshot regression tests
+
+use crate::cli::Options;
+use crate::repl;
+use crate::{
+    fail, load_symbols, save_battery, start_gdb, start_playback,
+    start_recording, write_movie,
+};
+
+use gbemu::debugger::Debugger;
+use gbemu::gdb::GdbStub;
+use gbemu::gpu::{HEIGHT, WIDTH};
+use gbemu::hash::{self, FrameHashes};
+use gbemu::image;
+use gbemu::movie::{Input, Movie};
+use gbemu::profile::Profiler;
+use gbemu::symbols::Symbols;
+use gbemu::trace::Tracer;
+use gbemu::vram;
+use gbemu::{Buttons, Emulator};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Exit code when every frame ran and the final one is as expected
+pub const EXIT_SUCCESS: i32 = 0;
+
+/// Exit code when the emulator stopped on an error
+pub const EXIT_EMULATOR_ERROR: i32 = 2;
+
+/// Exit code when the final frame differs from the expected screenshot
+pub const EXIT_MISMATCH: i32 = 3;
+
+/// Exit code when the machine state diverges from a hash log
+pub const EXIT_DIVERGED: i32 = 4;
+
+/// Functions and addresses listed by profile reports
+const PROFILE_LINES: usize = 20;
+
+/// Buttons to hold from given frames on
+#[derive(Default)]
+pub struct InputScript {
+    /// Frame number and buttons held from it on, sorted by frame
+    events: Vec<(u64, Buttons)>,
+}
+
+impl InputScript {
+    /// Parse a script made of lines like `120 start` or `300 a,right`,
+    /// giving the buttons held from a frame on. A frame with no buttons
+    /// releases them all and `#` starts a comment.
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut events: Vec<(u64, Buttons)> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error =
+                |message: String| format!("line {}: {}", number + 1, message);
+
+            let mut fields = line.split_whitespace();
+            let frame = fields.next().unwrap_or("");
+            let frame = frame.parse().map_err(|_| {
+                error(format!("{} is not a frame number", frame))
+            })?;
+            let buttons =
+                parse_buttons(fields.next().unwrap_or("")).map_err(error)?;
+            if let Some(extra) = fields.next() {
+                return Err(error(format!("unexpected {}", extra)));
+            }
+            if events.last().is_some_and(|&(last, _)| last > frame) {
+                return Err(error("frames must be in order".to_string()));
+            }
+            events.push((frame, buttons));
+        }
+        Ok(InputScript { events })
+    }
+
+    /// Buttons held during `frame`
+    pub fn buttons(&self, frame: u64) -> Buttons {
+        let next = self.events.partition_point(|&(start, _)| start <= frame);
+        match next {
+            0 => Buttons::default(),
+            _ => self.events[next - 1].1,
+        }
+    }
+}
+
+/// Parse a comma separated list of button names
+fn parse_buttons(names: &str) -> Result<Buttons, String> {
+    let mut buttons = Buttons::default();
+    for name in names.split(',').filter(|name| !name.is_empty()) {
+        let button = match name.to_ascii_lowercase().as_str() {
+            "right" => &mut buttons.right,
+            "left" => &mut buttons.left,
+            "up" => &mut buttons.up,
+            "down" => &mut buttons.down,
+            "a" => &mut buttons.a,
+            "b" => &mut buttons.b,
+            "select" => &mut buttons.select,
+            "start" => &mut buttons.start,
+            _ => return Err(format!("unknown button {}", name)),
+        };
+        *button = true;
+    }
+    Ok(buttons)
+}
+
+/// Run until the requested number of frames or an error, writing the
+/// requested screenshots, and return the process exit code
+pub fn run(mut emulator: Emulator, options: &Options) -> i32 {
+    let script = match &options.input {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                fail(&format!("can't read {}: {}", path.display(), e))
+            });
+            InputScript::parse(&text).unwrap_or_else(|e| {
+                fail(&format!("invalid input script {}: {}", path.display(), e))
+            })
+        }
+        None => InputScript::default(),
+    };
+    let playback = start_playback(&mut emulator, options);
+    let mut recording = start_recording(&mut emulator, options);
+    let mut hash_log = options.hash_log.as_ref().map(|path| {
+        create_hash_log(path).unwrap_or_else(|e| {
+            fail(&format!("can't write {}: {}", path.display(), e))
+        })
+    });
+    let expected_hashes = match &options.check_hashes {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                fail(&format!("can't read {}: {}", path.display(), e))
+            });
+            hash::parse_log(&text).unwrap_or_else(|e| {
+                fail(&format!("invalid hash log {}: {}", path.display(), e))
+            })
+        }
+        None => Vec::new(),
+    };
+    let mut expected = expected_hashes.iter().peekable();
+
+    // Replay the whole movie, or up to the last frame checked, unless told
+    // otherwise
+    let frames = options
+        .frames
+        .or_else(|| playback.as_ref().map(|movie| movie.len() as u64))
+        .or_else(|| expected_hashes.last().map(|&(frame, _)| frame))
+        .unwrap_or_else(|| fail("the hash log is empty, give --frames"));
+
+    let symbols = load_symbols(&options.rom);
+    let mut tracer = options.trace.as_ref().map(|path| {
+        let file = File::create(path).unwrap_or_else(|e| {
+            fail(&format!("can't write {}: {}", path.display(), e))
+        });
+        let mut tracer =
+            Tracer::new(BufWriter::new(file), options.trace_filter);
+        tracer.set_symbols(symbols.clone());
+        tracer
+    });
+
+    let mut gdb = start_gdb(options);
+    let mut debugger = if options.debug || gdb.is_some() {
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(symbols.clone());
+        debugger.interrupt();
+        Some(debugger)
+    } else {
+        None
+    };
+
+    let mut profiler = if options.profile || options.profile_folded.is_some() {
+        Some(Profiler::new())
+    } else {
+        None
+    };
+
+    let mut frame = 0;
+    while frame < frames {
+        let input = match &playback {
+            Some(movie) => movie.input(frame).unwrap_or_default(),
+            None => Input {
+                buttons: script.buttons(frame),
+                reset: false,
+            },
+        };
+        input.apply(&mut emulator);
+        let result = match (&mut debugger, &mut tracer, &mut profiler) {
+            (Some(debugger), _, _) => {
+                if gdb.as_mut().is_some_and(GdbStub::poll_interrupt) {
+                    debugger.interrupt();
+                }
+                debugger.run_frame(&mut emulator, |debugger, emulator| {
+                    match &mut gdb {
+                        Some(gdb) => gdb.on_break(debugger, emulator),
+                        None => repl::prompt(debugger, emulator),
+                    }
+                })
+            }
+            (None, Some(tracer), _) => {
+                tracer.run_frame(&mut emulator).map(|_| true)
+            }
+            (None, None, Some(profiler)) => {
+                profiler.run_frame(&mut emulator).map(|_| true)
+            }
+            (None, None, None) => emulator.run_frame().map(|_| true),
+        };
+        if let Some(movie) = &mut recording {
+            movie.push(input);
+        }
+        if let Ok(false) = result {
+            // Quit from the debugger
+            break;
+        }
+        if let Some(tracer) = &mut tracer {
+            let path = options.trace.as_ref().unwrap();
+            tracer.flush().unwrap_or_else(|e| {
+                fail(&format!("can't write {}: {}", path.display(), e))
+            });
+        }
+        if let Err(e) = result {
+            let report = symbols.exit_report(&emulator, &e);
+            eprintln!("Emulator stopped: {} {}", report, emulator);
+            if let Some(profiler) = &profiler {
+                write_profile(profiler, &symbols, options);
+            }
+            finish(&emulator, options, recording.as_ref());
+            return EXIT_EMULATOR_ERROR;
+        }
+        frame += 1;
+
+        let hashes = FrameHashes::of(&emulator);
+        if let Some(log) = &mut hash_log {
+            let path = options.hash_log.as_ref().unwrap();
+            writeln!(log, "{}", hashes.to_line(frame)).unwrap_or_else(|e| {
+                fail(&format!("can't write {}: {}", path.display(), e))
+            });
+        }
+        while let Some((_, logged)) =
+            expected.next_if(|&&(logged, _)| logged <= frame)
+        {
+            if let Some(component) = hashes.diverging(logged) {
+                eprintln!(
+                    "Frame {} diverges first in the {}",
+                    frame, component
+                );
+                finish(&emulator, options, recording.as_ref());
+                return EXIT_DIVERGED;
+            }
+        }
+
+        if let (Some(every), Some(dir)) =
+            (options.screenshot_every, &options.screenshot_dir)
+        {
+            if frame % every == 0 {
+                let name = format!("frame-{:06}.png", frame);
+                write_screenshot(&emulator, &dir.join(name));
+            }
+        }
+    }
+    if let Some(profiler) = &profiler {
+        write_profile(profiler, &symbols, options);
+    }
+    finish(&emulator, options, recording.as_ref());
+
+    if let Some(path) = &options.screenshot {
+        write_screenshot(&emulator, path);
+    }
+    if let Some(dir) = &options.vram_dump {
+        vram::dump(&emulator.memory.gpu, dir).unwrap_or_else(|e| {
+            fail(&format!("can't write {}: {}", dir.display(), e))
+        });
+    }
+    if let Some(path) = &options.expect {
+        let (width, height, expected) =
+            image::load_png(path).unwrap_or_else(|e| {
+                fail(&format!("can't read {}: {}", path.display(), e))
+            });
+        if (width, height) != (WIDTH, HEIGHT)
+            || expected != emulator.framebuffer()
+        {
+            eprintln!("Frame {} differs from {}", frame, path.display());
+            return EXIT_MISMATCH;
+        }
+    }
+    EXIT_SUCCESS
+}
+
+/// Keep the cartridge RAM and the movie recorded, if any
+fn finish(emulator: &Emulator, options: &Options, recording: Option<&Movie>) {
+    save_battery(emulator, options.save_dir.as_deref(), &options.rom)
+        .unwrap_or_else(|e| fail(&e));
+    if let (Some(movie), Some(path)) = (recording, &options.record) {
+        write_movie(movie, path).unwrap_or_else(|e| fail(&e));
+    }
+}
+
+/// Print the profile report and write the folded stacks if asked to
+fn write_profile(profiler: &Profiler, symbols: &Symbols, options: &Options) {
+    if options.profile {
+        println!("{}", profiler.report(symbols, PROFILE_LINES));
+    }
+    if let Some(path) = &options.profile_folded {
+        let mut stacks = profiler.folded_stacks(symbols);
+        stacks.push('\n');
+        std::fs::write(path, stacks).unwrap_or_else(|e| {
+            fail(&format!("can't write {}: {}", path.display(), e))
+        });
+    }
+}
+
+/// Create a hash log at `path`, starting with its header
+fn create_hash_log(path: &Path) -> io::Result<BufWriter<File>> {
+    let mut log = BufWriter::new(File::create(path)?);
+    writeln!(log, "{}", hash::LOG_HEADER)?;
+    Ok(log)
+}
+
+fn write_screenshot(emulator: &Emulator, path: &Path) {
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if let Err(e) = image::save_png(path, WIDTH, HEIGHT, emulator.framebuffer())
+    {
+        fail(&format!("can't write {}: {}", path.display(), e));
+    }
+}
@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+/// Write an RGBA buffer of `width` x `height` pixels as a PNG file
+pub fn save_png<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    rgba: &[u8],
+) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(rgba))
+        .map_err(io::Error::other)
+}
+
+/// Read a PNG file as an RGBA buffer, along with its width and height
+pub fn load_png<P: AsRef<Path>>(path: P) -> io::Result<(u32, u32, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(
+        png::Transformations::EXPAND | png::Transformations::STRIP_16,
+    );
+    let (info, mut reader) = decoder.read_info().map_err(io::Error::other)?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer).map_err(io::Error::other)?;
+
+    let rgba = match info.color_type {
+        png::ColorType::RGBA => buffer,
+        png::ColorType::RGB => buffer
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buffer
+            .chunks(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => {
+            buffer.iter().flat_map(|&g| [g, g, g, 0xFF]).collect()
+        }
+        // Palettes are expanded by the decoder
+        png::ColorType::Indexed => {
+            return Err(io::Error::other("unexpected indexed PNG"))
+        }
+    };
+    Ok((info.width, info.height, rgba))
+}
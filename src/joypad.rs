@@ -0,0 +1,127 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// State of the eight buttons, `true` when pressed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Buttons {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl Buttons {
+    /// Pack the buttons in a byte, directions in the low nibble and actions
+    /// in the high one, in the order of the P1 input lines
+    pub fn to_bits(self) -> u8 {
+        [
+            self.right,
+            self.left,
+            self.up,
+            self.down,
+            self.a,
+            self.b,
+            self.select,
+            self.start,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, &pressed)| acc | (pressed as u8) << i)
+    }
+
+    /// Unpack buttons packed by `to_bits`
+    pub fn from_bits(bits: u8) -> Buttons {
+        let pressed = |i: u8| bits & (1 << i) != 0;
+        Buttons {
+            right: pressed(0),
+            left: pressed(1),
+            up: pressed(2),
+            down: pressed(3),
+            a: pressed(4),
+            b: pressed(5),
+            select: pressed(6),
+            start: pressed(7),
+        }
+    }
+}
+
+pub struct Joypad {
+    /// P1 bits 4 and 5, selecting the direction and action lines
+    select: u8,
+
+    buttons: Buttons,
+
+    pub interrupt_flags: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0x30,
+            buttons: Buttons::default(),
+            interrupt_flags: 0,
+        }
+    }
+
+    /// P1/JOYP - Joypad (R/W)
+    pub fn read_byte(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    /// P1/JOYP - Joypad (R/W)
+    pub fn write_byte(&mut self, val: u8) {
+        self.select = val & 0x30;
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        let old = self.lines();
+        self.buttons = buttons;
+
+        // The interrupt is requested when an input line goes low
+        if old & !self.lines() != 0 {
+            self.interrupt_flags |= 0x10;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.select);
+        writer.write_u8(self.buttons.to_bits());
+        writer.write_u8(self.interrupt_flags);
+    }
+
+    pub fn load_state(
+        &mut self,
+        reader: &mut StateReader,
+    ) -> Result<(), StateError> {
+        self.select = reader.read_u8()? & 0x30;
+        self.buttons = Buttons::from_bits(reader.read_u8()?);
+        self.interrupt_flags = reader.read_u8()?;
+        Ok(())
+    }
+
+    /// State of the four input lines, a pressed button pulls its line low
+    fn lines(&self) -> u8 {
+        let bits = self.buttons.to_bits();
+        let mut pressed = 0;
+        if self.select & 0x10 == 0 {
+            pressed |= bits & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            pressed |= bits >> 4;
+        }
+        !pressed & 0x0F
+    }
+}
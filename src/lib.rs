@@ -0,0 +1,44 @@
+//! Game Boy emulator core
+//!
+//! The [`Emulator`] runs a cartridge and exposes the screen, the joypad and
+//! the machine state, frontends only need to drive it frame by frame:
+//!
+//! ```no_run
+//! use gbemu::{Buttons, Emulator};
+//!
+//! let mut emulator = Emulator::new();
+//! emulator.load_rom_from_bytes(std::fs::read("game.gb").unwrap());
+//! emulator.set_buttons(Buttons { start: true, ..Default::default() });
+//! emulator.run_frame().unwrap();
+//! let _rgba = emulator.framebuffer();
+//! ```
+
+mod boot;
+pub mod bus;
+pub mod debugger;
+pub mod disasm;
+pub mod emulator;
+pub mod gdb;
+pub mod gpu;
+pub mod hash;
+pub mod image;
+pub mod joypad;
+pub mod link;
+pub mod mmu;
+pub mod model;
+pub mod movie;
+pub mod printer;
+pub mod profile;
+pub mod rewind;
+pub mod serial;
+pub mod state;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+pub mod triple_buffer;
+pub mod vram;
+pub mod watch;
+
+pub use emulator::{Emulator, Registers, VmExit};
+pub use joypad::Buttons;
+pub use model::Model;
@@ -0,0 +1,64 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Frames per second of the hardware, 4194304 Hz / 70224 cycles per frame
+pub const FRAME_RATE: f64 = 59.7275;
+
+/// Time left to sleep under which the limiter spins instead, sleeping being
+/// too coarse
+const SPIN_TIME: Duration = Duration::from_millis(1);
+
+/// Frames the limiter may fall behind before giving up on catching up
+const MAX_LAG: u32 = 4;
+
+/// Paces the emulator thread to the hardware frame rate, times a speed
+/// multiplier
+pub struct FrameLimiter {
+    /// When the next frame is due
+    deadline: Instant,
+
+    speed: f64,
+}
+
+impl FrameLimiter {
+    pub fn new(speed: f64) -> FrameLimiter {
+        FrameLimiter {
+            deadline: Instant::now(),
+            speed,
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    /// Wait until the next frame is due
+    pub fn wait(&mut self) {
+        let period = Duration::from_secs_f64(1.0 / (FRAME_RATE * self.speed));
+        self.deadline += period;
+
+        let now = Instant::now();
+        if now > self.deadline + period * MAX_LAG {
+            // Too slow or paused for a while, rushing would not help
+            self.deadline = now;
+            return;
+        }
+        if let Some(left) = self.deadline.checked_duration_since(now) {
+            if left > SPIN_TIME {
+                thread::sleep(left - SPIN_TIME);
+            }
+        }
+        while Instant::now() < self.deadline {
+            thread::yield_now();
+        }
+    }
+
+    /// Pace from now on, e.g. after running unlimited
+    pub fn reset(&mut self) {
+        self.deadline = Instant::now();
+    }
+}
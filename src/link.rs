@@ -0,0 +1,476 @@
+//! Link cable between two emulator instances over a local socket
+//!
+//! Each side starts by sending `GBLK` followed by the protocol version. Then
+//! every message holds its kind, a byte and the clock cycles the sending
+//! machine ran so far. Both machines stay in step: one that runs more than
+//! `LOOKAHEAD` cycles ahead of the other waits for it, each side sending a
+//! `SYNC` message every `SYNC_CYCLES` cycles to let the other one go on.
+//!
+//! Whenever a game starts a transfer with its internal clock, that side acts
+//! as the clock master for this byte: it sends a `TRANSFER` message and
+//! waits for the other side to answer. The slave answers with a `REPLY`
+//! message holding its SB register once its machine reached the cycle the
+//! transfer started at. If both games clock the same byte, both `TRANSFER`
+//! messages cross on the wire and each side takes the other's byte, like two
+//! Game Boys would.
+//!
+//! A side waiting for the other one longer than the timeout, because it was
+//! paused or hung, unplugs the cable and reads ones from then on.
+
+use crate::serial::SerialDevice;
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"GBLK";
+const VERSION: u8 = 2;
+
+const MSG_TRANSFER: u8 = 0x01;
+const MSG_REPLY: u8 = 0x02;
+const MSG_SYNC: u8 = 0x03;
+
+/// Kind, byte and clock cycles
+const MESSAGE_LEN: usize = 10;
+
+/// Clock cycles a machine may run ahead of the other
+const LOOKAHEAD: u64 = 8192;
+
+/// Clock cycles between two `SYNC` messages
+const SYNC_CYCLES: u64 = LOOKAHEAD / 4;
+
+/// How long to wait for the other side before unplugging the cable
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Byte read when nothing drives the line
+const DISCONNECTED: u8 = 0xFF;
+
+enum Message {
+    /// The other side clocked a transfer and shifted out this byte
+    Transfer(u8, u64),
+
+    /// The other side answered our transfer with this byte
+    Reply(u8, u64),
+
+    /// The other side ran this far
+    Sync(u64),
+}
+
+impl Message {
+    fn decode(bytes: &[u8; MESSAGE_LEN]) -> Option<Message> {
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&bytes[2..]);
+        let cycles = u64::from_le_bytes(cycles);
+        match bytes[0] {
+            MSG_TRANSFER => Some(Message::Transfer(bytes[1], cycles)),
+            MSG_REPLY => Some(Message::Reply(bytes[1], cycles)),
+            MSG_SYNC => Some(Message::Sync(cycles)),
+            _ => None,
+        }
+    }
+
+    /// Clock cycles the other side ran when sending it
+    fn cycles(&self) -> u64 {
+        match *self {
+            Message::Transfer(_, cycles)
+            | Message::Reply(_, cycles)
+            | Message::Sync(cycles) => cycles,
+        }
+    }
+}
+
+/// Where the two instances meet: `tcp:HOST:PORT`, `unix:PATH` or just
+/// `HOST:PORT` for TCP
+enum LinkAddress<'a> {
+    Tcp(&'a str),
+    Unix(&'a str),
+}
+
+impl<'a> LinkAddress<'a> {
+    fn parse(address: &'a str) -> LinkAddress<'a> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            LinkAddress::Unix(path)
+        } else {
+            LinkAddress::Tcp(address.strip_prefix("tcp:").unwrap_or(address))
+        }
+    }
+}
+
+/// Sending half of a socket
+trait Socket: Write + Send {
+    /// Hang up, letting the other side know at once
+    fn close(&self);
+}
+
+impl Socket for TcpStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+pub struct LinkCable {
+    /// Sending half of the socket
+    writer: Box<dyn Socket>,
+
+    /// Messages received by the reader thread
+    messages: Receiver<Message>,
+
+    /// False once the other side went away or stopped answering
+    connected: bool,
+
+    /// Clock cycles our machine ran
+    cycles: u64,
+
+    /// Clock cycles the other machine ran, as far as we know
+    peer_cycles: u64,
+
+    /// Clock cycles our machine ran when we last sent a message
+    sent_cycles: u64,
+
+    /// Transfers clocked by the other side that our machine did not reach
+    /// yet, with the cycle they started at
+    pending: VecDeque<(u8, u64)>,
+
+    /// How long to wait for the other side before unplugging the cable
+    timeout: Duration,
+}
+
+impl LinkCable {
+    /// Wait for the other instance to connect on `address`
+    pub fn listen(address: &str) -> io::Result<LinkCable> {
+        match LinkAddress::parse(address) {
+            LinkAddress::Tcp(address) => {
+                let (stream, _) = TcpListener::bind(address)?.accept()?;
+                stream.set_nodelay(true)?;
+                LinkCable::from_stream(stream.try_clone()?, stream)
+            }
+            LinkAddress::Unix(path) => listen_unix(path),
+        }
+    }
+
+    /// Connect to an instance listening on `address`
+    pub fn connect(address: &str) -> io::Result<LinkCable> {
+        match LinkAddress::parse(address) {
+            LinkAddress::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                LinkCable::from_stream(stream.try_clone()?, stream)
+            }
+            LinkAddress::Unix(path) => connect_unix(path),
+        }
+    }
+
+    fn from_stream<R, W>(mut reader: R, mut writer: W) -> io::Result<LinkCable>
+    where
+        R: Read + Send + 'static,
+        W: Socket + 'static,
+    {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.flush()?;
+
+        let mut hello = [0; 5];
+        reader.read_exact(&mut hello)?;
+        if &hello[..4] != MAGIC || hello[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the other side does not speak the gbemu link protocol",
+            ));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut bytes = [0; MESSAGE_LEN];
+            while reader.read_exact(&mut bytes).is_ok() {
+                let msg = match Message::decode(&bytes) {
+                    Some(msg) => msg,
+                    None => break,
+                };
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(LinkCable {
+            writer: Box::new(writer),
+            messages: rx,
+            connected: true,
+            cycles: 0,
+            peer_cycles: 0,
+            sent_cycles: 0,
+            pending: VecDeque::new(),
+            timeout: TIMEOUT,
+        })
+    }
+
+    /// Change how long to wait for the other side before unplugging the
+    /// cable
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Whether the other side is still there
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn send(&mut self, kind: u8, byte: u8) {
+        let mut msg = [0; MESSAGE_LEN];
+        msg[0] = kind;
+        msg[1] = byte;
+        msg[2..].copy_from_slice(&self.cycles.to_le_bytes());
+        let res = self
+            .writer
+            .write_all(&msg)
+            .and_then(|_| self.writer.flush());
+        match res {
+            Ok(()) => self.sent_cycles = self.cycles,
+            Err(_) => self.unplug(),
+        }
+    }
+
+    /// Wait for the next message, unplugging the cable if none comes in time
+    fn receive(&mut self) -> Option<Message> {
+        match self.messages.recv_timeout(self.timeout) {
+            Ok(msg) => {
+                self.peer_cycles = msg.cycles();
+                Some(msg)
+            }
+            Err(RecvTimeoutError::Timeout)
+            | Err(RecvTimeoutError::Disconnected) => {
+                self.unplug();
+                None
+            }
+        }
+    }
+
+    /// Take note of the messages already received
+    fn receive_pending(&mut self) {
+        loop {
+            match self.messages.try_recv() {
+                Ok(msg) => {
+                    self.peer_cycles = msg.cycles();
+                    if let Message::Transfer(incoming, cycles) = msg {
+                        self.pending.push_back((incoming, cycles));
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.unplug();
+                    break;
+                }
+            }
+        }
+    }
+
+    fn unplug(&mut self) {
+        self.connected = false;
+        self.pending.clear();
+        self.writer.close();
+    }
+}
+
+impl Drop for LinkCable {
+    fn drop(&mut self) {
+        self.writer.close();
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        if !self.connected {
+            return DISCONNECTED;
+        }
+
+        self.send(MSG_TRANSFER, byte);
+        // The other side clocked a byte too and waits for our answer
+        if let Some((incoming, _)) = self.pending.pop_front() {
+            return incoming;
+        }
+        while self.connected {
+            match self.receive() {
+                Some(Message::Reply(incoming, _))
+                | Some(Message::Transfer(incoming, _)) => return incoming,
+                Some(Message::Sync(_)) | None => {}
+            }
+        }
+        DISCONNECTED
+    }
+
+    fn poll(&mut self, cycles: usize, byte: u8, ready: bool) -> Option<u8> {
+        if !self.connected {
+            return None;
+        }
+        self.cycles += cycles as u64;
+        self.receive_pending();
+
+        // Let the other side go on before waiting for it, so that both never
+        // wait for each other
+        if self.cycles > self.peer_cycles + LOOKAHEAD
+            || self.cycles >= self.sent_cycles + SYNC_CYCLES
+        {
+            self.send(MSG_SYNC, 0);
+        }
+        while self.connected
+            && self.pending.is_empty()
+            && self.cycles > self.peer_cycles + LOOKAHEAD
+        {
+            if let Some(Message::Transfer(incoming, cycles)) = self.receive() {
+                self.pending.push_back((incoming, cycles));
+            }
+        }
+
+        match self.pending.front() {
+            Some(&(incoming, cycles)) if cycles <= self.cycles => {
+                self.pending.pop_front();
+                self.send(MSG_REPLY, byte);
+                if ready {
+                    Some(incoming)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Socket for std::os::unix::net::UnixStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(unix)]
+fn listen_unix(path: &str) -> io::Result<LinkCable> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    // Remove the socket left behind by a previous session
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let (stream, _) = UnixListener::bind(path)?.accept()?;
+    LinkCable::from_stream(stream.try_clone()?, stream)
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<LinkCable> {
+    use std::os::unix::net::UnixStream;
+
+    let stream = UnixStream::connect(path)?;
+    LinkCable::from_stream(stream.try_clone()?, stream)
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_path: &str) -> io::Result<LinkCable> {
+    Err(io::Error::other(
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(path: &str) -> io::Result<LinkCable> {
+    listen_unix(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emulator;
+
+    use std::time::Instant;
+
+    /// Both ends of a cable over the loopback interface
+    fn cable_pair() -> (LinkCable, LinkCable) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let listening = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            LinkCable::from_stream(stream.try_clone().unwrap(), stream).unwrap()
+        });
+        let connected = LinkCable::connect(&address).unwrap();
+        (listening.join().unwrap(), connected)
+    }
+
+    /// An emulator exchanging four bytes from 0x0200 with `control`
+    /// written to SC to start each transfer, and keeping the received ones
+    /// at C000
+    fn exchanging(control: u8, bytes: &[u8; 4]) -> Emulator {
+        Emulator::with_code(&[
+            (
+                0x100,
+                &[
+                    0x21, 0x00, 0xC0, // LD HL, 0xC000
+                    0x11, 0x00, 0x02, // LD DE, 0x0200
+                    0x1A, // loop: LD A, (DE)
+                    0x13, // INC DE
+                    0xE0, 0x01, // LDH (SB), A
+                    0x3E, control, // LD A, control
+                    0xE0, 0x02, // LDH (SC), A
+                    0xF0, 0x02, // wait: LDH A, (SC)
+                    0xE6, 0x80, // AND 0x80
+                    0x20, 0xFA, // JR NZ, wait
+                    0xF0, 0x01, // LDH A, (SB)
+                    0x22, // LD (HL+), A
+                    0x7D, // LD A, L
+                    0xFE, 0x04, // CP 4
+                    0x20, 0xEA, // JR NZ, loop
+                    0x18, 0xFE, // JR -2
+                ],
+            ),
+            (0x200, bytes),
+        ])
+    }
+
+    /// Run `emulator` for a few frames with `cable` plugged in and return
+    /// the bytes it received
+    fn run_linked(mut emulator: Emulator, cable: LinkCable) -> Vec<u8> {
+        emulator.memory.serial.connect(Box::new(cable));
+        for _ in 0..4 {
+            emulator.run_frame().unwrap();
+        }
+        (0xC000..0xC004)
+            .map(|address| emulator.read_memory(address).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn two_emulators() {
+        let (master, slave) = cable_pair();
+        let master = thread::spawn(move || {
+            run_linked(exchanging(0x81, b"PING"), master)
+        });
+        let slave =
+            thread::spawn(move || run_linked(exchanging(0x80, b"pong"), slave));
+        assert_eq!(master.join().unwrap(), b"pong");
+        assert_eq!(slave.join().unwrap(), b"PING");
+    }
+
+    #[test]
+    fn unplugged_when_the_other_side_stops() {
+        let (mut cable, _paused) = cable_pair();
+        cable.set_timeout(Duration::from_millis(100));
+        let start = Instant::now();
+        assert_eq!(cable.transfer(0x42), DISCONNECTED);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(!cable.is_connected());
+        assert_eq!(cable.transfer(0x42), DISCONNECTED);
+        assert_eq!(cable.poll(LOOKAHEAD as usize * 2, 0x42, true), None);
+    }
+
+    #[test]
+    fn unplugged_when_the_other_side_leaves() {
+        let (mut cable, other) = cable_pair();
+        drop(other);
+        assert_eq!(cable.poll(LOOKAHEAD as usize * 2, 0x42, true), None);
+        assert!(!cable.is_connected());
+    }
+}
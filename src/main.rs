@@ -1,111 +1,339 @@
-pub mod emulator;
-pub mod gpu;
-pub mod mmu;
+mod cli;
+mod headless;
+mod limiter;
+mod repl;
+mod window;
 
-use emulator::Emulator;
-use gpu::{FRAME_LENGTH, HEIGHT, WIDTH};
+use cli::{Action, Link, Options, SerialOutput};
+use gbemu::disasm;
+use gbemu::gdb::GdbStub;
+use gbemu::link::LinkCable;
+use gbemu::movie::Movie;
+use gbemu::printer::Printer;
+use gbemu::serial::{FileDevice, SerialDevice, StdoutDevice};
+use gbemu::symbols::Symbols;
+use gbemu::trace;
+use gbemu::{Emulator, Model};
 
-use std::sync::{mpsc, Arc, Condvar, Mutex};
-use std::thread;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
 
-use log::error;
-use pixels::{Pixels, SurfaceTexture};
-use winit::dpi::LogicalSize;
-use winit::event::{Event, VirtualKeyCode};
-use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::WindowBuilder;
-use winit_input_helper::WinitInputHelper;
+fn main() {
+    let options = match Action::from_args() {
+        Action::Run(options) => options,
+        Action::Disasm {
+            rom,
+            bank,
+            address,
+            count,
+        } => {
+            let symbols = load_symbols(&rom);
+            disasm(&read_file(&rom, "ROM"), &symbols, bank, address, count);
+            return;
+        }
+        Action::TraceDiff { trace, reference } => {
+            process::exit(trace_diff(&trace, &reference));
+        }
+    };
 
-const GRAPHICS_OUTPUT: bool = true;
+    let mut emulator = match options.model {
+        Some(model) => Emulator::with_model(model),
+        None => Emulator::new(),
+    };
+    emulator.memory.gpu.set_palette(options.palette);
+    let rom = read_file(&options.rom, "ROM");
+    if let Some(path) = &options.bootrom {
+        let model = options.model.unwrap_or_else(|| Model::from_header(&rom));
+        let path = bootrom_path(path, model);
+        emulator.load_bootrom(read_file(&path, "boot ROM"));
+    }
+    emulator.load_rom_from_bytes(rom);
+    load_battery(&mut emulator, options.save_dir.as_deref(), &options.rom)
+        .unwrap_or_else(|e| fail(&e));
+    plug_serial_device(&mut emulator, &options);
 
-fn main() {
-    let mut emulator = Emulator::new();
-    // emulator.memory.load_rom("roms/Mario's Picross (UE) [S][!].gb");
-    emulator.memory.load_rom("roms/tetris.gb");
-
-    if GRAPHICS_OUTPUT {
-        // Start the emulator and sync the GPU
-        let (tx, rx) = mpsc::channel();
-        let pair = Arc::new((Mutex::new(true), Condvar::new()));
-        let pair2 = pair.clone();
-        let emulator_thread = thread::spawn(move || {
-            emulator.memory.gpu.sync(tx, pair2);
-            emulator.run().unwrap();
+    if options.headless {
+        process::exit(headless::run(emulator, &options));
+    } else {
+        window::run(emulator, *options);
+    }
+}
+
+/// Boot ROM of `model` when `path` is a directory of boot ROMs, `path`
+/// itself otherwise
+fn bootrom_path(path: &Path, model: Model) -> PathBuf {
+    if path.is_dir() {
+        path.join(model.bootrom_name())
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Print `count` instructions of `rom` from `address` in `bank`, which is
+/// mapped at 0x4000-0x7FFF unless it is bank 0, with their labels
+fn disasm(
+    rom: &[u8],
+    symbols: &Symbols,
+    bank: usize,
+    address: u16,
+    count: usize,
+) {
+    let base = match (bank, address) {
+        (0, 0x0000..=0x3FFF) => 0,
+        (_, 0x4000..=0x7FFF) if bank > 0 => bank * 0x4000 - 0x4000,
+        _ => fail(&format!("{:04X} is not in ROM bank {:X}", address, bank)),
+    };
+    let end = if bank == 0 { 0x4000 } else { 0x8000 };
+    let mut address = address;
+    for _ in 0..count {
+        let offset = base + address as usize;
+        if address >= end || offset >= rom.len() {
+            break;
+        }
+        let instruction = disasm::decode_bytes(&rom[offset..], address);
+        if let Some(symbol) = symbols.exact(Some(bank), address) {
+            println!("{}:", symbol.name);
+        }
+        // Bank 0 code may call into any bank
+        let switchable = Some(bank).filter(|&bank| bank > 0);
+        let length = (instruction.length as usize).min(rom.len() - offset);
+        let bytes: Vec<String> = rom[offset..offset + length]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        println!(
+            "{:02X}:{:04X}  {:<9}  {}",
+            bank,
+            address,
+            bytes.join(" "),
+            symbols.annotate(&instruction, switchable)
+        );
+        address += instruction.length;
+    }
+}
+
+/// Compare two traces and print their first difference, returning the
+/// process exit code
+fn trace_diff(ours: &Path, reference: &Path) -> i32 {
+    let lines = |path: &Path| {
+        let file = File::open(path).unwrap_or_else(|e| {
+            fail(&format!("can't read {}: {}", path.display(), e))
         });
+        let path = path.to_path_buf();
+        BufReader::new(file).lines().map(move |line| {
+            line.unwrap_or_else(|e| {
+                fail(&format!("can't read {}: {}", path.display(), e))
+            })
+        })
+    };
+    let mismatch = match trace::diff(lines(ours), lines(reference)) {
+        Some(mismatch) => mismatch,
+        None => {
+            println!("Traces match");
+            return 0;
+        }
+    };
+    match (&mismatch.ours, &mismatch.reference) {
+        (Some(_), Some(_)) => println!(
+            "Line {} differs in {}",
+            mismatch.line,
+            mismatch.fields.join(", ")
+        ),
+        (None, _) => {
+            println!("{} ends before line {}", ours.display(), mismatch.line)
+        }
+        (_, None) => println!(
+            "{} ends before line {}",
+            reference.display(),
+            mismatch.line
+        ),
+    }
+    if let Some(line) = &mismatch.ours {
+        println!("  ours:      {}", line);
+    }
+    if let Some(line) = &mismatch.reference {
+        println!("  reference: {}", line);
+    }
+    1
+}
 
-        let event_loop = EventLoop::new();
-        let mut input = WinitInputHelper::new();
-        let window = {
-            let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
-            WindowBuilder::new()
-                .with_title("GBEMU")
-                .with_inner_size(size)
-                .with_min_inner_size(size)
-                .build(&event_loop)
-                .unwrap()
-        };
+/// Print `message` and exit with a failure code
+pub fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
 
-        let mut pixels = {
-            let window_size = window.inner_size();
-            let surface_texture = SurfaceTexture::new(
-                window_size.width,
-                window_size.height,
-                &window,
-            );
-            Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
-        };
+/// Read the whole file at `path`, `what` naming it in the error message
+fn read_file(path: &Path, what: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| {
+        fail(&format!("can't read {} {}: {}", what, path.display(), e))
+    })
+}
 
-        let mut frame = [0; FRAME_LENGTH];
-
-        event_loop.run(move |event, _, control_flow| {
-            emulator_thread.thread().id();
-
-            // Draw the current frame
-            if let Event::RedrawRequested(_) = event {
-                match rx.recv() {
-                    Ok(buffer) => {
-                        frame = *buffer;
-                        let (lock, cvar) = &*pair;
-                        let mut drawn = lock.lock().unwrap();
-                        *drawn = true;
-                        cvar.notify_one();
-                    }
-                    Err(_) => (),
-                }
-
-                pixels.get_frame().copy_from_slice(&frame);
-
-                if pixels
-                    .render()
-                    .map_err(|e| error!("pixels.render() failed: {}", e))
-                    .is_err()
-                {
-                    *control_flow = ControlFlow::Exit;
-                    return;
-                }
+/// Plug what the command line asks for in the link port
+fn plug_serial_device(emulator: &mut Emulator, options: &Options) {
+    let device: Box<dyn SerialDevice> =
+        match (&options.serial, &options.link, &options.printer) {
+            (Some(SerialOutput::Stdout), _, _) => Box::new(StdoutDevice),
+            (Some(SerialOutput::File(path)), _, _) => {
+                Box::new(FileDevice::create(path).unwrap_or_else(|e| {
+                    fail(&format!("can't write {}: {}", path.display(), e))
+                }))
             }
-
-            // Handle input events
-            if input.update(event) {
-                // Close events
-                if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
-                    *control_flow = ControlFlow::Exit;
-                    return;
-                }
-
-                // Resize the window
-                if let Some(size) = input.window_resized() {
-                    pixels.resize(size.width, size.height);
-                }
-
-                // Request a redraw
-                window.request_redraw();
+            (_, Some(link), _) => Box::new(connect_link(link)),
+            (_, _, Some(dir)) => {
+                std::fs::create_dir_all(dir).unwrap_or_else(|e| {
+                    fail(&format!("can't create {}: {}", dir.display(), e))
+                });
+                Box::new(Printer::new(dir))
             }
-        });
-    } else {
-        match emulator.run() {
-            Ok(_) => println!("Should not happen"),
-            Err(e) => println!("{:?} {}", e, emulator),
+            _ => return,
         };
+    emulator.memory.serial.connect(device);
+}
+
+/// Wait for the other instance of a link cable
+fn connect_link(link: &Link) -> LinkCable {
+    let (cable, address) = match link {
+        Link::Listen(address) => {
+            println!("Waiting for the other side of the link on {}", address);
+            (LinkCable::listen(address), address)
+        }
+        Link::Connect(address) => (LinkCable::connect(address), address),
+    };
+    let cable = cable.unwrap_or_else(|e| {
+        fail(&format!("can't link over {}: {}", address, e))
+    });
+    println!("Link cable connected");
+    cable
+}
+
+/// Wait for GDB to connect when asked to
+pub fn start_gdb(options: &Options) -> Option<GdbStub> {
+    let port = options.gdb?;
+    println!("Waiting for GDB on port {}", port);
+    let stub = GdbStub::listen(("127.0.0.1", port)).unwrap_or_else(|e| {
+        fail(&format!("can't listen on port {}: {}", port, e))
+    });
+    println!("GDB connected");
+    Some(stub)
+}
+
+/// Load the symbols of the `.sym` file next to `rom`, or of its `.map`
+/// file, warning about invalid ones
+pub fn load_symbols(rom: &Path) -> Symbols {
+    for (extension, parse) in [
+        (
+            "sym",
+            Symbols::parse_sym as fn(&str) -> Result<Symbols, String>,
+        ),
+        ("map", Symbols::parse_map),
+    ] {
+        let path = rom.with_extension(extension);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        match parse(&text) {
+            Ok(symbols) => {
+                println!(
+                    "Loaded {} symbols from {}",
+                    symbols.len(),
+                    path.display()
+                );
+                return symbols;
+            }
+            Err(e) => eprintln!("error: {}: {}", path.display(), e),
+        }
+    }
+    Symbols::new()
+}
+
+/// Load the movie to replay, if any, and get `emulator` to where it starts
+pub fn start_playback(
+    emulator: &mut Emulator,
+    options: &Options,
+) -> Option<Movie> {
+    let path = options.play.as_ref()?;
+    let movie = Movie::from_bytes(&read_file(path, "movie"))
+        .and_then(|movie| movie.start(emulator).map(|_| movie))
+        .unwrap_or_else(|e| fail(&format!("{}: {}", path.display(), e)));
+    Some(movie)
+}
+
+/// Start recording a movie if asked to, from where `emulator` is when it
+/// already replays one and from power on otherwise
+pub fn start_recording(
+    emulator: &mut Emulator,
+    options: &Options,
+) -> Option<Movie> {
+    options.record.as_ref()?;
+    Some(match options.play {
+        Some(_) => Movie::from_state(emulator),
+        None => Movie::from_power_on(emulator),
+    })
+}
+
+pub fn write_movie(movie: &Movie, path: &Path) -> Result<(), String> {
+    path.parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(path, movie.to_bytes()))
+        .map_err(|e| format!("can't write movie {}: {}", path.display(), e))
+}
+
+/// Where the battery backed RAM of the cartridge `rom` is kept, if anywhere
+fn save_path(save_dir: Option<&Path>, rom: &Path) -> Option<PathBuf> {
+    let mut name = rom.file_stem()?.to_os_string();
+    name.push(".sav");
+    Some(save_dir?.join(name))
+}
+
+/// Where save states of the cartridge `rom` go: in the save directory if
+/// there is one, next to the ROM otherwise
+pub fn state_path(save_dir: Option<&Path>, rom: &Path, slot: usize) -> PathBuf {
+    let mut name = rom.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".ss{}", slot));
+    match save_dir {
+        Some(dir) => dir.join(name),
+        None => rom.with_file_name(name),
+    }
+}
+
+/// Restore the battery backed RAM of the cartridge `rom` from `save_dir`
+pub fn load_battery(
+    emulator: &mut Emulator,
+    save_dir: Option<&Path>,
+    rom: &Path,
+) -> Result<(), String> {
+    let path = match save_path(save_dir, rom) {
+        Some(path) if emulator.memory.has_battery() => path,
+        _ => return Ok(()),
+    };
+    match std::fs::read(&path) {
+        Ok(data) => emulator.memory.load_cartridge_ram(&data),
+        // Nothing saved yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(format!("can't read save {}: {}", path.display(), e))
+        }
     }
+    Ok(())
+}
+
+/// Keep the battery backed RAM of the cartridge `rom` in `save_dir`
+pub fn save_battery(
+    emulator: &Emulator,
+    save_dir: Option<&Path>,
+    rom: &Path,
+) -> Result<(), String> {
+    let path = match save_path(save_dir, rom) {
+        Some(path) if emulator.memory.has_battery() => path,
+        _ => return Ok(()),
+    };
+    path.parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, emulator.memory.cartridge_ram()))
+        .map_err(|e| format!("can't write save {}: {}", path.display(), e))
 }
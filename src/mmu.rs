@@ -1,101 +1,247 @@
-use crate::emulator::VmExit;
+use crate::bus::Bus;
+use crate::emulator::{Access, VmExit};
 use crate::gpu::Gpu;
+use crate::joypad::Joypad;
+use crate::model::{Model, MODELS};
+use crate::serial::Serial;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::timer::Timer;
+use crate::watch::{WatchHit, Watchpoint};
+
+use std::io;
+use std::path::Path;
+
+use log::debug;
+
+/// Bits that always read as 1 in the sound registers, from NR10 to 0xFF2F
+const SOUND_READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
+
+/// Value read on the data bus when nothing drives it
+const OPEN_BUS: u8 = 0xFF;
 
 pub struct Mmu {
+    model: Model,
     rom: Vec<u8>,
     bootrom: Vec<u8>,
     bootrom_lock: bool,
     ram: Vec<u8>,
     mbc0_ram: Vec<u8>,
     zero_page_ram: Vec<u8>,
+    sound_registers: Vec<u8>,
+    dma: u8,
     pub gpu: Gpu,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    pub timer: Timer,
     pub interrupt_flags: u8,
+
+    /// Accesses the debugger stops on
+    watchpoints: Vec<Watchpoint>,
+
+    /// First watchpoint hit, until taken
+    watch_hit: Option<WatchHit>,
 }
 
 impl Mmu {
     pub fn new() -> Mmu {
-        let bootrom = std::fs::read("roms/bootrom.gb").ok().unwrap();
         Mmu {
-            bootrom: bootrom,
-            bootrom_lock: true,
+            model: Model::Dmg,
+            bootrom: Vec::new(),
+            bootrom_lock: false,
             rom: vec![0; 32768],
             ram: vec![0; 8192],
-            mbc0_ram: vec![0; 8192],
+            mbc0_ram: Vec::new(),
             zero_page_ram: vec![0; 128],
+            sound_registers: vec![0; 0x30],
+            dma: 0,
             gpu: Gpu::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
             interrupt_flags: 0,
+            watchpoints: Vec::new(),
+            watch_hit: None,
         }
     }
 
-    pub fn load_rom(&mut self, path: &str) {
-        self.rom = std::fs::read(path).ok().unwrap();
-        print!("Cartridge type = 0x{:x}\n", self.rom[0x147]);
+    /// Power cycle every device, keeping the cartridge, its RAM, the boot
+    /// ROM and whatever is plugged in the link port
+    pub fn reset(&mut self) {
+        self.bootrom_lock = self.has_bootrom();
+        self.ram = vec![0; 8192];
+        self.zero_page_ram = vec![0; 128];
+        self.sound_registers = vec![0; 0x30];
+        self.dma = 0;
+        self.gpu.reset();
+        self.joypad = Joypad::new();
+        self.serial.reset();
+        self.timer = Timer::new();
+        self.interrupt_flags = 0;
     }
 
-    pub fn read_byte(&mut self, address: u16) -> Result<u8, VmExit> {
-        let address = address as usize;
-        match address {
-            0x0000..=0x7FFF => {
-                if self.bootrom_lock == true && address <= 0xFF {
-                    return Ok(self.bootrom[address]);
-                }
-                Ok(self.rom[address])
-            }
-            0x8000..=0x9FFF => self.gpu.read_byte(address),
-            0xA000..=0xBFFF => Ok(self.mbc0_ram[address - 0xA000]),
-            0xC000..=0xDFFF => Ok(self.ram[address - 0xC000]),
-            0xE000..=0xFDFF => Ok(self.ram[address - 0xE000]),
-            0xFE00..=0xFE9F => self.gpu.read_byte(address),
-            0xFF00..=0xFF7F => self.handle_io_read(address),
-            0xFF80..=0xFFFF => Ok(self.zero_page_ram[address - 0xFF80]),
-            _ => panic!(
-                "Trying to read byte at address 0x{:04x}",
-                address
-            ),
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Make every device behave like `model`
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.gpu.set_model(model);
+        self.serial.set_cgb_mode(model.is_cgb());
+    }
+
+    /// Map `bootrom` over the cartridge on the next reset
+    pub fn load_bootrom(&mut self, bootrom: Vec<u8>) {
+        self.bootrom = bootrom;
+    }
+
+    /// Content of the boot ROM, empty when the boot sequence is skipped
+    pub fn bootrom(&self) -> &[u8] {
+        &self.bootrom
+    }
+
+    pub fn has_bootrom(&self) -> bool {
+        !self.bootrom.is_empty()
+    }
+
+    /// Content of the cartridge ROM
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.load_rom_from_bytes(std::fs::read(path)?);
+        Ok(())
+    }
+
+    pub fn load_rom_from_bytes(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+        if let Some(cartridge_type) = self.rom.get(0x147) {
+            debug!("Cartridge type = 0x{:x}", cartridge_type);
         }
+
+        // Without a MBC only the first 8 KiB of the cartridge RAM are visible
+        let ram_size = match self.rom.get(0x149) {
+            Some(0x01) => 0x800,
+            Some(0x02..=0x05) => 0x2000,
+            _ => 0,
+        };
+        self.mbc0_ram = vec![0; ram_size];
     }
 
-    pub fn read_word(&mut self, address: u16) -> Result<u16, VmExit> {
-        Ok(self.read_byte(address)? as u16
-            | (self.read_byte(address + 1)? as u16) << 8)
+    /// Whether the cartridge RAM is kept by a battery when powered off
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.rom.get(0x147),
+            Some(0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E)
+                | Some(0x22 | 0xFF)
+        )
     }
 
-    pub fn write_byte(&mut self, address: u16, val: u8) -> Result<(), VmExit> {
-        let address = address as usize;
+    /// ROM bank mapped at `address`, `None` outside of the cartridge ROM
+    pub fn rom_bank(&self, address: u16) -> Option<usize> {
         match address {
-            0x0000..=0x3FFF => Ok(()),
-            0x8000..=0x9FFF => self.gpu.write_byte(address, val),
-            0xC000..=0xDFFF => {
-                self.ram[address - 0xC000] = val;
-                Ok(())
-            }
-            0xFE00..=0xFE9F => Ok(()), // TODO Sprite Attribute Table (OAM)
-            0xFEA0..=0xFEFF => Ok(()), // Unusable
-            0xFF00..=0xFF7F => self.handle_io_write(address, val),
-
-            0xFF80..=0xFFFF => {
-                self.zero_page_ram[address - 0xFF80] = val;
-                Ok(())
-            }
-            _ => panic!(
-                "Trying to write byte 0x{:02x} at address 0x{:04x}",
-                val, address
-            ),
+            0x0000..=0x3FFF => Some(0),
+            // No MBC, the second bank is always mapped
+            0x4000..=0x7FFF => Some(1),
+            _ => None,
         }
     }
 
-    pub fn write_word(&mut self, address: u16, val: u16) -> Result<(), VmExit> {
-        // print!("Writing {:04x} at {:04x}\n", val, address);
-        self.write_byte(address, (val & 0xFF) as u8)?;
-        self.write_byte(address + 1, (val >> 8) as u8)?;
-        Ok(())
+    /// Content of the work RAM
+    pub fn work_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Content of the cartridge RAM
+    pub fn cartridge_ram(&self) -> &[u8] {
+        &self.mbc0_ram
     }
 
-    pub fn get_mut_ref_byte(
+    /// Restore the cartridge RAM, e.g. from a battery save
+    pub fn load_cartridge_ram(&mut self, data: &[u8]) {
+        let length = data.len().min(self.mbc0_ram.len());
+        self.mbc0_ram[..length].copy_from_slice(&data[..length]);
+    }
+
+    /// Save the state of the memory and of every device. The cartridge ROM
+    /// and the boot ROM are not part of it.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        let model = MODELS.iter().position(|&model| model == self.model);
+        writer.write_u8(model.unwrap_or(0) as u8);
+        writer.write_bool(self.bootrom_lock);
+        writer.write_bytes(&self.ram);
+        writer.write_bytes(&self.mbc0_ram);
+        writer.write_bytes(&self.zero_page_ram);
+        writer.write_bytes(&self.sound_registers);
+        writer.write_u8(self.dma);
+        writer.write_u8(self.interrupt_flags);
+        self.gpu.save_state(writer);
+        self.joypad.save_state(writer);
+        self.serial.save_state(writer);
+        self.timer.save_state(writer);
+    }
+
+    pub fn load_state(
         &mut self,
-        address: u16,
-    ) -> Result<&mut u8, VmExit> {
-        Ok(&mut self.rom[address as usize])
+        reader: &mut StateReader,
+    ) -> Result<(), StateError> {
+        let model = MODELS
+            .get(reader.read_u8()? as usize)
+            .ok_or(StateError::Invalid("model"))?;
+        self.set_model(*model);
+        self.bootrom_lock = reader.read_bool()?;
+        if self.bootrom_lock && !self.has_bootrom() {
+            return Err(StateError::Invalid("boot ROM lock"));
+        }
+        reader.read_into(&mut self.ram, "WRAM")?;
+        reader.read_into(&mut self.mbc0_ram, "cartridge RAM")?;
+        reader.read_into(&mut self.zero_page_ram, "HRAM")?;
+        reader.read_into(&mut self.sound_registers, "sound registers")?;
+        self.dma = reader.read_u8()?;
+        self.interrupt_flags = reader.read_u8()? & 0x1F;
+        self.gpu.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.timer.load_state(reader)
+    }
+
+    /// Read in the unusable area between OAM and the IO registers
+    fn unusable_read(&self, address: usize) -> u8 {
+        if self.model.is_cgb() {
+            // Later CGB revisions and the AGB repeat the high nibble of the
+            // low address byte
+            let nibble = (address as u8) & 0xF0;
+            nibble | nibble >> 4
+        } else {
+            0x00
+        }
+    }
+
+    /// Whether `address` is mapped to the boot ROM rather than the cartridge
+    fn in_bootrom(&self, address: usize) -> bool {
+        // The CGB boot ROM leaves a hole for the cartridge header
+        self.bootrom_lock
+            && address < self.bootrom.len()
+            && !(0x100..0x200).contains(&address)
+    }
+
+    /// Copy 160 bytes from `val` * 0x100 to OAM. The DMA reads the source
+    /// behind the CPU's back, but write watchpoints see OAM change.
+    fn oam_dma(&mut self, val: u8) -> Result<(), VmExit> {
+        let source = (val as u16) << 8;
+        for i in 0..0xA0 {
+            let byte = self.read_mapped(source + i)?;
+            self.write_byte(0xFE00 + i, byte)?;
+        }
+        Ok(())
     }
 
     fn handle_io_write(
@@ -106,112 +252,251 @@ impl Mmu {
         match address {
             0xFF00 => {
                 // P1/JOYP - Joypad (R/W)
-                Ok(())
-            }
-            0xFF01 => {
-                // SB - Serial transfer data (R/W)
-                Ok(())
-            }
-            0xFF02 => {
-                // SC - Serial Transfer Control (R/W)
-                Ok(())
+                self.joypad.write_byte(val);
             }
-            0xFF06 => {
-                // TMA - Timer Modulo (R/W)
-                Ok(())
+            0xFF01..=0xFF02 => {
+                // SB/SC - Serial transfer data and control (R/W)
+                self.serial.write_byte(address, val);
             }
-            0xFF10 => {
-                // NR10 - Channel 1 Sweep register (R/W)
-                Ok(())
+            0xFF04..=0xFF07 => {
+                // DIV/TIMA/TMA/TAC - Timer registers (R/W)
+                self.timer.write_byte(address, val);
             }
-            0xFF11 => {
-                // NR11 - Channel 1 Sound length/Wave pattern duty (R/W)
-                Ok(())
-            }
-            0xFF12 => {
-                // NR12 - Channel 1 Volume Envelope (R/W)
-                Ok(())
+            0xFF0F => {
+                // IF - Interrupt Flag (R/W)
+                self.interrupt_flags = val & 0x1F;
             }
-            0xFF13 => {
-                // NR13 - Channel 1 Frequency lo (Write Only)
-                Ok(())
+            0xFF10..=0xFF25 | 0xFF30..=0xFF3F => {
+                // NR10-NR51 - Sound registers and Wave Pattern RAM (R/W)
+                self.sound_registers[address - 0xFF10] = val;
             }
-            0xFF14 => {
-                // NR14 - Channel 1 Frequency hi (R/W)
-                Ok(())
+            0xFF26 => {
+                // NR52 - Sound on/off, channel flags are read only
+                self.sound_registers[address - 0xFF10] = val & 0x80;
             }
-            0xFF17 => {
-                // NR22 - Channel 2 Volume Envelope (R/W)
-                Ok(())
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => {
+                self.gpu.write_byte(address, val)?
             }
-            0xFF19 => {
-                // NR24 - Channel 2 Frequency hi data (R/W)
-                Ok(())
+            0xFF46 => {
+                // DMA - DMA Transfer and Start Address (R/W)
+                self.dma = val;
+                self.oam_dma(val)?;
             }
-            0xFF1A => {
-                // NR30 - Channel 3 Sound on/off (R/W)
-                Ok(())
+            0xFF50 if val & 0x01 == 0x01 => {
+                // Boot ROM lock register, can only be set
+                self.bootrom_lock = false;
             }
-            0xFF21 => {
-                // NR42 - Channel 4 Volume Envelope (R/W)
-                Ok(())
+            _ => {
+                // Unmapped, writes are ignored
             }
-            0xFF23 => {
-                // NR44 - Channel 4 Counter/consecutive; Inital (R/W)
-                Ok(())
+        }
+        Ok(())
+    }
+
+    fn handle_io_read(&mut self, address: usize) -> Result<u8, VmExit> {
+        match address {
+            0xFF00 => {
+                // P1/JOYP - Joypad (R/W)
+                Ok(self.joypad.read_byte())
             }
-            0xFF24 => {
-                // NR50 - Channel control / ON-OFF / Volume (R/W)
-                Ok(())
+            0xFF01..=0xFF02 => Ok(self.serial.read_byte(address)),
+            0xFF04..=0xFF07 => Ok(self.timer.read_byte(address)),
+            0xFF0F => {
+                // IF - Interrupt Flag (R/W)
+                Ok(self.interrupt_flags | 0xE0)
             }
-            0xFF25 => {
-                // NR51 - Selection of Sound output terminal (R/W)
-                Ok(())
+            0xFF10..=0xFF2F => {
+                let index = address - 0xFF10;
+                Ok(self.sound_registers[index] | SOUND_READ_MASKS[index])
             }
-            0xFF26 => {
-                // NR52 Sound on/off
-                Ok(())
+            0xFF30..=0xFF3F => {
+                // Wave Pattern RAM
+                Ok(self.sound_registers[address - 0xFF10])
             }
-            0xFF40..=0xFF4F => self.gpu.write_byte(address, val),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.gpu.read_byte(address),
+            0xFF46 => Ok(self.dma),
             0xFF50 => {
                 // Boot ROM lock register
-                if val & 0x01 == 0x01
-                    && self.read_byte(address as u16)? & 0x01 == 0
-                {
-                    self.bootrom_lock = false;
+                Ok(0xFE | if self.bootrom_lock { 0 } else { 1 })
+            }
+            _ => Ok(OPEN_BUS),
+        }
+    }
+
+    /// Stop on the accesses `watchpoint` watches
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Remove the watchpoints of the range `start`-`end`, returning whether
+    /// there were any
+    pub fn remove_watchpoints(&mut self, start: u16, end: u16) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| {
+            (watchpoint.start, watchpoint.end) != (start, end)
+        });
+        self.watchpoints.len() != count
+    }
+
+    /// Remove a watchpoint, returning whether there was one
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|&w| w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// First watchpoint hit since the last call
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    /// Check the watchpoints against an access at `address` turning `old`
+    /// into `new`, the same for reads, which `changed` the stored value
+    fn watch(
+        &mut self,
+        address: u16,
+        access: Access,
+        old: u8,
+        new: u8,
+        changed: bool,
+    ) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        self.watch_hit = self
+            .watchpoints
+            .iter()
+            .find(|watchpoint| {
+                watchpoint.matches(address, access, new, changed)
+            })
+            .map(|&watchpoint| WatchHit {
+                watchpoint,
+                address,
+                access,
+                old,
+                new,
+            });
+    }
+
+    /// Read a byte, whatever the watchpoints
+    fn read_mapped(&mut self, address: u16) -> Result<u8, VmExit> {
+        let address = address as usize;
+        match address {
+            0x0000..=0x7FFF => {
+                if self.in_bootrom(address) {
+                    return Ok(self.bootrom[address]);
                 }
-                Ok(())
+                Ok(self.rom.get(address).copied().unwrap_or(OPEN_BUS))
             }
-            0xFF0F => { // IF - Interrupt Flag (R/W)
-                if val == 0 || val == 1 {
-                    self.interrupt_flags = val;
-                    Ok(())
-                } else {
-                    panic!("interrupt write 0b{:b}", val);
+            0x8000..=0x9FFF => self.gpu.read_byte(address),
+            0xA000..=0xBFFF => Ok(self
+                .mbc0_ram
+                .get(address - 0xA000)
+                .copied()
+                .unwrap_or(OPEN_BUS)),
+            0xC000..=0xDFFF => Ok(self.ram[address - 0xC000]),
+            0xE000..=0xFDFF => Ok(self.ram[address - 0xE000]),
+            0xFE00..=0xFE9F => self.gpu.read_byte(address),
+            0xFEA0..=0xFEFF => Ok(self.unusable_read(address)),
+            0xFF00..=0xFF7F => self.handle_io_read(address),
+            // 0xFF80-0xFFFF, the rest of the 16 bit address space
+            _ => Ok(self.zero_page_ram[address - 0xFF80]),
+        }
+    }
+
+    /// Write a byte, whatever the watchpoints
+    fn write_mapped(&mut self, address: u16, val: u8) -> Result<(), VmExit> {
+        let address = address as usize;
+        match address {
+            0x0000..=0x7FFF => Ok(()), // No MBC, the ROM is read only
+            0x8000..=0x9FFF => self.gpu.write_byte(address, val),
+            0xA000..=0xBFFF => {
+                if let Some(byte) = self.mbc0_ram.get_mut(address - 0xA000) {
+                    *byte = val;
                 }
+                Ok(())
+            }
+            0xC000..=0xDFFF => {
+                self.ram[address - 0xC000] = val;
+                Ok(())
             }
-            0xFF7F => {
+            0xE000..=0xFDFF => {
+                // Echo RAM
+                self.ram[address - 0xE000] = val;
                 Ok(())
             }
+            0xFE00..=0xFE9F => self.gpu.write_byte(address, val),
+            0xFEA0..=0xFEFF => Ok(()), // Unusable
+            0xFF00..=0xFF7F => self.handle_io_write(address, val),
+            // 0xFF80-0xFFFF, the rest of the 16 bit address space
             _ => {
-                panic!("Trying to write 0x{:02x} to I/O 0x{:04x}", val, address)
+                self.zero_page_ram[address - 0xFF80] = val;
+                Ok(())
             }
         }
     }
+}
 
-    fn handle_io_read(&mut self, address: usize) -> Result<u8, VmExit> {
-        match address {
-            0xFF00 => {
-                // P1/JOYP - Joypad (R/W)
-                Ok(0)
-            }
-            0xFF40..=0xFF4F => self.gpu.read_byte(address),
-            0xFF50 => {
-                // Boot ROM lock register
-                Ok(if self.bootrom_lock { 0 } else { 1 })
-            }
-            0xFF68..=0xFF6B => self.gpu.read_byte(address),
-            _ => panic!("Trying to read at I/O 0x{:04x}", address),
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for Mmu {
+    fn read_byte(&mut self, address: u16) -> Result<u8, VmExit> {
+        let val = self.read_mapped(address)?;
+        if !self.watchpoints.is_empty() {
+            self.watch(address, Access::Read, val, val, false);
+        }
+        Ok(val)
+    }
+
+    fn write_byte(&mut self, address: u16, val: u8) -> Result<(), VmExit> {
+        if !self.watchpoints.iter().any(|w| w.contains(address)) {
+            return self.write_mapped(address, val);
+        }
+        let old = self.read_mapped(address).unwrap_or(OPEN_BUS);
+        self.write_mapped(address, val)?;
+        // Compare what reads back, as unused bits of I/O registers read as
+        // ones and the ROM ignores writes
+        let changed = self.read_mapped(address).unwrap_or(OPEN_BUS) != old;
+        self.watch(address, Access::Write, old, val, changed);
+        Ok(())
+    }
+
+    fn peek_byte(&mut self, address: u16) -> Result<u8, VmExit> {
+        self.read_mapped(address)
+    }
+
+    fn poke_byte(&mut self, address: u16, val: u8) -> Result<(), VmExit> {
+        self.write_mapped(address, val)
+    }
+
+    fn idu_access(&mut self, address: u16) {
+        if let 0xFE00..=0xFEFF = address {
+            self.gpu.oam_bug();
         }
     }
+
+    fn tick(&mut self, cycles: usize) {
+        self.gpu.step(cycles);
+        self.serial.step(cycles);
+        self.timer.step(cycles);
+
+        self.interrupt_flags |= self.gpu.interrupt_flags;
+        self.gpu.interrupt_flags = 0;
+        self.interrupt_flags |= self.serial.interrupt_flags;
+        self.serial.interrupt_flags = 0;
+        self.interrupt_flags |= self.timer.interrupt_flags;
+        self.timer.interrupt_flags = 0;
+        self.interrupt_flags |= self.joypad.interrupt_flags;
+        self.joypad.interrupt_flags = 0;
+    }
 }
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Game Boy hardware model
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// Original Game Boy, early revision
+    Dmg0,
+
+    /// Original Game Boy
+    Dmg,
+
+    /// Game Boy Pocket
+    Mgb,
+
+    /// Super Game Boy
+    Sgb,
+
+    /// Super Game Boy 2
+    Sgb2,
+
+    /// Game Boy Color
+    Cgb,
+
+    /// Game Boy Advance
+    Agb,
+}
+
+pub const MODELS: [Model; 7] = [
+    Model::Dmg0,
+    Model::Dmg,
+    Model::Mgb,
+    Model::Sgb,
+    Model::Sgb2,
+    Model::Cgb,
+    Model::Agb,
+];
+
+impl Model {
+    /// Pick the model a cartridge is made for from its header
+    pub fn from_header(rom: &[u8]) -> Model {
+        let header = |address: usize| rom.get(address).copied().unwrap_or(0);
+        if header(0x143) & 0x80 == 0x80 {
+            // CGB flag, set by CGB enhanced and CGB only cartridges
+            Model::Cgb
+        } else if header(0x146) == 0x03 && header(0x14B) == 0x33 {
+            // SGB flag, only honored with the new licensee code
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    /// Whether this is a Game Boy Color or a later model
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    /// Whether 16-bit increments in 0xFE00-0xFEFF corrupt OAM
+    pub fn has_oam_bug(self) -> bool {
+        !self.is_cgb()
+    }
+
+    /// Usual file name of the boot ROM of this model
+    pub fn bootrom_name(self) -> &'static str {
+        match self {
+            Model::Dmg0 => "dmg0_boot.bin",
+            Model::Dmg => "dmg_boot.bin",
+            Model::Mgb => "mgb_boot.bin",
+            Model::Sgb => "sgb_boot.bin",
+            Model::Sgb2 => "sgb2_boot.bin",
+            Model::Cgb => "cgb_boot.bin",
+            Model::Agb => "agb_boot.bin",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Model::Dmg0 => "dmg0",
+            Model::Dmg => "dmg",
+            Model::Mgb => "mgb",
+            Model::Sgb => "sgb",
+            Model::Sgb2 => "sgb2",
+            Model::Cgb => "cgb",
+            Model::Agb => "agb",
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        MODELS
+            .iter()
+            .copied()
+            .find(|model| model.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown model {}", s))
+    }
+}
@@ -0,0 +1,805 @@
+//! Input movies: the joypad state of every frame, along with what the run
+//! starts from, to replay a run exactly
+//!
+//! Movies are written in their own format, and VBA (`.vbm`) and BizHawk
+//! (`.bk2`) movies can be imported. A movie starts with the magic bytes, the
+//! format version, the version of the emulator that wrote it, what the ROM
+//! and boot ROM must be, the model, the start condition and then two bytes
+//! per frame: the buttons packed by `Buttons::to_bits` and the events.
+
+use crate::emulator::Emulator;
+use crate::joypad::Buttons;
+use crate::model::{Model, MODELS};
+use crate::state::{crc32, StateError, StateReader, StateWriter};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Magic bytes at the start of every movie
+pub const MAGIC: &[u8; 4] = b"GBMV";
+
+/// Version of the format, bumped whenever the layout changes
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Magic bytes of VBA movies
+const VBM_MAGIC: &[u8; 4] = b"VBM\x1A";
+
+/// Magic bytes of zip archives, which BizHawk movies are
+const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+
+/// Event bit of a frame power cycling the machine before it runs
+const EVENT_RESET: u8 = 0x01;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MovieError {
+    /// Not a movie in any known format
+    UnknownFormat,
+
+    /// Written with another version of the format
+    UnsupportedVersion(u16),
+
+    /// Recorded with another cartridge or boot ROM
+    RomMismatch,
+
+    /// The movie ends before its header does
+    Truncated,
+
+    /// Something in the movie can't be replayed
+    Invalid(String),
+
+    /// The save state the movie starts from doesn't load
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::UnknownFormat => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "unsupported movie version {}", version)
+            }
+            MovieError::RomMismatch => {
+                write!(f, "movie made with another cartridge or boot ROM")
+            }
+            MovieError::Truncated => write!(f, "truncated movie"),
+            MovieError::Invalid(what) => write!(f, "invalid movie: {}", what),
+            MovieError::State(e) => write!(f, "movie start: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> MovieError {
+        match e {
+            StateError::Truncated => MovieError::Truncated,
+            e => MovieError::State(e),
+        }
+    }
+}
+
+/// What happens during one frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Input {
+    pub buttons: Buttons,
+
+    /// Power cycle the machine before running the frame
+    pub reset: bool,
+}
+
+impl Input {
+    /// Get `emulator` ready to run the frame
+    pub fn apply(self, emulator: &mut Emulator) {
+        if self.reset {
+            emulator.reset();
+        }
+        emulator.set_buttons(self.buttons);
+    }
+}
+
+/// What a movie starts from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Start {
+    /// Power on, the cartridge RAM holding the given bytes and zeros past
+    /// them
+    PowerOn { cartridge_ram: Vec<u8> },
+
+    /// Load the given save state
+    State(Vec<u8>),
+}
+
+/// How to tell whether the running cartridge is the one a movie was made
+/// with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RomCheck {
+    /// CRC-32 of the whole ROM
+    Crc32(u32),
+
+    /// Header and global checksums, at 0x014D and 0x014E, the latter
+    /// little endian
+    Header { complement: u8, checksum: u16 },
+
+    /// Anything goes
+    Unknown,
+}
+
+impl RomCheck {
+    fn matches(self, rom: &[u8]) -> bool {
+        match self {
+            RomCheck::Crc32(crc) => crc32(rom) == crc,
+            RomCheck::Header {
+                complement,
+                checksum,
+            } => {
+                let byte = |address| rom.get(address).copied().unwrap_or(0);
+                byte(0x14D) == complement
+                    && u16::from_le_bytes([byte(0x14E), byte(0x14F)])
+                        == checksum
+            }
+            RomCheck::Unknown => true,
+        }
+    }
+}
+
+pub struct Movie {
+    rom: RomCheck,
+
+    /// CRC-32 of the boot ROM, of nothing when the boot sequence is
+    /// skipped, `None` when unknown
+    bootrom: Option<u32>,
+
+    /// Model to emulate, `None` to leave it up to the emulator
+    model: Option<Model>,
+
+    start: Start,
+    inputs: Vec<Input>,
+}
+
+impl Movie {
+    /// Start recording a movie from power on, which resets `emulator`
+    pub fn from_power_on(emulator: &mut Emulator) -> Movie {
+        emulator.reset();
+        let cartridge_ram = emulator.memory.cartridge_ram().to_vec();
+        Movie::new(emulator, Start::PowerOn { cartridge_ram })
+    }
+
+    /// Start recording a movie from the current state of `emulator`
+    pub fn from_state(emulator: &Emulator) -> Movie {
+        Movie::new(emulator, Start::State(emulator.save_state()))
+    }
+
+    fn new(emulator: &Emulator, start: Start) -> Movie {
+        Movie {
+            rom: RomCheck::Crc32(crc32(emulator.memory.rom())),
+            bootrom: Some(crc32(emulator.memory.bootrom())),
+            model: Some(emulator.model()),
+            start,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Record the input of the next frame
+    pub fn push(&mut self, input: Input) {
+        self.inputs.push(input);
+    }
+
+    /// Input of `frame`, `None` past the end of the movie
+    pub fn input(&self, frame: u64) -> Option<Input> {
+        self.inputs.get(usize::try_from(frame).ok()?).copied()
+    }
+
+    /// Number of frames recorded
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Put `emulator` in the state the movie starts from. It must run the
+    /// cartridge and boot ROM the movie was made with.
+    pub fn start(&self, emulator: &mut Emulator) -> Result<(), MovieError> {
+        let bootrom = crc32(emulator.memory.bootrom());
+        if !self.rom.matches(emulator.memory.rom())
+            || self.bootrom.is_some_and(|crc| crc != bootrom)
+        {
+            return Err(MovieError::RomMismatch);
+        }
+        if let Some(model) = self.model {
+            emulator.set_model(Some(model));
+        }
+        match &self.start {
+            Start::PowerOn { cartridge_ram } => {
+                let mut ram = vec![0; emulator.memory.cartridge_ram().len()];
+                let length = cartridge_ram.len().min(ram.len());
+                ram[..length].copy_from_slice(&cartridge_ram[..length]);
+                emulator.memory.load_cartridge_ram(&ram);
+                emulator.reset();
+            }
+            Start::State(state) => emulator.load_state(state)?,
+        }
+        Ok(())
+    }
+
+    /// Serialise the movie in its own format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u16(FORMAT_VERSION);
+        writer.write_bytes(env!("CARGO_PKG_VERSION").as_bytes());
+        match self.rom {
+            RomCheck::Crc32(crc) => {
+                writer.write_u8(0);
+                writer.write_u32(crc);
+            }
+            RomCheck::Header {
+                complement,
+                checksum,
+            } => {
+                writer.write_u8(1);
+                writer.write_u8(complement);
+                writer.write_u16(checksum);
+            }
+            RomCheck::Unknown => writer.write_u8(2),
+        }
+        writer.write_bool(self.bootrom.is_some());
+        writer.write_u32(self.bootrom.unwrap_or(0));
+        let model =
+            self.model.and_then(|m| MODELS.iter().position(|&x| x == m));
+        writer.write_u8(model.map_or(0xFF, |i| i as u8));
+        match &self.start {
+            Start::PowerOn { cartridge_ram } => {
+                writer.write_u8(0);
+                writer.write_bytes(cartridge_ram);
+            }
+            Start::State(state) => {
+                writer.write_u8(1);
+                writer.write_bytes(state);
+            }
+        }
+        writer.write_u32(self.inputs.len() as u32);
+        for input in &self.inputs {
+            writer.write_u8(input.buttons.to_bits());
+            writer.write_u8(if input.reset { EVENT_RESET } else { 0 });
+        }
+
+        let mut data = MAGIC.to_vec();
+        data.extend(writer.into_bytes());
+        data
+    }
+
+    /// Parse a movie in its own format, or import a VBA or BizHawk one
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        if let Some(data) = data.strip_prefix(&MAGIC[..]) {
+            Movie::read(&mut StateReader::new(data))
+        } else if data.starts_with(VBM_MAGIC) {
+            import_vbm(data)
+        } else if data.starts_with(ZIP_MAGIC) {
+            let log = zip_entry(data, "Input Log.txt").ok_or_else(|| {
+                MovieError::Invalid("no input log in archive".to_string())
+            })?;
+            import_bk2_log(&String::from_utf8_lossy(&log))
+        } else if data.starts_with(b"[Input]") {
+            // Input log taken out of its archive
+            import_bk2_log(&String::from_utf8_lossy(data))
+        } else {
+            Err(MovieError::UnknownFormat)
+        }
+    }
+
+    fn read(reader: &mut StateReader) -> Result<Movie, MovieError> {
+        let version = reader.read_u16()?;
+        if version != FORMAT_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        // Only there to tell where a movie comes from
+        reader.read_bytes()?;
+        let rom = match reader.read_u8()? {
+            0 => RomCheck::Crc32(reader.read_u32()?),
+            1 => RomCheck::Header {
+                complement: reader.read_u8()?,
+                checksum: reader.read_u16()?,
+            },
+            2 => RomCheck::Unknown,
+            _ => return Err(MovieError::Invalid("ROM check".to_string())),
+        };
+        let has_bootrom = reader.read_bool()?;
+        let bootrom = Some(reader.read_u32()?).filter(|_| has_bootrom);
+        let model = match reader.read_u8()? {
+            0xFF => None,
+            i => Some(
+                *MODELS
+                    .get(i as usize)
+                    .ok_or_else(|| MovieError::Invalid("model".to_string()))?,
+            ),
+        };
+        let start = match reader.read_u8()? {
+            0 => Start::PowerOn {
+                cartridge_ram: reader.read_bytes()?.to_vec(),
+            },
+            1 => Start::State(reader.read_bytes()?.to_vec()),
+            _ => return Err(MovieError::Invalid("start".to_string())),
+        };
+        let frames = reader.read_u32()?;
+        let inputs = (0..frames)
+            .map(|_| {
+                Ok(Input {
+                    buttons: Buttons::from_bits(reader.read_u8()?),
+                    reset: reader.read_u8()? & EVENT_RESET != 0,
+                })
+            })
+            .collect::<Result<_, StateError>>()?;
+        Ok(Movie {
+            rom,
+            bootrom,
+            model,
+            start,
+            inputs,
+        })
+    }
+}
+
+/// Import a VBA movie, which starts with a 64 bytes header
+fn import_vbm(data: &[u8]) -> Result<Movie, MovieError> {
+    let u32_at = |offset: usize| -> Result<u32, MovieError> {
+        let bytes =
+            data.get(offset..offset + 4).ok_or(MovieError::Truncated)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    if data.len() < 0x40 {
+        return Err(MovieError::Truncated);
+    }
+    let version = u32_at(0x04)?;
+    if version != 1 {
+        return Err(MovieError::UnsupportedVersion(version as u16));
+    }
+    let frames = u32_at(0x0C)? as usize;
+    let start_flags = data[0x14];
+    let controllers = (data[0x15] & 0x0F).count_ones() as usize;
+    let system_flags = data[0x16];
+    let start_offset = u32_at(0x38)? as usize;
+    let inputs_offset = u32_at(0x3C)? as usize;
+
+    let model = match system_flags & 0x07 {
+        0x00 => Model::Dmg,
+        0x02 => Model::Cgb,
+        0x04 => Model::Sgb,
+        _ => {
+            return Err(MovieError::Invalid(
+                "Game Boy Advance movie".to_string(),
+            ))
+        }
+    };
+    let start = match start_flags & 0x03 {
+        0x00 => Start::PowerOn {
+            cartridge_ram: Vec::new(),
+        },
+        0x02 => Start::PowerOn {
+            cartridge_ram: data
+                .get(start_offset..inputs_offset)
+                .ok_or(MovieError::Truncated)?
+                .to_vec(),
+        },
+        _ => {
+            return Err(MovieError::Invalid(
+                "starts from a VBA save state".to_string(),
+            ))
+        }
+    };
+    if controllers == 0 {
+        return Err(MovieError::Invalid("no controller".to_string()));
+    }
+
+    // Only the first controller matters, each frame holds a u16 for every
+    // controller used
+    let stride = controllers * 2;
+    let inputs = data
+        .get(inputs_offset..)
+        .ok_or(MovieError::Truncated)?
+        .chunks_exact(stride)
+        .take(frames)
+        .map(|frame| {
+            let bits = u16::from_le_bytes([frame[0], frame[1]]);
+            let pressed = |mask: u16| bits & mask != 0;
+            Input {
+                buttons: Buttons {
+                    a: pressed(0x0001),
+                    b: pressed(0x0002),
+                    select: pressed(0x0004),
+                    start: pressed(0x0008),
+                    right: pressed(0x0010),
+                    left: pressed(0x0020),
+                    up: pressed(0x0040),
+                    down: pressed(0x0080),
+                },
+                // Older versions of VBA used another bit for resets
+                reset: pressed(0x0400 | 0x0800),
+            }
+        })
+        .collect::<Vec<_>>();
+    if inputs.len() < frames {
+        return Err(MovieError::Truncated);
+    }
+
+    Ok(Movie {
+        rom: RomCheck::Header {
+            complement: data[0x31],
+            checksum: u16::from_le_bytes([data[0x32], data[0x33]]),
+        },
+        bootrom: None,
+        model: Some(model),
+        start,
+        inputs,
+    })
+}
+
+/// Import the input log of a BizHawk movie. Its `LogKey` names the buttons
+/// of each column of the lines between `[Input]` and `[/Input]`, where any
+/// character but `.` means pressed.
+fn import_bk2_log(log: &str) -> Result<Movie, MovieError> {
+    // Order of the Gambatte core, used by logs without a key
+    let mut keys: Vec<String> = [
+        "Up", "Down", "Left", "Right", "Start", "Select", "B", "A", "Power",
+    ]
+    .iter()
+    .map(|key| key.to_string())
+    .collect();
+    let mut inputs = Vec::new();
+    let mut in_input = false;
+    for line in log.lines().map(str::trim) {
+        match line {
+            "[Input]" => in_input = true,
+            "[/Input]" => break,
+            _ if !in_input => {}
+            _ if line.starts_with("LogKey:") => {
+                keys = line["LogKey:".len()..]
+                    .split(['#', '|'])
+                    .filter(|key| !key.is_empty())
+                    .map(|key| key.trim_start_matches("P1 ").to_string())
+                    .collect();
+            }
+            _ if line.starts_with('|') => {
+                let mut input = Input::default();
+                let columns = line.chars().filter(|&c| c != '|');
+                for (key, column) in keys.iter().zip(columns) {
+                    if column == '.' {
+                        continue;
+                    }
+                    let buttons = &mut input.buttons;
+                    match key.as_str() {
+                        "Up" => buttons.up = true,
+                        "Down" => buttons.down = true,
+                        "Left" => buttons.left = true,
+                        "Right" => buttons.right = true,
+                        "Start" => buttons.start = true,
+                        "Select" => buttons.select = true,
+                        "B" => buttons.b = true,
+                        "A" => buttons.a = true,
+                        "Power" | "Reset" => input.reset = true,
+                        // Other consoles or analog inputs
+                        _ => {}
+                    }
+                }
+                inputs.push(input);
+            }
+            _ => {}
+        }
+    }
+    if !in_input {
+        return Err(MovieError::Invalid("no [Input] section".to_string()));
+    }
+
+    Ok(Movie {
+        rom: RomCheck::Unknown,
+        bootrom: None,
+        model: None,
+        start: Start::PowerOn {
+            cartridge_ram: Vec::new(),
+        },
+        inputs,
+    })
+}
+
+/// Content of the file called `name` in the zip archive `data`, either
+/// stored or deflated
+fn zip_entry(data: &[u8], name: &str) -> Option<Vec<u8>> {
+    let u16_at = |offset: usize| {
+        let bytes = data.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+    };
+    let u32_at = |offset: usize| {
+        let bytes = data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            as usize)
+    };
+
+    // The end of central directory record is last, followed by a comment
+    // of up to 64 KiB
+    let end = (0..=data.len().checked_sub(22)?)
+        .rev()
+        .take(0x10000 + 1)
+        .find(|&i| data[i..].starts_with(b"PK\x05\x06"))?;
+    let entries = u16_at(end + 10)?;
+    let mut entry = u32_at(end + 16)?;
+
+    for _ in 0..entries {
+        if !data.get(entry..)?.starts_with(b"PK\x01\x02") {
+            return None;
+        }
+        let method = u16_at(entry + 10)?;
+        let compressed_size = u32_at(entry + 20)?;
+        let name_length = u16_at(entry + 28)?;
+        let extra_length = u16_at(entry + 30)?;
+        let comment_length = u16_at(entry + 32)?;
+        let header = u32_at(entry + 42)?;
+        let entry_name = data.get(entry + 46..entry + 46 + name_length)?;
+        entry += 46 + name_length + extra_length + comment_length;
+        if entry_name != name.as_bytes() {
+            continue;
+        }
+
+        // The local header has its own name and extra field lengths
+        let start = header + 30 + u16_at(header + 26)? + u16_at(header + 28)?;
+        let content = data.get(start..start + compressed_size)?;
+        return match method {
+            0 => Some(content.to_vec()),
+            8 => miniz_oxide::inflate::decompress_to_vec(content).ok(),
+            _ => None,
+        };
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emulator(rom_byte: u8) -> Emulator {
+        Emulator::with_code(&[
+            (0x100, &[0x18, 0xFE]), // JR -2
+            (0x4000, &[rom_byte]),
+        ])
+    }
+
+    fn inputs() -> Vec<Input> {
+        (0..=255)
+            .map(|bits| Input {
+                buttons: Buttons::from_bits(bits),
+                reset: bits % 7 == 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut emulator = emulator(0);
+        for _ in 0..3 {
+            emulator.run_frame().unwrap();
+        }
+        for mut movie in [
+            Movie::from_state(&emulator),
+            Movie::from_power_on(&mut emulator),
+        ] {
+            for input in inputs() {
+                movie.push(input);
+            }
+            let bytes = movie.to_bytes();
+            let read = Movie::from_bytes(&bytes).unwrap();
+            assert_eq!(read.rom, movie.rom);
+            assert_eq!(read.bootrom, movie.bootrom);
+            assert_eq!(read.model, movie.model);
+            assert_eq!(read.start, movie.start);
+            assert_eq!(read.inputs, inputs());
+            assert_eq!(read.input(255), inputs().last().copied());
+            assert_eq!(read.input(256), None);
+            assert_eq!(read.to_bytes(), bytes);
+            assert_eq!(read.start(&mut emulator), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejected() {
+        let mut emulator = emulator(0);
+        let mut movie = Movie::from_power_on(&mut emulator);
+        movie.push(Input::default());
+        let bytes = movie.to_bytes();
+
+        let error = |data: &[u8]| Movie::from_bytes(data).err();
+        assert_eq!(error(b"not a movie"), Some(MovieError::UnknownFormat));
+        for length in 0..bytes.len() {
+            assert!(error(&bytes[..length]).is_some(), "{}", length);
+        }
+        assert_eq!(error(&bytes[..8]), Some(MovieError::Truncated));
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            error(&newer),
+            Some(MovieError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+
+        let mut other = self::emulator(1);
+        assert_eq!(movie.start(&mut other), Err(MovieError::RomMismatch));
+    }
+
+    /// VBA movie with 2 controllers, starting from SRAM
+    fn vbm(frames: &[u16]) -> Vec<u8> {
+        let sram = [0x12, 0x34, 0x56];
+        let mut data = vec![0; 0x40];
+        data[..4].copy_from_slice(VBM_MAGIC);
+        data[0x04..0x08].copy_from_slice(&1u32.to_le_bytes());
+        data[0x0C..0x10].copy_from_slice(&(frames.len() as u32).to_le_bytes());
+        data[0x14] = 0x02;
+        data[0x15] = 0x03;
+        data[0x31] = 0xAB;
+        data[0x32..0x34].copy_from_slice(&[0xCD, 0xEF]);
+        data[0x38..0x3C].copy_from_slice(&0x40u32.to_le_bytes());
+        data[0x3C..0x40].copy_from_slice(&0x43u32.to_le_bytes());
+        data.extend_from_slice(&sram);
+        for &frame in frames {
+            data.extend_from_slice(&frame.to_le_bytes());
+            // Second controller, ignored
+            data.extend_from_slice(&0xFFFFu16.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn vbm_import() {
+        let movie = Movie::from_bytes(&vbm(&[0x0000, 0x0081, 0x0808])).unwrap();
+        assert_eq!(
+            movie.rom,
+            RomCheck::Header {
+                complement: 0xAB,
+                checksum: 0xEFCD,
+            }
+        );
+        assert_eq!(movie.bootrom, None);
+        assert_eq!(movie.model, Some(Model::Dmg));
+        assert_eq!(
+            movie.start,
+            Start::PowerOn {
+                cartridge_ram: vec![0x12, 0x34, 0x56],
+            }
+        );
+        let a_down = Buttons {
+            a: true,
+            down: true,
+            ..Buttons::default()
+        };
+        let start = Buttons {
+            start: true,
+            ..Buttons::default()
+        };
+        assert_eq!(
+            movie.inputs,
+            [
+                Input::default(),
+                Input {
+                    buttons: a_down,
+                    reset: false,
+                },
+                Input {
+                    buttons: start,
+                    reset: true,
+                },
+            ]
+        );
+
+        let error = |data: &[u8]| Movie::from_bytes(data).err();
+        let data = vbm(&[0; 4]);
+        assert_eq!(error(&data[..data.len() - 1]), Some(MovieError::Truncated));
+        assert_eq!(error(&data[..0x20]), Some(MovieError::Truncated));
+        let mut gba = data.clone();
+        gba[0x16] = 0x01;
+        assert!(matches!(error(&gba), Some(MovieError::Invalid(_))));
+        let mut from_state = data.clone();
+        from_state[0x14] = 0x01;
+        assert!(matches!(error(&from_state), Some(MovieError::Invalid(_))));
+        let mut newer = data;
+        newer[0x04] = 2;
+        assert_eq!(error(&newer), Some(MovieError::UnsupportedVersion(2)));
+    }
+
+    const BK2_LOG: &str = "[Input]
+LogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|
+|.........|
+|U..R...A.|
+|....S...P|
+[/Input]
+|UDLRSsBAP|
+";
+
+    /// Zip archive storing `files` uncompressed
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for (name, content) in files {
+            let header = data.len() as u32;
+            data.extend_from_slice(ZIP_MAGIC);
+            data.extend_from_slice(&[0; 14]);
+            data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&0u16.to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(content);
+
+            directory.extend_from_slice(b"PK\x01\x02");
+            directory.extend_from_slice(&[0; 16]);
+            directory.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&header.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let offset = data.len() as u32;
+        let size = directory.len() as u32;
+        data.extend(directory);
+        data.extend_from_slice(b"PK\x05\x06");
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn bk2_import() {
+        let archive = zip(&[
+            ("Header.txt", b"MovieVersion BizHawk v2.0\n"),
+            ("Input Log.txt", BK2_LOG.as_bytes()),
+        ]);
+        for data in [BK2_LOG.as_bytes(), &archive] {
+            let movie = Movie::from_bytes(data).unwrap();
+            assert_eq!(movie.rom, RomCheck::Unknown);
+            assert_eq!(movie.model, None);
+            let up_right_a = Buttons {
+                up: true,
+                right: true,
+                a: true,
+                ..Buttons::default()
+            };
+            let start = Buttons {
+                start: true,
+                ..Buttons::default()
+            };
+            assert_eq!(
+                movie.inputs,
+                [
+                    Input::default(),
+                    Input {
+                        buttons: up_right_a,
+                        reset: false,
+                    },
+                    Input {
+                        buttons: start,
+                        reset: true,
+                    },
+                ]
+            );
+        }
+
+        // Gambatte order without a key
+        let movie = Movie::from_bytes(b"[Input]\n|U.......P|\n").unwrap();
+        assert_eq!(
+            movie.inputs,
+            [Input {
+                buttons: Buttons {
+                    up: true,
+                    ..Buttons::default()
+                },
+                reset: true,
+            }]
+        );
+
+        let error = |data: &[u8]| Movie::from_bytes(data).err();
+        let no_log = zip(&[("Header.txt", b"")]);
+        assert!(matches!(error(&no_log), Some(MovieError::Invalid(_))));
+        let no_input = zip(&[("Input Log.txt", b"|U.......P|\n")]);
+        assert!(matches!(error(&no_input), Some(MovieError::Invalid(_))));
+    }
+}
@@ -0,0 +1,350 @@
+//! Game Boy Printer plugged in the link port
+//!
+//! Every packet sent by the Game Boy looks like
+//! `88 33 | command | compression | length (LE) | data | checksum (LE) | 00 00`.
+//! The printer answers `00` to every byte but the last two, for which it
+//! sends `81` to tell it is alive and then its status byte.
+
+use crate::image::save_png;
+use crate::serial::SerialDevice;
+
+use std::path::PathBuf;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+/// Width of the paper in tiles
+const TILES_PER_ROW: usize = 20;
+
+/// Bytes taken by a row of tiles
+const ROW_BYTES: usize = TILES_PER_ROW * 16;
+
+/// The printer memory holds 9 data packets of two tile rows each
+const BUFFER_BYTES: usize = 9 * 2 * ROW_BYTES;
+
+/// Blank pixel lines fed for each unit of a margin
+const MARGIN_LINES: usize = 8;
+
+/// Status requests answered as busy after a print command
+const PRINT_POLLS: u8 = 8;
+
+/// Paper shades for the four colours of the palette byte
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Copy)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLo,
+    LengthHi,
+    Data,
+    ChecksumLo,
+    ChecksumHi,
+    KeepAlive,
+    Status,
+}
+
+pub struct Printer {
+    /// Where the printed strips are written
+    output_dir: PathBuf,
+
+    /// Number of strips printed so far
+    printed: usize,
+
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+
+    /// Decompressed tile data waiting to be printed
+    buffer: Vec<u8>,
+
+    status: u8,
+
+    /// Status requests left before the current print is done
+    busy: u8,
+}
+
+impl Printer {
+    /// Create a printer writing each printed strip as a PNG in `output_dir`
+    pub fn new<P: Into<PathBuf>>(output_dir: P) -> Printer {
+        Printer {
+            output_dir: output_dir.into(),
+            printed: 0,
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            buffer: Vec::new(),
+            status: 0,
+            busy: 0,
+        }
+    }
+
+    fn handle_packet(&mut self) {
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.busy = 0;
+                self.status = 0;
+            }
+            CMD_PRINT if self.data.len() >= 4 => {
+                let sheets = self.data[0];
+                let margins = self.data[1];
+                let palette = self.data[2];
+                if sheets > 0 {
+                    self.print(margins, palette);
+                }
+                self.buffer.clear();
+                self.busy = PRINT_POLLS;
+            }
+            CMD_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    std::mem::take(&mut self.data)
+                };
+                let room = BUFFER_BYTES - self.buffer.len();
+                self.buffer.extend(data.into_iter().take(room));
+            }
+            CMD_STATUS => {
+                self.busy = self.busy.saturating_sub(1);
+            }
+            _ => (),
+        }
+
+        self.status &= STATUS_CHECKSUM_ERROR;
+        if self.busy > 0 {
+            self.status |= STATUS_PRINTING;
+        }
+        if self.buffer.len() == BUFFER_BYTES {
+            self.status |= STATUS_DATA_FULL;
+        }
+        if !self.buffer.is_empty() {
+            self.status |= STATUS_UNPROCESSED;
+        }
+    }
+
+    /// Render the buffered tiles and write them to the next PNG file
+    fn print(&mut self, margins: u8, palette: u8) {
+        // A zero palette is what most games send for the default one
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        let top = (margins >> 4) as usize * MARGIN_LINES;
+        let bottom = (margins & 0x0F) as usize * MARGIN_LINES;
+        let rows = self.buffer.len() / ROW_BYTES;
+        let width = TILES_PER_ROW * 8;
+        let height = top + rows * 8 + bottom;
+        if height == 0 {
+            return;
+        }
+
+        let mut image = vec![0xFF; width * height * 4];
+        for row in 0..rows {
+            for tile in 0..TILES_PER_ROW {
+                let offset = row * ROW_BYTES + tile * 16;
+                for y in 0..8 {
+                    let lo = self.buffer[offset + y * 2];
+                    let hi = self.buffer[offset + y * 2 + 1];
+                    for x in 0..8 {
+                        let bit = 7 - x;
+                        let color = ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1);
+                        let shade =
+                            SHADES[(palette >> (color * 2)) as usize & 3];
+                        let line = top + row * 8 + y;
+                        let pixel = (line * width + tile * 8 + x) * 4;
+                        image[pixel..pixel + 3].copy_from_slice(&[shade; 3]);
+                    }
+                }
+            }
+        }
+
+        self.printed += 1;
+        let path = self
+            .output_dir
+            .join(format!("print-{:04}.png", self.printed));
+        if let Err(e) = save_png(&path, width as u32, height as u32, &image) {
+            eprintln!("Could not write {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            PacketState::Magic1 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if byte == 0x33 => PacketState::Command,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 0x01 == 0x01;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthLo
+            }
+            PacketState::LengthLo => {
+                self.length = byte as usize;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthHi
+            }
+            PacketState::LengthHi => {
+                self.length |= (byte as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 {
+                    PacketState::ChecksumLo
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length {
+                    PacketState::ChecksumLo
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::ChecksumLo => {
+                self.received_checksum = byte as u16;
+                PacketState::ChecksumHi
+            }
+            PacketState::ChecksumHi => {
+                self.received_checksum |= (byte as u16) << 8;
+                PacketState::KeepAlive
+            }
+            PacketState::KeepAlive => {
+                self.handle_packet();
+                response = 0x81;
+                PacketState::Status
+            }
+            PacketState::Status => {
+                response = self.status;
+                PacketState::Magic1
+            }
+        };
+        response
+    }
+}
+
+/// Expand the run-length encoding of data packets. A control byte with its
+/// top bit set repeats the next byte `(control & 0x7F) + 2` times, otherwise
+/// `control + 1` bytes are copied as is.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 == 0x80 {
+            if let Some(&byte) = data.get(i) {
+                let count = (control & 0x7F) as usize + 2;
+                res.extend(std::iter::repeat_n(byte, count));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            res.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::load_png;
+
+    /// Frame `data` as a packet, with its checksum and the two bytes the
+    /// printer answers
+    fn packet(command: u8, compression: u8, data: &[u8]) -> Vec<u8> {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut packet = vec![0x88, 0x33, command, compression];
+        packet.extend_from_slice(&length);
+        packet.extend_from_slice(data);
+        let checksum = packet[2..]
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(&[0x00, 0x00]);
+        packet
+    }
+
+    /// Send `packet` and return the two last answers of the printer
+    fn send(printer: &mut Printer, packet: &[u8]) -> [u8; 2] {
+        let answers: Vec<u8> =
+            packet.iter().map(|&byte| printer.transfer(byte)).collect();
+        assert!(answers[..answers.len() - 2].iter().all(|&a| a == 0));
+        [answers[answers.len() - 2], answers[answers.len() - 1]]
+    }
+
+    #[test]
+    fn print_strip() {
+        let dir = std::env::temp_dir()
+            .join(format!("gbemu-printer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut printer = Printer::new(&dir);
+
+        assert_eq!(send(&mut printer, &packet(CMD_INIT, 0, &[])), [0x81, 0]);
+
+        // A row of black tiles, the first two bytes copied as they are,
+        // then a row of white ones
+        let tiles = [
+            0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xBA,
+            0xFF, // 320 * FF
+            0xFF, 0x00, 0xFF, 0x00, 0xBC, 0x00, // 320 * 00
+        ];
+        assert_eq!(
+            send(&mut printer, &packet(CMD_DATA, 1, &tiles)),
+            [0x81, STATUS_UNPROCESSED]
+        );
+
+        let mut bad = packet(CMD_STATUS, 0, &[]);
+        bad[6] ^= 0xFF;
+        assert_eq!(
+            send(&mut printer, &bad),
+            [0x81, STATUS_UNPROCESSED | STATUS_CHECKSUM_ERROR]
+        );
+
+        // One sheet, no margins, default palette
+        let print = packet(CMD_PRINT, 0, &[0x01, 0x00, 0xE4, 0x40]);
+        assert_eq!(send(&mut printer, &print), [0x81, STATUS_PRINTING]);
+
+        let (width, height, image) =
+            load_png(dir.join("print-0001.png")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((width, height), (160, 16));
+        let (black, white) = image.split_at(160 * 8 * 4);
+        assert!(black
+            .chunks(4)
+            .all(|pixel| pixel == [0x00, 0x00, 0x00, 0xFF]));
+        assert!(white.chunks(4).all(|pixel| pixel == [0xFF; 4]));
+    }
+}
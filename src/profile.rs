@@ -0,0 +1,396 @@
+//! Cycle profiler, counting the cycles spent at every PC and in every
+//! function of the call graph
+//!
+//! Functions are entered by CALL, RST and interrupts, and left once SP rises
+//! back above their return address, which also covers code dropping it
+//! instead of returning. Cycles are those of the CPU, 70224 per frame of
+//! which 4560 in VBlank.
+
+use crate::emulator::{Emulator, VmExit};
+use crate::symbols::Symbols;
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Deepest call stack tracked, deeper calls count in their caller
+const MAX_DEPTH: usize = 256;
+
+/// Name of the code running outside of any call
+const ROOT: &str = "root";
+
+/// An address and the ROM bank mapped there, if any
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    pub bank: Option<usize>,
+    pub address: u16,
+}
+
+impl Location {
+    /// `address` with the ROM bank `emulator` maps there
+    pub fn of(emulator: &Emulator, address: u16) -> Location {
+        Location {
+            bank: emulator.memory.rom_bank(address),
+            address,
+        }
+    }
+
+    /// Label of the location, or the location itself
+    fn name(&self, symbols: &Symbols) -> String {
+        symbols
+            .label(self.bank, self.address)
+            .unwrap_or_else(|| self.to_string())
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.address),
+            None => write!(f, "{:04X}", self.address),
+        }
+    }
+}
+
+/// A function called from a path of the call graph
+struct Node {
+    /// `None` for the root
+    function: Option<Location>,
+    parent: usize,
+    children: HashMap<Location, usize>,
+
+    /// Whether the function is also an ancestor, so that its cycles are
+    /// counted once
+    recursive: bool,
+
+    /// Cycles spent in the function itself, in total and this frame
+    cycles: u64,
+    frame_cycles: u64,
+}
+
+pub struct Profiler {
+    /// Cycles spent at each PC
+    pcs: HashMap<Location, u64>,
+
+    /// Call graph, parents before their children and the root first
+    nodes: Vec<Node>,
+
+    /// Node of each function running, and SP once it returns
+    stack: Vec<(usize, u16)>,
+
+    /// Most cycles a function and its callees took in a frame
+    peaks: HashMap<Option<Location>, u64>,
+
+    frames: u64,
+    cycles: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            pcs: HashMap::new(),
+            nodes: vec![Node {
+                function: None,
+                parent: 0,
+                children: HashMap::new(),
+                recursive: false,
+                cycles: 0,
+                frame_cycles: 0,
+            }],
+            stack: Vec::new(),
+            peaks: HashMap::new(),
+            frames: 0,
+            cycles: 0,
+        }
+    }
+
+    /// Run until the GPU finishes a frame, profiling every instruction
+    pub fn run_frame(&mut self, emulator: &mut Emulator) -> Result<(), VmExit> {
+        let frame = emulator.memory.gpu.frame_count();
+        while emulator.memory.gpu.frame_count() == frame {
+            self.step(emulator)?;
+        }
+        self.end_frame();
+        Ok(())
+    }
+
+    /// Execute an instruction, or the dispatch of an interrupt, and count
+    /// its cycles
+    fn step(&mut self, emulator: &mut Emulator) -> Result<(), VmExit> {
+        let (pc, sp) = (emulator.registers().pc, emulator.registers().sp);
+        let location = Location::of(emulator, pc);
+        let opcode = emulator.peek_memory(pc)?;
+        let cycles = emulator.step_instruction()? as u64;
+        let (new_pc, new_sp) =
+            (emulator.registers().pc, emulator.registers().sp);
+
+        // Calls push the address after them, interrupts the one they
+        // interrupted
+        let mut entered = None;
+        if new_sp == sp.wrapping_sub(2) {
+            let low = emulator.peek_memory(new_sp)?;
+            let high = emulator.peek_memory(new_sp.wrapping_add(1))?;
+            let pushed = u16::from_le_bytes([low, high]);
+            let call = is_call(opcode) && pushed == pc.wrapping_add(3);
+            let rst = is_rst(opcode) && pushed == pc.wrapping_add(1);
+            if pushed == pc && !call && !rst {
+                // The interrupt dispatch counts in the handler
+                self.enter(Location::of(emulator, new_pc), sp);
+                self.count(Location::of(emulator, new_pc), cycles);
+                return Ok(());
+            }
+            if call || rst {
+                entered = Some(Location::of(emulator, new_pc));
+            }
+        }
+        self.count(location, cycles);
+        if let Some(function) = entered {
+            self.enter(function, sp);
+        }
+        while self
+            .stack
+            .last()
+            .is_some_and(|&(_, return_sp)| new_sp >= return_sp)
+        {
+            self.stack.pop();
+        }
+        Ok(())
+    }
+
+    fn count(&mut self, location: Location, cycles: u64) {
+        *self.pcs.entry(location).or_insert(0) += cycles;
+        let current = self.current();
+        let node = &mut self.nodes[current];
+        node.cycles += cycles;
+        node.frame_cycles += cycles;
+        self.cycles += cycles;
+    }
+
+    /// Start running `function`, which returns when SP is back to
+    /// `return_sp`
+    fn enter(&mut self, function: Location, return_sp: u16) {
+        if self.stack.len() >= MAX_DEPTH {
+            return;
+        }
+        let parent = self.current();
+        let node = match self.nodes[parent].children.get(&function) {
+            Some(&node) => node,
+            None => {
+                let node = self.nodes.len();
+                let recursive = self.ancestors(parent).any(|ancestor| {
+                    self.nodes[ancestor].function == Some(function)
+                });
+                self.nodes.push(Node {
+                    function: Some(function),
+                    parent,
+                    children: HashMap::new(),
+                    recursive,
+                    cycles: 0,
+                    frame_cycles: 0,
+                });
+                self.nodes[parent].children.insert(function, node);
+                node
+            }
+        };
+        self.stack.push((node, return_sp));
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().map_or(0, |&(node, _)| node)
+    }
+
+    /// `node` and its ancestors up to the root
+    fn ancestors(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut next = Some(node);
+        std::iter::from_fn(move || {
+            let node = next?;
+            next = match node {
+                0 => None,
+                _ => Some(self.nodes[node].parent),
+            };
+            Some(node)
+        })
+    }
+
+    /// Keep the peak cycles of every function and start counting the next
+    /// frame
+    fn end_frame(&mut self) {
+        let frame: Vec<u64> =
+            self.nodes.iter().map(|node| node.frame_cycles).collect();
+        for (function, cycles) in self.by_function(&frame) {
+            let peak = self.peaks.entry(function).or_insert(0);
+            *peak = (*peak).max(cycles);
+        }
+        for node in &mut self.nodes {
+            node.frame_cycles = 0;
+        }
+        self.frames += 1;
+    }
+
+    /// Cycles of every function and its callees, from the cycles spent in
+    /// each node itself
+    fn by_function(&self, cycles: &[u64]) -> HashMap<Option<Location>, u64> {
+        // Children come after their parent
+        let mut inclusive = cycles.to_vec();
+        for node in (1..self.nodes.len()).rev() {
+            inclusive[self.nodes[node].parent] += inclusive[node];
+        }
+        let mut functions = HashMap::new();
+        for (node, &cycles) in self.nodes.iter().zip(&inclusive) {
+            if !node.recursive {
+                *functions.entry(node.function).or_insert(0) += cycles;
+            }
+        }
+        functions
+    }
+
+    /// Report of the `limit` functions and PCs taking the most cycles
+    pub fn report(&self, symbols: &Symbols, limit: usize) -> String {
+        let percent =
+            |cycles: u64| 100.0 * cycles as f64 / self.cycles.max(1) as f64;
+        let mut lines = vec![format!(
+            "{} cycles in {} frames, {} per frame",
+            self.cycles,
+            self.frames,
+            self.cycles / self.frames.max(1)
+        )];
+
+        let totals: Vec<u64> =
+            self.nodes.iter().map(|node| node.cycles).collect();
+        let inclusive = self.by_function(&totals);
+        let mut own = HashMap::new();
+        for node in &self.nodes {
+            *own.entry(node.function).or_insert(0) += node.cycles;
+        }
+        let mut functions: Vec<_> = inclusive.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        lines.push(String::new());
+        lines.push("  total    self  peak/frame  function".to_string());
+        for (function, cycles) in functions.into_iter().take(limit) {
+            lines.push(format!(
+                "{:6.2}% {:6.2}% {:11}  {}",
+                percent(cycles),
+                percent(own[&function]),
+                self.peaks.get(&function).copied().unwrap_or(0),
+                function_name(function, symbols)
+            ));
+        }
+
+        let mut pcs: Vec<_> = self.pcs.iter().collect();
+        pcs.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        lines.push(String::new());
+        lines.push("   share      cycles  address".to_string());
+        for (&location, &cycles) in pcs.into_iter().take(limit) {
+            let label = match symbols.label(location.bank, location.address) {
+                Some(label) => format!(" <{}>", label),
+                None => String::new(),
+            };
+            lines.push(format!(
+                "{:6.2}% {:11}  {}{}",
+                percent(cycles),
+                cycles,
+                location,
+                label
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Call stacks in the folded format of flame graph tools: the functions
+    /// from the root separated by `;` and the cycles spent in the last one
+    pub fn folded_stacks(&self, symbols: &Symbols) -> String {
+        let mut lines = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.cycles == 0 {
+                continue;
+            }
+            let mut names: Vec<String> = self
+                .ancestors(index)
+                .map(|ancestor| {
+                    function_name(self.nodes[ancestor].function, symbols)
+                })
+                .collect();
+            names.reverse();
+            lines.push(format!("{} {}", names.join(";"), node.cycles));
+        }
+        lines.sort();
+        lines.join("\n")
+    }
+}
+
+/// Name of a function of the call graph, `None` being the root
+fn function_name(function: Option<Location>, symbols: &Symbols) -> String {
+    match function {
+        Some(location) => location.name(symbols),
+        None => ROOT.to_string(),
+    }
+}
+
+fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC)
+}
+
+fn is_rst(opcode: u8) -> bool {
+    opcode & 0xC7 == 0xC7
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_rst_and_interrupts() {
+        let mut emulator = Emulator::with_code(&[
+            (0x08, &[0xC9]), // RET
+            (0x40, &[0xD9]), // RETI
+            (
+                0x100,
+                &[
+                    0xCD, 0x00, 0x02, // CALL 0x0200
+                    0xCF, // RST 0x08
+                    0xFB, // EI
+                    0x76, // HALT
+                    0x18, 0xFD, // JR -3
+                ],
+            ),
+            (
+                0x200,
+                &[
+                    0x00, // NOP
+                    0xC9, // RET
+                ],
+            ),
+        ]);
+        emulator.write_memory(0xFF0F, 0x00).unwrap();
+        emulator.write_memory(0xFFFF, 0x01).unwrap();
+
+        let mut profiler = Profiler::new();
+        for _ in 0..3 {
+            profiler.run_frame(&mut emulator).unwrap();
+        }
+
+        let name = |address| Location::of(&emulator, address).to_string();
+        let stacks = profiler.folded_stacks(&Symbols::new());
+        let stacks: HashMap<&str, u64> = stacks
+            .lines()
+            .map(|line| {
+                let (stack, cycles) = line.rsplit_once(' ').unwrap();
+                (stack, cycles.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(stacks.len(), 4);
+        // NOP and RET
+        assert_eq!(stacks[format!("root;{}", name(0x200)).as_str()], 20);
+        // RET
+        assert_eq!(stacks[format!("root;{}", name(0x08)).as_str()], 16);
+        // Dispatch and RETI of the VBlank interrupts
+        let vblank = stacks[format!("root;{}", name(0x40)).as_str()];
+        assert!(vblank >= 2 * 36 && vblank.is_multiple_of(36));
+        assert_eq!(stacks.values().sum::<u64>(), profiler.cycles);
+    }
+}
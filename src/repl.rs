@@ -0,0 +1,42 @@
+//! Debugger prompt on the standard input
+
+use gbemu::debugger::{Debugger, Flow};
+use gbemu::Emulator;
+
+use std::io::{self, BufRead, Write};
+
+/// Show where execution stopped and run the commands typed until one
+/// resumes execution or quits. An empty line repeats the last command.
+pub fn prompt(debugger: &mut Debugger, emulator: &mut Emulator) -> Flow {
+    if let Some(report) = debugger.take_report() {
+        println!("{}", report);
+    }
+    println!("{}", debugger.location(emulator));
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(gbemu) ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            // Nobody left to type commands
+            Ok(0) | Err(_) => return Flow::Quit,
+            Ok(_) => {}
+        }
+        if line.trim().is_empty() {
+            line = last.clone();
+        } else {
+            last = line.clone();
+        }
+
+        match debugger.execute(emulator, &line) {
+            Ok((Flow::Prompt, output)) => {
+                if !output.is_empty() {
+                    println!("{}", output);
+                }
+            }
+            Ok((flow, _)) => return flow,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! History of save states to play the game backwards
+//!
+//! Only the latest snapshot is kept whole. Each older one is stored as the
+//! XOR of its state with the snapshot taken after it, where runs of zeros,
+//! i.e. unchanged bytes, are run-length encoded.
+
+use crate::emulator::Emulator;
+
+use std::collections::VecDeque;
+
+pub struct Rewind {
+    /// Frames between two snapshots
+    interval: u32,
+
+    /// Bytes the history may take, the oldest snapshots are dropped past it
+    budget: usize,
+
+    /// Frames since the last snapshot
+    frames: u32,
+
+    /// Latest snapshot, whole
+    latest: Option<Vec<u8>>,
+
+    /// Older snapshots, oldest first, as deltas against the next one
+    deltas: VecDeque<Vec<u8>>,
+
+    /// Bytes taken by `latest` and `deltas`
+    size: usize,
+}
+
+impl Rewind {
+    /// Snapshot every `interval` frames, keeping at most `budget` bytes
+    pub fn new(interval: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    /// Call after every frame, takes a snapshot when one is due
+    pub fn record(&mut self, emulator: &Emulator) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let state = emulator.save_state();
+        self.size += state.len();
+        if let Some(previous) = self.latest.take() {
+            let delta = diff(&previous, &state);
+            self.size += delta.len();
+            self.size -= previous.len();
+            self.deltas.push_back(delta);
+        }
+        self.latest = Some(state);
+
+        while self.size > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.size -= delta.len(),
+                None => {
+                    // Not even a single snapshot fits
+                    self.clear();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Go back to the latest snapshot and forget it. Returns false when
+    /// there is nothing left to go back to.
+    pub fn step_back(&mut self, emulator: &mut Emulator) -> bool {
+        let state = match self.latest.take() {
+            Some(state) => state,
+            None => return false,
+        };
+        self.size -= state.len();
+        if let Some(delta) = self.deltas.pop_back() {
+            let previous = undiff(&state, &delta);
+            self.size += previous.len();
+            self.size -= delta.len();
+            self.latest = Some(previous);
+        }
+        self.frames = 0;
+        emulator.load_state(&state).is_ok()
+    }
+
+    /// Forget every snapshot, e.g. after loading another cartridge
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.size = 0;
+        self.frames = 0;
+    }
+
+    /// Number of snapshots kept
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+}
+
+/// Encode `older` against `newer`
+fn diff(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let length = older.len().max(newer.len());
+    let xor = |i: usize| {
+        older.get(i).copied().unwrap_or(0) ^ newer.get(i).copied().unwrap_or(0)
+    };
+
+    let mut delta = Vec::new();
+    write_varint(&mut delta, older.len());
+    let mut i = 0;
+    while i < length {
+        let start = i;
+        while i < length && xor(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut delta, i - start);
+
+        let start = i;
+        while i < length && xor(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut delta, i - start);
+        delta.extend((start..i).map(xor));
+    }
+    delta
+}
+
+/// Decode the `older` state given to `diff`
+fn undiff(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position);
+
+    let mut older = newer.to_vec();
+    older.resize(length.max(newer.len()), 0);
+    let mut i = 0;
+    while position < delta.len() {
+        i += read_varint(delta, &mut position);
+        let literals = read_varint(delta, &mut position);
+        for &byte in &delta[position..position + literals] {
+            older[i] ^= byte;
+            i += 1;
+        }
+        position += literals;
+    }
+    older.truncate(length);
+    older
+}
+
+/// Write `val` 7 bits at a time, the high bit telling whether more follow
+fn write_varint(output: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        output.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    output.push(val as u8);
+}
+
+fn read_varint(input: &[u8], position: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = input[*position];
+        *position += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::counter;
+
+    #[test]
+    fn varints() {
+        for &val in &[0, 1, 0x7F, 0x80, 300, 0x3FFF, 0x4000, usize::MAX] {
+            let mut data = Vec::new();
+            write_varint(&mut data, val);
+            let mut position = 0;
+            assert_eq!(read_varint(&data, &mut position), val);
+            assert_eq!(position, data.len());
+        }
+    }
+
+    #[test]
+    fn deltas() {
+        let newer: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut changed = newer.clone();
+        changed[0] = 0xFF;
+        changed[500..520].fill(0);
+        changed[999] ^= 1;
+        let unrelated: Vec<u8> = (0..1000).map(|i| (i * 7 + 3) as u8).collect();
+        for older in [
+            newer.clone(),
+            changed,
+            unrelated,
+            newer[..600].to_vec(),
+            [&newer[..], &[1, 2, 3]].concat(),
+            Vec::new(),
+        ] {
+            assert_eq!(undiff(&newer, &diff(&older, &newer)), older);
+        }
+
+        // Unchanged bytes take next to nothing
+        let mut older = newer.clone();
+        older[400] ^= 0x55;
+        assert!(diff(&older, &newer).len() < 10);
+    }
+
+    #[test]
+    fn step_back() {
+        let mut emulator = counter();
+        let mut rewind = Rewind::new(2, usize::MAX);
+        let mut states = Vec::new();
+        for frame in 1..=10 {
+            emulator.run_frame().unwrap();
+            rewind.record(&emulator);
+            if frame % 2 == 0 {
+                states.push(emulator.save_state());
+            }
+        }
+        assert_eq!(rewind.len(), 5);
+
+        for state in states.iter().rev() {
+            assert!(rewind.step_back(&mut emulator));
+            assert_eq!(&emulator.save_state(), state);
+        }
+        assert!(rewind.is_empty());
+        assert!(!rewind.step_back(&mut emulator));
+    }
+
+    #[test]
+    fn budget() {
+        let mut emulator = counter();
+        let state_size = emulator.save_state().len();
+        // Room for the latest snapshot and a few deltas
+        let mut rewind = Rewind::new(1, state_size + 1000);
+        let mut states = Vec::new();
+        for _ in 0..20 {
+            emulator.run_frame().unwrap();
+            rewind.record(&emulator);
+            states.push(emulator.save_state());
+        }
+        assert!(rewind.len() > 1 && rewind.len() < 20);
+
+        // The most recent snapshots are kept
+        for state in states.iter().rev().take(rewind.len()) {
+            assert!(rewind.step_back(&mut emulator));
+            assert_eq!(&emulator.save_state(), state);
+        }
+        assert!(!rewind.step_back(&mut emulator));
+
+        let mut rewind = Rewind::new(1, state_size - 1);
+        rewind.record(&emulator);
+        assert!(rewind.is_empty());
+    }
+}
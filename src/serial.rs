@@ -0,0 +1,344 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Clock cycles per bit with the internal 8192 Hz clock
+const NORMAL_BIT_CYCLES: usize = 512;
+
+/// Clock cycles per bit with the CGB internal 262144 Hz clock
+const FAST_BIT_CYCLES: usize = 16;
+
+/// Clock cycles between two polls of the device
+const POLL_CYCLES: usize = NORMAL_BIT_CYCLES;
+
+/// Something plugged in the link port
+pub trait SerialDevice: Send {
+    /// Exchange a byte during a transfer clocked by the Game Boy. `byte` is
+    /// the byte shifted out of SB, the returned value is shifted in.
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    /// Let the device clock a transfer itself, `cycles` clock cycles after
+    /// the previous poll. `byte` is the current content of SB and `ready`
+    /// tells whether the Game Boy waits for an external clock. Returns the
+    /// byte shifted in if the device clocked a full byte.
+    fn poll(&mut self, _cycles: usize, _byte: u8, _ready: bool) -> Option<u8> {
+        None
+    }
+}
+
+/// Prints every byte sent by the Game Boy on stdout
+pub struct StdoutDevice;
+
+impl SerialDevice for StdoutDevice {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        let _ = stdout.flush();
+        0xFF
+    }
+}
+
+/// Writes every byte sent by the Game Boy to a file
+pub struct FileDevice {
+    file: File,
+}
+
+impl FileDevice {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileDevice> {
+        Ok(FileDevice {
+            file: File::create(path)?,
+        })
+    }
+}
+
+impl SerialDevice for FileDevice {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let _ = self.file.write_all(&[byte]);
+        0xFF
+    }
+}
+
+/// Collects every byte sent by the Game Boy. Clones share the same buffer.
+#[derive(Clone, Default)]
+pub struct BufferDevice {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl BufferDevice {
+    pub fn new() -> BufferDevice {
+        Default::default()
+    }
+
+    /// Get the bytes received so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.lock().unwrap().clone()
+    }
+}
+
+impl SerialDevice for BufferDevice {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.buffer.lock().unwrap().push(byte);
+        0xFF
+    }
+}
+
+pub struct Serial {
+    /// Device plugged in the link port, if any
+    device: Option<Box<dyn SerialDevice>>,
+
+    /// SB - Serial transfer data
+    data: u8,
+
+    /// SC - Serial Transfer Control
+    control: u8,
+
+    /// Byte being shifted in during an internal clock transfer
+    incoming: u8,
+
+    /// Number of bits already shifted during the current transfer
+    bits: u8,
+
+    /// Clock cycles since the last shifted bit
+    clock: usize,
+
+    /// Clock cycles since the last device poll
+    poll_clock: usize,
+
+    /// Whether the CGB fast clock bit of SC is available
+    cgb_mode: bool,
+
+    pub interrupt_flags: u8,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            device: None,
+            data: 0,
+            control: 0,
+            incoming: 0xFF,
+            bits: 0,
+            clock: 0,
+            poll_clock: 0,
+            cgb_mode: false,
+            interrupt_flags: 0,
+        }
+    }
+
+    /// Power cycle the serial port, keeping the device plugged in
+    pub fn reset(&mut self) {
+        *self = Serial {
+            device: self.device.take(),
+            cgb_mode: self.cgb_mode,
+            ..Serial::new()
+        };
+    }
+
+    /// Plug `device` in the link port, replacing the previous one
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    /// Unplug the current device from the link port
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    pub fn read_byte(&self, address: usize) -> u8 {
+        match address {
+            // SB - Serial transfer data (R/W)
+            0xFF01 => self.data,
+            // SC - Serial Transfer Control (R/W)
+            _ => self.control | if self.cgb_mode { 0x7C } else { 0x7E },
+        }
+    }
+
+    pub fn write_byte(&mut self, address: usize, val: u8) {
+        match address {
+            // SB - Serial transfer data (R/W)
+            0xFF01 => self.data = val,
+            // SC - Serial Transfer Control (R/W)
+            _ => {
+                self.control = val & if self.cgb_mode { 0x83 } else { 0x81 };
+                self.bits = 0;
+                self.clock = 0;
+                if self.transferring() && self.internal_clock() {
+                    // Without anything plugged in, the line is pulled up
+                    self.incoming = match &mut self.device {
+                        Some(device) => device.transfer(self.data),
+                        None => 0xFF,
+                    };
+                }
+            }
+        }
+    }
+
+    pub fn step(&mut self, cycle_nb: usize) {
+        self.clock += cycle_nb;
+        self.poll_clock += cycle_nb;
+
+        if self.transferring() && self.internal_clock() {
+            let bit_cycles = self.bit_cycles();
+            while self.clock >= bit_cycles && self.transferring() {
+                self.clock -= bit_cycles;
+                let bit = (self.incoming >> (7 - self.bits)) & 0b1;
+                self.data = (self.data << 1) | bit;
+                self.bits += 1;
+                if self.bits == 8 {
+                    self.complete();
+                }
+            }
+        }
+        if self.poll_clock >= POLL_CYCLES {
+            let cycles = std::mem::take(&mut self.poll_clock);
+            let ready = self.transferring() && !self.internal_clock();
+            let incoming = match &mut self.device {
+                Some(device) => device.poll(cycles, self.data, ready),
+                None => None,
+            };
+            if let (Some(incoming), true) = (incoming, ready) {
+                self.data = incoming;
+                self.complete();
+            }
+        }
+    }
+
+    /// Save the port itself, not what is plugged in
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control);
+        writer.write_u8(self.incoming);
+        writer.write_u8(self.bits);
+        writer.write_u64(self.clock as u64);
+        writer.write_u64(self.poll_clock as u64);
+        writer.write_u8(self.interrupt_flags);
+    }
+
+    pub fn load_state(
+        &mut self,
+        reader: &mut StateReader,
+    ) -> Result<(), StateError> {
+        self.data = reader.read_u8()?;
+        self.control = reader.read_u8()?;
+        self.incoming = reader.read_u8()?;
+        self.bits = reader.read_u8()?;
+        if self.bits > 7 {
+            return Err(StateError::Invalid("serial bit count"));
+        }
+        self.clock = reader.read_u64()? as usize;
+        self.poll_clock = reader.read_u64()? as usize;
+        self.interrupt_flags = reader.read_u8()?;
+        Ok(())
+    }
+
+    fn complete(&mut self) {
+        self.control &= 0x7F;
+        self.bits = 0;
+        self.clock = 0;
+        self.interrupt_flags |= 0x08;
+    }
+
+    fn transferring(&self) -> bool {
+        self.control & 0x80 == 0x80
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.control & 0x01 == 0x01
+    }
+
+    fn bit_cycles(&self) -> usize {
+        if self.cgb_mode && self.control & 0x02 == 0x02 {
+            FAST_BIT_CYCLES
+        } else {
+            NORMAL_BIT_CYCLES
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emulator;
+
+    #[test]
+    fn internal_clock_transfer() {
+        let device = BufferDevice::new();
+        let mut serial = Serial::new();
+        serial.connect(Box::new(device.clone()));
+
+        serial.write_byte(0xFF01, 0x42);
+        serial.write_byte(0xFF02, 0x81);
+        assert_eq!(device.contents(), [0x42]);
+
+        // Nothing answers, ones are shifted in one bit at a time
+        serial.step(NORMAL_BIT_CYCLES * 4);
+        assert_eq!(serial.read_byte(0xFF01), 0x2F);
+        assert_eq!(serial.read_byte(0xFF02), 0xFF);
+        assert_eq!(serial.interrupt_flags, 0);
+
+        serial.step(NORMAL_BIT_CYCLES * 4);
+        assert_eq!(serial.read_byte(0xFF01), 0xFF);
+        assert_eq!(serial.read_byte(0xFF02), 0x7F);
+        assert_eq!(serial.interrupt_flags, 0x08);
+    }
+
+    #[test]
+    fn cgb_fast_clock() {
+        let mut serial = Serial::new();
+        serial.set_cgb_mode(true);
+        serial.write_byte(0xFF02, 0x83);
+        serial.step(FAST_BIT_CYCLES * 8);
+        assert_eq!(serial.interrupt_flags, 0x08);
+    }
+
+    #[test]
+    fn external_clock_waits() {
+        let mut serial = Serial::new();
+        serial.write_byte(0xFF02, 0x80);
+        serial.step(NORMAL_BIT_CYCLES * 64);
+        assert_eq!(serial.read_byte(0xFF02), 0xFE);
+        assert_eq!(serial.interrupt_flags, 0);
+    }
+
+    #[test]
+    fn program_output() {
+        let mut emulator = Emulator::with_code(&[
+            (
+                0x100,
+                &[
+                    0x21, 0x00, 0x02, // LD HL, 0x0200
+                    0x2A, // loop: LD A, (HL+)
+                    0xB7, // OR A
+                    0x28, 0xFE, // JR Z, -2
+                    0xE0, 0x01, // LDH (SB), A
+                    0x3E, 0x81, // LD A, 0x81
+                    0xE0, 0x02, // LDH (SC), A
+                    0xF0, 0x02, // wait: LDH A, (SC)
+                    0xE6, 0x80, // AND 0x80
+                    0x20, 0xFA, // JR NZ, wait
+                    0x18, 0xEE, // JR loop
+                ],
+            ),
+            (0x200, b"Hi!\n"),
+        ]);
+        let device = BufferDevice::new();
+        emulator.memory.serial.connect(Box::new(device.clone()));
+        for _ in 0..2 {
+            emulator.run_frame().unwrap();
+        }
+        assert_eq!(device.contents(), b"Hi!\n");
+    }
+}
@@ -0,0 +1,292 @@
+//! Binary format of save states
+//!
+//! A state starts with a header made of the magic bytes, the format version,
+//! the version of the emulator that wrote it and a CRC-32 of the cartridge
+//! ROM, followed by every component in a fixed order. Numbers are little
+//! endian and buffers are prefixed with their length.
+
+use std::fmt;
+
+/// Magic bytes at the start of every save state
+pub const MAGIC: &[u8; 4] = b"GBST";
+
+/// Version of the format, bumped whenever the layout changes
+pub const FORMAT_VERSION: u16 = 2;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// Not a save state
+    BadMagic,
+
+    /// Written with another version of the format
+    UnsupportedVersion(u16),
+
+    /// Written while running another cartridge
+    RomMismatch,
+
+    /// The state ends before every component was read
+    Truncated,
+
+    /// A value does not fit the running machine
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::RomMismatch => {
+                write!(f, "save state made with another cartridge")
+            }
+            StateError::Truncated => write!(f, "truncated save state"),
+            StateError::Invalid(what) => {
+                write!(f, "invalid {} in save state", what)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Serialises components one value after the other
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        Default::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Write a buffer prefixed with its length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+/// Reads back what a `StateWriter` wrote, in the same order
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < length {
+            return Err(StateError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read a buffer written by `write_bytes`
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+
+    /// Read a buffer written by `write_bytes` into `buffer`, which must have
+    /// the same length
+    pub fn read_into(
+        &mut self,
+        buffer: &mut [u8],
+        what: &'static str,
+    ) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(StateError::Invalid(what));
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Write the header of a state for a machine running `rom`
+pub fn write_header(writer: &mut StateWriter, rom: &[u8]) {
+    writer.data.extend_from_slice(MAGIC);
+    writer.write_u16(FORMAT_VERSION);
+    writer.write_bytes(env!("CARGO_PKG_VERSION").as_bytes());
+    writer.write_u32(crc32(rom));
+}
+
+/// Check that the header of a state fits a machine running `rom`
+pub fn read_header(
+    reader: &mut StateReader,
+    rom: &[u8],
+) -> Result<(), StateError> {
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(StateError::BadMagic);
+    }
+    let version = reader.read_u16()?;
+    if version != FORMAT_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    // Only there to tell where a state comes from
+    reader.read_bytes()?;
+    if reader.read_u32()? != crc32(rom) {
+        return Err(StateError::RomMismatch);
+    }
+    Ok(())
+}
+
+/// CRC-32 of `data`, as used by zip and PNG
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1))
+        })
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::Emulator;
+
+    /// An emulator filling C000-CFFF with a counter, with the screen on
+    pub(crate) fn counter() -> Emulator {
+        Emulator::with_program(&[
+            0x21, 0x00, 0xC0, // LD HL, 0xC000
+            0x1C, // loop: INC E
+            0x7B, // LD A, E
+            0x22, // LD (HL+), A
+            0x7C, // LD A, H
+            0xE6, 0xCF, // AND 0xCF
+            0x67, // LD H, A
+            0x18, 0xF7, // JR loop
+        ])
+    }
+
+    fn run(emulator: &mut Emulator, frames: usize) {
+        for _ in 0..frames {
+            emulator.run_frame().unwrap();
+        }
+    }
+
+    #[test]
+    fn values() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789A_BCDE);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_bytes(b"abc");
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u32(), Ok(0x789A_BCDE));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        let mut buffer = [0; 3];
+        assert_eq!(reader.read_into(&mut buffer, "buffer"), Ok(()));
+        assert_eq!(&buffer, b"abc");
+        assert_eq!(reader.read_u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut emulator = counter();
+        run(&mut emulator, 3);
+        let state = emulator.save_state();
+        run(&mut emulator, 2);
+        let later = emulator.save_state();
+        let frame = emulator.framebuffer().to_vec();
+
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.save_state(), state);
+        run(&mut emulator, 2);
+        assert_eq!(emulator.save_state(), later);
+        assert_eq!(emulator.framebuffer(), &frame[..]);
+    }
+
+    #[test]
+    fn rejected() {
+        let mut emulator = counter();
+        run(&mut emulator, 1);
+        let state = emulator.save_state();
+        run(&mut emulator, 1);
+        let current = emulator.save_state();
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = b'X';
+        let mut other_version = state.clone();
+        other_version[4..6].copy_from_slice(&99u16.to_le_bytes());
+        let other_rom = Emulator::with_program(&[0xFF]);
+        for (data, error) in [
+            (&bad_magic[..], StateError::BadMagic),
+            (&other_version[..], StateError::UnsupportedVersion(99)),
+            (&other_rom.save_state()[..], StateError::RomMismatch),
+            (&state[..state.len() - 1], StateError::Truncated),
+        ] {
+            assert_eq!(emulator.load_state(data), Err(error));
+            assert_eq!(emulator.save_state(), current);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! Symbols of RGBDS `.sym` and `.map` files, to show `Main.loop+3` instead
+//! of raw addresses and to find addresses by label
+//!
+//! Addresses of the switchable ROM bank only match symbols of the bank
+//! mapped, others match symbols of any bank.
+
+use crate::disasm::Instruction;
+use crate::emulator::{Emulator, VmExit};
+
+/// A label and where it is
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub bank: usize,
+    pub address: u16,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    /// Sorted by address
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Default::default()
+    }
+
+    /// Parse a `.sym` file, made of `BANK:ADDR Name` lines in hexadecimal,
+    /// `;` starting a comment
+    pub fn parse_sym(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("line {}: invalid symbol", number + 1);
+
+            let mut fields = line.split_whitespace();
+            let (bank, address) = fields
+                .next()
+                .and_then(|location| location.split_once(':'))
+                .ok_or_else(error)?;
+            let name = fields.next().ok_or_else(error)?;
+            symbols.push(Symbol {
+                bank: usize::from_str_radix(bank, 16).map_err(|_| error())?,
+                address: u16::from_str_radix(address, 16)
+                    .map_err(|_| error())?,
+                name: name.to_string(),
+            });
+        }
+        Ok(Symbols::from_vec(symbols))
+    }
+
+    /// Parse a `.map` file, taking the `$ADDR = Name` lines of every
+    /// `REGION bank #N:` block
+    pub fn parse_map(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Vec::new();
+        let mut bank = 0;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let lower = line.to_ascii_lowercase();
+            if let Some((_, rest)) = lower.split_once("bank #") {
+                let digits: String =
+                    rest.chars().take_while(char::is_ascii_digit).collect();
+                bank = digits.parse().map_err(|_| {
+                    format!("line {}: invalid bank", number + 1)
+                })?;
+                continue;
+            }
+            let (address, name) = match line.split_once(" = ") {
+                Some((address, name)) if address.starts_with('$') => {
+                    (address, name)
+                }
+                _ => continue,
+            };
+            let address = u16::from_str_radix(&address[1..], 16)
+                .map_err(|_| format!("line {}: invalid symbol", number + 1))?;
+            symbols.push(Symbol {
+                bank,
+                address,
+                name: name.trim().to_string(),
+            });
+        }
+        Ok(Symbols::from_vec(symbols))
+    }
+
+    fn from_vec(mut symbols: Vec<Symbol>) -> Symbols {
+        symbols.sort_by_key(|symbol| (symbol.address, symbol.bank));
+        Symbols { symbols }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Symbol named `name`
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Symbol at `address` exactly, of `bank` if known
+    pub fn exact(&self, bank: Option<usize>, address: u16) -> Option<&Symbol> {
+        let start = self.symbols.partition_point(|s| s.address < address);
+        self.symbols[start..]
+            .iter()
+            .take_while(|symbol| symbol.address == address)
+            .find(|symbol| bank.is_none_or(|bank| bank == symbol.bank))
+    }
+
+    /// Nearest symbol at or before `address` in the same memory region,
+    /// like `Main.loop+3`
+    pub fn label(&self, bank: Option<usize>, address: u16) -> Option<String> {
+        let end = self.symbols.partition_point(|s| s.address <= address);
+        let symbol = self.symbols[..end]
+            .iter()
+            .rev()
+            .take_while(|symbol| region(symbol.address) == region(address))
+            .find(|symbol| bank.is_none_or(|bank| bank == symbol.bank))?;
+        Some(match address - symbol.address {
+            0 => symbol.name.clone(),
+            offset => format!("{}+{}", symbol.name, offset),
+        })
+    }
+
+    /// Label of `address` with the ROM bank mapped in `emulator`
+    pub fn label_at(
+        &self,
+        emulator: &Emulator,
+        address: u16,
+    ) -> Option<String> {
+        self.label(emulator.memory.rom_bank(address), address)
+    }
+
+    /// Why the emulator stopped, with the label of the instruction that
+    /// caused it
+    pub fn exit_report(&self, emulator: &Emulator, exit: &VmExit) -> String {
+        let label = exit.pc().and_then(|pc| self.label_at(emulator, pc));
+        match label {
+            Some(label) => format!("{} in {}", exit, label),
+            None => exit.to_string(),
+        }
+    }
+
+    /// Text of `instruction` with its address operand replaced by the
+    /// symbol there, `bank` being the ROM bank mapped at 4000-7FFF if known
+    pub fn annotate(
+        &self,
+        instruction: &Instruction,
+        bank: Option<usize>,
+    ) -> String {
+        let operand = match instruction.operand {
+            Some(operand) => operand,
+            None => return instruction.text.clone(),
+        };
+        let bank = match operand {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => bank,
+            _ => None,
+        };
+        match self.exact(bank, operand) {
+            Some(symbol) => instruction
+                .text
+                .replace(&format!("${:04X}", operand), &symbol.name),
+            None => instruction.text.clone(),
+        }
+    }
+}
+
+/// Memory region of `address`, symbols of a region don't label another
+fn region(address: u16) -> u8 {
+    match address {
+        0x0000..=0x3FFF => 0,
+        0x4000..=0x7FFF => 1,
+        0x8000..=0x9FFF => 2,
+        0xA000..=0xBFFF => 3,
+        0xC000..=0xCFFF => 4,
+        0xD000..=0xDFFF => 5,
+        0xE000..=0xFDFF => 6,
+        0xFE00..=0xFEFF => 7,
+        0xFF00..=0xFF7F => 8,
+        _ => 9,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::decode_bytes;
+
+    const SYM: &str = "; File generated by rgblink
+00:0150 Main
+00:0158 Main.loop
+01:4000 Banked
+02:4000 OtherBank ; trailing comment
+00:c000 wCounter
+
+00:ff80 hStack
+";
+
+    const MAP: &str = "SUMMARY:
+\tROM0: 344 bytes used / 16040 free
+
+ROM0 bank #0:
+\tSECTION: $0150-$015f ($0010 bytes) [\"Main\"]
+\t         $0150 = Main
+\t         $0158 = Main.loop
+\tEMPTY: $0160-$3fff ($3ea0 bytes)
+
+ROMX bank #1:
+\tSECTION: $4000-$4001 ($0002 bytes) [\"Banked\"]
+\t         $4000 = Banked
+
+ROMX bank #2:
+\tSECTION: $4000-$4001 ($0002 bytes) [\"Other\"]
+\t         $4000 = OtherBank
+
+WRAM0 bank #0:
+\tSECTION: $c000-$c000 ($0001 bytes) [\"Variables\"]
+\t         $c000 = wCounter
+
+HRAM bank #0:
+\tSECTION: $ff80-$ff80 ($0001 bytes) [\"Stack\"]
+\t         $ff80 = hStack
+";
+
+    fn symbol(bank: usize, address: u16, name: &str) -> Symbol {
+        Symbol {
+            bank,
+            address,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let expected = [
+            symbol(0, 0x0150, "Main"),
+            symbol(0, 0x0158, "Main.loop"),
+            symbol(1, 0x4000, "Banked"),
+            symbol(2, 0x4000, "OtherBank"),
+            symbol(0, 0xC000, "wCounter"),
+            symbol(0, 0xFF80, "hStack"),
+        ];
+        assert_eq!(Symbols::parse_sym(SYM).unwrap().symbols, expected);
+        assert_eq!(Symbols::parse_map(MAP).unwrap().symbols, expected);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for bad in ["0150 Main", "00:0150", "00:zz Main", "g:0150 Main"] {
+            assert_eq!(
+                Symbols::parse_sym(&format!("00:0100 Start\n{}", bad))
+                    .unwrap_err(),
+                "line 2: invalid symbol",
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(
+            Symbols::parse_map("ROMX bank #x:").unwrap_err(),
+            "line 1: invalid bank"
+        );
+        assert_eq!(
+            Symbols::parse_map("ROM0 bank #0:\n\t$zz = Main").unwrap_err(),
+            "line 2: invalid symbol"
+        );
+    }
+
+    #[test]
+    fn lookups() {
+        let symbols = Symbols::parse_sym(SYM).unwrap();
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(
+            symbols.find("Main.loop"),
+            Some(&symbol(0, 0x158, "Main.loop"))
+        );
+        assert_eq!(symbols.find("Missing"), None);
+
+        assert_eq!(symbols.exact(None, 0x4000).unwrap().name, "Banked");
+        assert_eq!(symbols.exact(Some(2), 0x4000).unwrap().name, "OtherBank");
+        assert_eq!(symbols.exact(Some(3), 0x4000), None);
+        assert_eq!(symbols.exact(None, 0x0151), None);
+
+        let label = |bank, address| symbols.label(bank, address);
+        assert_eq!(label(Some(0), 0x0150).as_deref(), Some("Main"));
+        assert_eq!(label(Some(0), 0x015B).as_deref(), Some("Main.loop+3"));
+        assert_eq!(label(Some(0), 0x3FFF).as_deref(), Some("Main.loop+16039"));
+        assert_eq!(label(Some(0), 0x0100), None);
+        assert_eq!(label(Some(2), 0x4002).as_deref(), Some("OtherBank+2"));
+        assert_eq!(label(Some(3), 0x4002), None);
+        // Symbols of another region don't label an address
+        assert_eq!(label(None, 0x8000), None);
+        assert_eq!(label(None, 0xC010).as_deref(), Some("wCounter+16"));
+        assert_eq!(label(None, 0xD000), None);
+        assert_eq!(label(None, 0xFFFF).as_deref(), Some("hStack+127"));
+    }
+
+    #[test]
+    fn annotate() {
+        let symbols = Symbols::parse_sym(SYM).unwrap();
+        let annotate = |code: &[u8], bank| {
+            symbols.annotate(&decode_bytes(code, 0x0150), bank)
+        };
+        assert_eq!(annotate(&[0xC3, 0x58, 0x01], None), "jp Main.loop");
+        assert_eq!(annotate(&[0xCD, 0x00, 0x40], Some(2)), "call OtherBank");
+        assert_eq!(annotate(&[0xCD, 0x00, 0x40], Some(3)), "call $4000");
+        assert_eq!(annotate(&[0xFA, 0x00, 0xC0], None), "ld a, [wCounter]");
+        assert_eq!(annotate(&[0x00], None), "nop");
+    }
+}
@@ -0,0 +1,127 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub struct Timer {
+    /// Internal 16-bit counter, DIV is its upper byte
+    counter: u16,
+
+    /// TIMA - Timer counter
+    tima: u8,
+
+    /// TMA - Timer Modulo
+    tma: u8,
+
+    /// TAC - Timer Control
+    tac: u8,
+
+    pub interrupt_flags: u8,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            interrupt_flags: 0,
+        }
+    }
+
+    pub fn read_byte(&self, address: usize) -> u8 {
+        match address {
+            // DIV - Divider Register (R/W)
+            0xFF04 => (self.counter >> 8) as u8,
+            // TIMA - Timer counter (R/W)
+            0xFF05 => self.tima,
+            // TMA - Timer Modulo (R/W)
+            0xFF06 => self.tma,
+            // TAC - Timer Control (R/W)
+            _ => self.tac | 0xF8,
+        }
+    }
+
+    pub fn write_byte(&mut self, address: usize, val: u8) {
+        match address {
+            0xFF04 => {
+                // DIV - Divider Register (R/W)
+                // Resetting the counter can make the selected bit fall
+                let old = self.timer_bit();
+                self.counter = 0;
+                self.tick_on_falling_edge(old);
+            }
+            0xFF05 => self.tima = val,
+            0xFF06 => self.tma = val,
+            _ => {
+                // TAC - Timer Control (R/W)
+                let old = self.timer_bit();
+                self.tac = val & 0x07;
+                self.tick_on_falling_edge(old);
+            }
+        }
+    }
+
+    pub fn step(&mut self, cycle_nb: usize) {
+        // The counter is incremented once per machine cycle
+        for _ in 0..cycle_nb / 4 {
+            let old = self.timer_bit();
+            self.counter = self.counter.wrapping_add(4);
+            self.tick_on_falling_edge(old);
+        }
+    }
+
+    /// Set the internal counter, e.g. to the value left by the boot ROM
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_u8(self.tima);
+        writer.write_u8(self.tma);
+        writer.write_u8(self.tac);
+        writer.write_u8(self.interrupt_flags);
+    }
+
+    pub fn load_state(
+        &mut self,
+        reader: &mut StateReader,
+    ) -> Result<(), StateError> {
+        self.counter = reader.read_u16()?;
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tac = reader.read_u8()? & 0x07;
+        self.interrupt_flags = reader.read_u8()?;
+        Ok(())
+    }
+
+    /// State of the counter bit selected by TAC, ANDed with the enable bit
+    fn timer_bit(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 == 0x04 && (self.counter >> bit) & 0b1 == 0b1
+    }
+
+    fn tick_on_falling_edge(&mut self, old: bool) {
+        if !old || self.timer_bit() {
+            return;
+        }
+
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        if overflow {
+            self.tima = self.tma;
+            self.interrupt_flags |= 0x04;
+        } else {
+            self.tima = tima;
+        }
+    }
+}
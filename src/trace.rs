@@ -0,0 +1,260 @@
+//! Execution traces in the format of Gameboy Doctor, one line per
+//! instruction with the registers before it runs and the 4 bytes at PC:
+//!
+//! `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+//!
+//! With symbols loaded, lines end with the label of PC as a `;` comment,
+//! which is ignored when comparing traces.
+//!
+//! Gameboy Doctor logs are made with LY always reading 0x90, so it does
+//! while tracing for the traces to compare.
+
+use crate::emulator::{Emulator, VmExit};
+use crate::symbols::Symbols;
+
+use std::io::{self, Write};
+
+/// What LY reads as in Gameboy Doctor logs, the first line of VBlank
+pub const DOCTOR_LY: u8 = 0x90;
+
+/// Which instructions are traced
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// First and last PC traced, `None` for any
+    pub range: Option<(u16, u16)>,
+
+    /// ROM bank that must be mapped at PC, `None` for any
+    pub bank: Option<usize>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, emulator: &Emulator) -> bool {
+        let pc = emulator.registers().pc;
+        self.range
+            .is_none_or(|(start, end)| (start..=end).contains(&pc))
+            && self
+                .bank
+                .is_none_or(|bank| emulator.memory.rom_bank(pc) == Some(bank))
+    }
+}
+
+/// Writes the trace of the instructions passing a filter
+pub struct Tracer<W: Write> {
+    output: W,
+    filter: TraceFilter,
+
+    /// First write error, tracing stops on it
+    error: Option<io::Error>,
+
+    symbols: Symbols,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(output: W, filter: TraceFilter) -> Tracer<W> {
+        Tracer {
+            output,
+            filter,
+            error: None,
+            symbols: Symbols::new(),
+        }
+    }
+
+    /// Label PCs with `symbols`
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Trace the instruction at PC, which is about to run
+    pub fn trace(&mut self, emulator: &mut Emulator) -> io::Result<()> {
+        if !self.filter.matches(emulator) {
+            return Ok(());
+        }
+        let pc = emulator.registers().pc;
+        match self.symbols.label_at(emulator, pc) {
+            Some(label) => {
+                writeln!(self.output, "{} ; {}", line(emulator), label)
+            }
+            None => writeln!(self.output, "{}", line(emulator)),
+        }
+    }
+
+    /// Run until the GPU finishes a frame, tracing every instruction but not
+    /// interrupt dispatches or the wait after a HALT, with LY reading as
+    /// `DOCTOR_LY`. Write errors are kept for `flush`.
+    pub fn run_frame(&mut self, emulator: &mut Emulator) -> Result<(), VmExit> {
+        emulator.memory.gpu.set_fixed_ly(Some(DOCTOR_LY));
+        let frame = emulator.memory.gpu.frame_count();
+        while emulator.memory.gpu.frame_count() == frame {
+            if self.error.is_none() && emulator.executes_opcode() {
+                self.error = self.trace(emulator).err();
+            }
+            emulator.step_instruction()?;
+        }
+        Ok(())
+    }
+
+    /// Flush the output, failing with the first write error if any
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.output.flush(),
+        }
+    }
+}
+
+/// Trace line of the instruction at PC
+pub fn line(emulator: &mut Emulator) -> String {
+    let regs = *emulator.registers();
+    let pc_mem: Vec<String> = (0..4)
+        .map(|i| {
+            let val = emulator
+                .peek_memory(regs.pc.wrapping_add(i))
+                .unwrap_or(0xFF);
+            format!("{:02X}", val)
+        })
+        .collect();
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} \
+         L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        regs.a,
+        regs.f,
+        regs.b,
+        regs.c,
+        regs.d,
+        regs.e,
+        regs.h,
+        regs.l,
+        regs.sp,
+        regs.pc,
+        pc_mem.join(",")
+    )
+}
+
+/// First line where two traces differ
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Line number, from 1
+    pub line: usize,
+
+    /// Both lines, `None` past the end of a trace
+    pub ours: Option<String>,
+    pub reference: Option<String>,
+
+    /// Fields whose values differ, like `F`, empty when a trace ended
+    pub fields: Vec<String>,
+}
+
+/// Compare a trace with a reference one, line by line
+pub fn diff<A, B>(ours: A, reference: B) -> Option<Mismatch>
+where
+    A: IntoIterator<Item = String>,
+    B: IntoIterator<Item = String>,
+{
+    let mut ours = ours.into_iter();
+    let mut reference = reference.into_iter();
+    let mut line = 0;
+    loop {
+        line += 1;
+        match (ours.next(), reference.next()) {
+            (None, None) => return None,
+            (Some(a), Some(b)) if strip(&a) == strip(&b) => {}
+            (ours, reference) => {
+                let fields = match (&ours, &reference) {
+                    (Some(a), Some(b)) => differing_fields(strip(a), strip(b)),
+                    _ => Vec::new(),
+                };
+                return Some(Mismatch {
+                    line,
+                    ours,
+                    reference,
+                    fields,
+                });
+            }
+        }
+    }
+}
+
+/// Line without its comment
+fn strip(line: &str) -> &str {
+    line.split(';').next().unwrap_or("").trim()
+}
+
+/// Names of the `NAME:VALUE` fields that differ between two lines
+fn differing_fields(a: &str, b: &str) -> Vec<String> {
+    let fields = |line: &str| -> Vec<(String, String)> {
+        line.split_whitespace()
+            .map(|field| match field.split_once(':') {
+                Some((name, val)) => (name.to_string(), val.to_string()),
+                None => (field.to_string(), String::new()),
+            })
+            .collect()
+    };
+    let (a, b) = (fields(a), fields(b));
+    let mut names: Vec<String> = a
+        .iter()
+        .filter(|field| !b.contains(field))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for (name, _) in b.iter().filter(|field| !a.contains(field)) {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_only() {
+        let mut emulator = Emulator::with_code(&[
+            (0x40, &[0xD9]), // RETI
+            (
+                0x100,
+                &[
+                    0xFB, // EI
+                    0x76, // HALT
+                    0x00, // NOP
+                    0x18, 0xFE, // JR -2
+                ],
+            ),
+        ]);
+        emulator.write_memory(0xFF0F, 0x00).unwrap();
+        emulator.write_memory(0xFFFF, 0x01).unwrap();
+
+        let mut output = Vec::new();
+        let mut tracer = Tracer::new(&mut output, TraceFilter::default());
+        for _ in 0..2 {
+            tracer.run_frame(&mut emulator).unwrap();
+        }
+        tracer.flush().unwrap();
+        drop(tracer);
+
+        let text = String::from_utf8(output).unwrap();
+        let pcs: Vec<&str> = text
+            .lines()
+            .take(5)
+            .map(|line| &line[line.find("PC:").unwrap() + 3..][..4])
+            .collect();
+        assert_eq!(pcs, ["0100", "0101", "0040", "0102", "0103"]);
+    }
+    #[test]
+    fn doctor_ly() {
+        let mut emulator = Emulator::with_program(&[
+            0xF0, 0x44, // LDH A, (LY)
+            0x18, 0xFE, // JR -2
+        ]);
+        let mut output = Vec::new();
+        let mut tracer = Tracer::new(&mut output, TraceFilter::default());
+        tracer.run_frame(&mut emulator).unwrap();
+        tracer.flush().unwrap();
+        drop(tracer);
+
+        let text = String::from_utf8(output).unwrap();
+        let second = text.lines().nth(1).unwrap();
+        assert!(second.starts_with("A:90 "), "{}", second);
+        assert!(second.contains("PC:0102"), "{}", second);
+    }
+}
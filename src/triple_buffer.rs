@@ -0,0 +1,143 @@
+//! Lock-free triple buffer, to hand frames from the emulator thread to the
+//! frontend without either side waiting for the other
+//!
+//! The writer and the reader each own one of the three buffers and the third
+//! one sits in the middle. Publishing swaps the writer buffer with the middle
+//! one, reading swaps the middle one with the reader buffer when something
+//! new was published since.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+/// Set in `Shared::middle` when the middle buffer holds a value not read yet
+const FRESH: u8 = 0x04;
+
+/// Bits of `Shared::middle` holding the index of the middle buffer
+const INDEX: u8 = 0x03;
+
+struct Shared<T> {
+    buffers: [UnsafeCell<T>; 3],
+
+    /// Index of the middle buffer, with `FRESH`
+    middle: AtomicU8,
+}
+
+// SAFETY: the buffers are only ever accessed through `Writer::buffer` and
+// `Reader::read`, each on the buffer whose index its side holds. The three
+// indices are always distinct since sides only exchange theirs with the
+// middle one in a single atomic swap, so no buffer is shared between threads
+// and only `T: Send` is needed to hand them over.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// Writing end of a triple buffer
+pub struct Writer<T> {
+    shared: Arc<Shared<T>>,
+    index: u8,
+}
+
+/// Reading end of a triple buffer
+pub struct Reader<T> {
+    shared: Arc<Shared<T>>,
+    index: u8,
+}
+
+/// Create a triple buffer whose three buffers start as `initial`
+pub fn triple_buffer<T: Clone>(initial: T) -> (Writer<T>, Reader<T>) {
+    let shared = Arc::new(Shared {
+        buffers: [
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial),
+        ],
+        middle: AtomicU8::new(1),
+    });
+    let writer = Writer {
+        shared: shared.clone(),
+        index: 0,
+    };
+    let reader = Reader { shared, index: 2 };
+    (writer, reader)
+}
+
+impl<T> Writer<T> {
+    /// Buffer to prepare the next value in
+    pub fn buffer(&mut self) -> &mut T {
+        // SAFETY: the reader never holds the writer index, see `Shared`, and
+        // the borrow of `self` keeps this the only reference to the buffer
+        unsafe { &mut *self.shared.buffers[self.index as usize].get() }
+    }
+
+    /// Make the prepared value the one the reader gets next
+    pub fn publish(&mut self) {
+        let middle = self
+            .shared
+            .middle
+            .swap(self.index | FRESH, Ordering::AcqRel);
+        self.index = middle & INDEX;
+    }
+}
+
+impl<T> Reader<T> {
+    /// Latest published value
+    pub fn read(&mut self) -> &T {
+        if self.shared.middle.load(Ordering::Relaxed) & FRESH == FRESH {
+            let middle = self.shared.middle.swap(self.index, Ordering::AcqRel);
+            self.index = middle & INDEX;
+        }
+        // SAFETY: the writer never holds the reader index, see `Shared`, and
+        // the buffer was fully written before the release half of the swap
+        // in `publish` made it the middle one, which the acquire half of our
+        // swap synchronises with
+        unsafe { &*self.shared.buffers[self.index as usize].get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn latest_published() {
+        let (mut writer, mut reader) = triple_buffer(0);
+        assert_eq!(*reader.read(), 0);
+
+        *writer.buffer() = 1;
+        assert_eq!(*reader.read(), 0);
+        writer.publish();
+        assert_eq!(*reader.read(), 1);
+
+        // Frames published in between are skipped
+        for value in 2..=4 {
+            *writer.buffer() = value;
+            writer.publish();
+        }
+        assert_eq!(*reader.read(), 4);
+        assert_eq!(*reader.read(), 4);
+    }
+
+    #[test]
+    fn never_torn() {
+        const FRAMES: u32 = 20_000;
+        let (mut writer, mut reader) = triple_buffer([0; 256]);
+        let writing = thread::spawn(move || {
+            for frame in 1..=FRAMES {
+                for value in writer.buffer().iter_mut() {
+                    *value = frame;
+                }
+                writer.publish();
+            }
+        });
+
+        let mut last = 0;
+        while last < FRAMES {
+            let frame = reader.read();
+            assert!(frame.iter().all(|&value| value == frame[0]));
+            assert!(frame[0] >= last);
+            last = frame[0];
+        }
+        writing.join().unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Images of what the game uploaded to video memory: the tiles, the
+//! background maps and the objects, to inspect them off screen
+//!
+//! Only the first VRAM bank is emulated, so CGB games show their bank 0.
+
+use crate::gpu::Gpu;
+use crate::image;
+
+use std::io;
+use std::path::Path;
+
+/// Number of tiles in a VRAM bank
+pub const TILES: usize = 384;
+
+/// Tiles per row of the tile sheet
+const TILES_PER_ROW: usize = 16;
+
+/// Addresses of the two background maps
+pub const MAP_ADDRESSES: [u16; 2] = [0x9800, 0x9C00];
+
+/// Number of objects in OAM
+pub const OBJECTS: usize = 40;
+
+/// Objects per row of the object sheet
+const OBJECTS_PER_ROW: usize = 8;
+
+/// Colour of the viewport outline in background maps
+const VIEWPORT_COLOUR: [u8; 4] = [0xFF, 0x00, 0x00, 0xFF];
+
+/// An RGBA image
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+impl Image {
+    /// A transparent image
+    fn new(width: u32, height: u32) -> Image {
+        Image {
+            width,
+            height,
+            rgba: vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, colour: [u8; 4]) {
+        let offset = (y * self.width as usize + x) * 4;
+        self.rgba[offset..offset + 4].copy_from_slice(&colour);
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        image::save_png(path, self.width, self.height, &self.rgba)
+    }
+}
+
+/// The 384 tiles, 16 per row, with colour numbers shown as the shades of
+/// the same number
+pub fn tiles(gpu: &Gpu) -> Image {
+    let rows = TILES / TILES_PER_ROW;
+    let mut image = Image::new(TILES_PER_ROW as u32 * 8, rows as u32 * 8);
+    for tile in 0..TILES {
+        let (x, y) = (tile % TILES_PER_ROW * 8, tile / TILES_PER_ROW * 8);
+        for row in 0..8 {
+            let colours = tile_row(gpu.vram(), tile * 16, row);
+            for (column, &colour) in colours.iter().enumerate() {
+                let shade = gpu.palette()[colour as usize];
+                image.set(x + column, y + row, shade);
+            }
+        }
+    }
+    image
+}
+
+/// Background map `map` of `MAP_ADDRESSES` as 32x32 tiles, addressed and
+/// coloured like the background, with the viewport outlined
+pub fn tile_map(gpu: &Gpu, map: usize) -> Image {
+    let vram = gpu.vram();
+    let bg_palette = gpu.dmg_palettes()[0];
+    let map_offset = (MAP_ADDRESSES[map] - 0x8000) as usize;
+    let mut image = Image::new(256, 256);
+    for index in 0..32 * 32 {
+        let tile = tile_address(gpu, vram[map_offset + index]);
+        let (x, y) = (index % 32 * 8, index / 32 * 8);
+        for row in 0..8 {
+            let colours = tile_row(vram, tile, row);
+            for (column, &colour) in colours.iter().enumerate() {
+                let shade = shade(bg_palette, colour);
+                image.set(x + column, y + row, gpu.palette()[shade]);
+            }
+        }
+    }
+
+    // The viewport wraps around the map
+    let (scroll_x, scroll_y) = gpu.scroll();
+    let (left, top) = (scroll_x as usize, scroll_y as usize);
+    for i in 0..160 {
+        image.set((left + i) % 256, top, VIEWPORT_COLOUR);
+        image.set((left + i) % 256, (top + 143) % 256, VIEWPORT_COLOUR);
+    }
+    for i in 0..144 {
+        image.set(left, (top + i) % 256, VIEWPORT_COLOUR);
+        image.set((left + 159) % 256, (top + i) % 256, VIEWPORT_COLOUR);
+    }
+    image
+}
+
+/// The 40 objects, 8 per row in cells of 8x16 pixels, flipped and coloured
+/// like on screen with colour 0 transparent
+pub fn objects(gpu: &Gpu) -> Image {
+    let vram = gpu.vram();
+    let oam = gpu.oam();
+    let tall = gpu.lcd_control() & 0x04 == 0x04;
+    let height = if tall { 16 } else { 8 };
+    let rows = OBJECTS / OBJECTS_PER_ROW;
+    let mut image = Image::new(OBJECTS_PER_ROW as u32 * 8, rows as u32 * 16);
+    for object in 0..OBJECTS {
+        let attributes = &oam[object * 4..object * 4 + 4];
+        let (tile, flags) = (attributes[2], attributes[3]);
+        // 8x16 objects ignore bit 0 of the tile number
+        let tile = if tall { tile & 0xFE } else { tile } as usize;
+        let palette = gpu.dmg_palettes()[1 + (flags >> 4 & 1) as usize];
+        let (x, y) =
+            (object % OBJECTS_PER_ROW * 8, object / OBJECTS_PER_ROW * 16);
+        for row in 0..height {
+            let source = if flags & 0x40 == 0x40 {
+                height - 1 - row
+            } else {
+                row
+            };
+            let colours = tile_row(vram, tile * 16, source);
+            for (column, &colour) in colours.iter().enumerate() {
+                if colour == 0 {
+                    continue;
+                }
+                let column = if flags & 0x20 == 0x20 {
+                    7 - column
+                } else {
+                    column
+                };
+                let shade = shade(palette, colour);
+                image.set(x + column, y + row, gpu.palette()[shade]);
+            }
+        }
+    }
+    image
+}
+
+/// Table of the 40 objects: their screen position, tile, attributes and
+/// what they mean
+pub fn oam_table(gpu: &Gpu) -> String {
+    let mut lines = vec![" #     X     Y  tile  attr".to_string()];
+    for (object, attributes) in gpu.oam().chunks(4).enumerate() {
+        let (y, x) = (attributes[0], attributes[1]);
+        let (tile, flags) = (attributes[2], attributes[3]);
+        let mut notes =
+            vec![if flags & 0x10 == 0x10 { "OBP1" } else { "OBP0" }];
+        if flags & 0x20 == 0x20 {
+            notes.push("x-flip");
+        }
+        if flags & 0x40 == 0x40 {
+            notes.push("y-flip");
+        }
+        if flags & 0x80 == 0x80 {
+            notes.push("behind");
+        }
+        if y == 0 || y >= 160 || x == 0 || x >= 168 {
+            notes.push("hidden");
+        }
+        lines.push(format!(
+            "{:02} {:5} {:5}    {:02X}    {:02X}  {}",
+            object,
+            x as i16 - 8,
+            y as i16 - 16,
+            tile,
+            flags,
+            notes.join(" ")
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Write `tiles.png`, `map-9800.png`, `map-9C00.png`, `oam.png` and
+/// `oam.txt` in `dir`
+pub fn dump(gpu: &Gpu, dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    tiles(gpu).save_png(dir.join("tiles.png"))?;
+    for (map, address) in MAP_ADDRESSES.iter().enumerate() {
+        let name = format!("map-{:04X}.png", address);
+        tile_map(gpu, map).save_png(dir.join(name))?;
+    }
+    objects(gpu).save_png(dir.join("oam.png"))?;
+    std::fs::write(dir.join("oam.txt"), oam_table(gpu) + "\n")
+}
+
+/// Offset in VRAM of a background tile, LCDC bit 4 selecting unsigned
+/// numbers from 0x8000 instead of signed ones from 0x9000
+fn tile_address(gpu: &Gpu, tile: u8) -> usize {
+    if gpu.lcd_control() & 0x10 == 0x10 {
+        tile as usize * 16
+    } else {
+        (0x1000 + tile as i8 as isize * 16) as usize
+    }
+}
+
+/// Colour numbers of the 8 pixels of `row` of the tile at `offset`
+fn tile_row(vram: &[u8], offset: usize, row: usize) -> [u8; 8] {
+    let low = vram[offset + row * 2];
+    let high = vram[offset + row * 2 + 1];
+    let mut colours = [0; 8];
+    for (i, colour) in colours.iter_mut().enumerate() {
+        let bit = 7 - i;
+        *colour = (high >> bit & 1) << 1 | low >> bit & 1;
+    }
+    colours
+}
+
+/// Shade of `colour` through a BGP or OBP palette register
+fn shade(palette: u8, colour: u8) -> usize {
+    (palette >> (colour * 2) & 0b11) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(gpu: &mut Gpu, address: usize, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            gpu.write_byte(address + i, byte).unwrap();
+        }
+    }
+
+    fn pixel(image: &Image, x: usize, y: usize) -> [u8; 4] {
+        let offset = (y * image.width as usize + x) * 4;
+        let mut colour = [0; 4];
+        colour.copy_from_slice(&image.rgba[offset..offset + 4]);
+        colour
+    }
+
+    #[test]
+    fn tile_rows() {
+        let vram = [0x3C, 0x7E, 0x42, 0x42, 0xFF, 0x00, 0x00, 0xFF];
+        assert_eq!(tile_row(&vram, 0, 0), [0, 2, 3, 3, 3, 3, 2, 0]);
+        assert_eq!(tile_row(&vram, 0, 1), [0, 3, 0, 0, 0, 0, 3, 0]);
+        assert_eq!(tile_row(&vram, 4, 0), [1; 8]);
+        assert_eq!(tile_row(&vram, 4, 1), [2; 8]);
+    }
+
+    #[test]
+    fn tile_addressing() {
+        let mut gpu = Gpu::new();
+        gpu.write_byte(0xFF40, 0x91).unwrap();
+        assert_eq!(tile_address(&gpu, 0x00), 0x0000);
+        assert_eq!(tile_address(&gpu, 0x7F), 0x07F0);
+        assert_eq!(tile_address(&gpu, 0x80), 0x0800);
+        assert_eq!(tile_address(&gpu, 0xFF), 0x0FF0);
+
+        gpu.write_byte(0xFF40, 0x81).unwrap();
+        assert_eq!(tile_address(&gpu, 0x00), 0x1000);
+        assert_eq!(tile_address(&gpu, 0x7F), 0x17F0);
+        assert_eq!(tile_address(&gpu, 0x80), 0x0800);
+        assert_eq!(tile_address(&gpu, 0xFF), 0x0FF0);
+    }
+
+    #[test]
+    fn object_flips() {
+        let mut gpu = Gpu::new();
+        // Tile 1 has a single pixel of colour 1 in its top left corner
+        write(&mut gpu, 0x8010, &[0x80]);
+        write(&mut gpu, 0xFF48, &[0xE4]);
+        for (object, flags) in [0x00, 0x20, 0x40, 0x60].iter().enumerate() {
+            write(&mut gpu, 0xFE00 + object * 4, &[16, 8, 0x01, *flags]);
+        }
+
+        let image = objects(&gpu);
+        let shade = gpu.palette()[1];
+        let lit =
+            |object: usize, x, y| pixel(&image, object * 8 + x, y) == shade;
+        assert!(lit(0, 0, 0));
+        assert!(lit(1, 7, 0));
+        assert!(lit(2, 0, 7));
+        assert!(lit(3, 7, 7));
+        // Colour 0 is transparent
+        assert_eq!(pixel(&image, 1, 0), [0; 4]);
+        assert_eq!(image.rgba.iter().filter(|&&byte| byte != 0).count(), 16);
+
+        // 8x16 objects take tiles 0 and 1 and flip over both
+        write(&mut gpu, 0xFF40, &[0x84]);
+        let image = objects(&gpu);
+        assert_eq!(pixel(&image, 0, 8), shade);
+        assert_eq!(pixel(&image, 0, 0), [0; 4]);
+        assert_eq!(pixel(&image, 8 * 2, 7), shade);
+        assert_eq!(pixel(&image, 8 * 2, 15), [0; 4]);
+    }
+
+    #[test]
+    fn oam_lines() {
+        let mut gpu = Gpu::new();
+        write(&mut gpu, 0xFE00, &[16, 8, 0x01, 0x60]);
+        write(&mut gpu, 0xFE04, &[20, 170, 0x8F, 0x90]);
+        let table = oam_table(&gpu);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 1 + OBJECTS);
+        assert_eq!(lines[0], " #     X     Y  tile  attr");
+        assert_eq!(lines[1], "00     0     0    01    60  OBP0 x-flip y-flip");
+        assert_eq!(lines[2], "01   162     4    8F    90  OBP1 behind hidden");
+        assert_eq!(lines[3], "02    -8   -16    00    00  OBP0 hidden");
+    }
+}
@@ -0,0 +1,105 @@
+//! Watchpoints on memory accesses, checked by the MMU for the debugger
+
+use crate::emulator::Access;
+
+use std::fmt;
+
+/// Accesses a watchpoint stops on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+
+    /// Writes changing the value
+    Change,
+
+    /// Reads and writes
+    Access,
+}
+
+/// Stops on accesses to a range of addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// First and last address watched
+    pub start: u16,
+    pub end: u16,
+
+    pub kind: WatchKind,
+
+    /// Only stop when this value is read or written
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn contains(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+
+    /// Whether an access at `address` reading or writing `value`, which
+    /// `changed` the stored value for writes, stops on this watchpoint
+    pub fn matches(
+        &self,
+        address: u16,
+        access: Access,
+        value: u8,
+        changed: bool,
+    ) -> bool {
+        let kind = match (self.kind, access) {
+            (WatchKind::Read, Access::Read) => true,
+            (WatchKind::Write, Access::Write) => true,
+            (WatchKind::Change, Access::Write) => changed,
+            (WatchKind::Access, _) => true,
+            _ => false,
+        };
+        kind && self.contains(address) && self.value.is_none_or(|v| v == value)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04X}", self.end)?;
+        }
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Change => "change",
+            WatchKind::Access => "access",
+        };
+        write!(f, " {}", kind)?;
+        if let Some(value) = self.value {
+            write!(f, " ={:02X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Access that stopped on a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub address: u16,
+    pub access: Access,
+
+    /// Value before and after the access, the same for reads
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.access {
+            Access::Read => write!(
+                f,
+                "Read {:02X} from {:04X}, watchpoint {}",
+                self.new, self.address, self.watchpoint
+            ),
+            Access::Write => write!(
+                f,
+                "Wrote {:04X}: {:02X} -> {:02X}, watchpoint {}",
+                self.address, self.old, self.new, self.watchpoint
+            ),
+        }
+    }
+}
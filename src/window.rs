@@ -0,0 +1,523 @@
+//! Frontend drawing in a window while the emulator runs in its own thread
+//!
+//! Frames go from the emulator thread to the window through a triple buffer
+//! so neither side waits for the other. The window sends everything else
+//! through a channel of commands, except the state of held keys which is
+//! shared in atomics.
+
+use crate::cli::Options;
+use crate::limiter::FrameLimiter;
+use crate::repl;
+use crate::{load_battery, load_symbols, save_battery, state_path};
+use crate::{start_gdb, start_playback, start_recording, write_movie};
+use gbemu::debugger::Debugger;
+use gbemu::gdb::GdbStub;
+use gbemu::gpu::{FRAME_LENGTH, HEIGHT, WIDTH};
+use gbemu::movie::{Input, Movie};
+use gbemu::rewind::Rewind;
+use gbemu::symbols::Symbols;
+use gbemu::triple_buffer::triple_buffer;
+use gbemu::{Buttons, Emulator};
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+use log::error;
+use pixels::{Pixels, SurfaceTexture};
+use winit::dpi::LogicalSize;
+use winit::event::{Event, VirtualKeyCode};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::WindowBuilder;
+use winit_input_helper::WinitInputHelper;
+
+/// Keys selecting the save state slot
+const SLOT_KEYS: [VirtualKeyCode; 9] = [
+    VirtualKeyCode::Key1,
+    VirtualKeyCode::Key2,
+    VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4,
+    VirtualKeyCode::Key5,
+    VirtualKeyCode::Key6,
+    VirtualKeyCode::Key7,
+    VirtualKeyCode::Key8,
+    VirtualKeyCode::Key9,
+];
+
+/// Bounds of the speed multiplier changed with hotkeys
+const MIN_SPEED: f64 = 0.125;
+const MAX_SPEED: f64 = 8.0;
+
+/// Requests from the window to the emulator thread
+enum Command {
+    /// Stop running, or start again
+    TogglePause,
+
+    /// Power cycle the machine
+    Reset,
+
+    /// Stop in the debugger
+    Break,
+
+    /// Insert the cartridge in the given file and power on
+    LoadRom(PathBuf),
+
+    /// Save or load the state in the given slot
+    SaveState(usize),
+    LoadState(usize),
+
+    /// Multiply the speed by the given factor
+    ScaleSpeed(f64),
+}
+
+/// State of the keys held in the window, read by the emulator thread
+#[derive(Default)]
+struct Held {
+    buttons: AtomicU8,
+    rewind: AtomicBool,
+    fast_forward: AtomicBool,
+}
+
+/// Everything the emulator thread owns
+struct Runner {
+    emulator: Emulator,
+
+    /// File of the cartridge running, naming its saves
+    rom: PathBuf,
+    save_dir: Option<PathBuf>,
+
+    limiter: FrameLimiter,
+    rewind: Option<Rewind>,
+
+    /// Snapshots gone back per frame when rewinding
+    rewind_speed: u32,
+
+    /// Frames skipped for every one drawn when fast-forwarding
+    frame_skip: u32,
+
+    paused: bool,
+
+    /// Power cycle before the next frame
+    reset: bool,
+
+    /// Movie replayed instead of the keyboard, and the next frame of it
+    playback: Option<Movie>,
+    playback_frame: u64,
+
+    /// Movie recorded and where it goes
+    recording: Option<(Movie, PathBuf)>,
+
+    debugger: Debugger,
+
+    /// Symbols of the cartridge, for crash reports
+    symbols: Symbols,
+
+    /// GDB debugging the program instead of the debugger prompt
+    gdb: Option<GdbStub>,
+}
+
+impl Runner {
+    /// Run until `running` is cleared, the emulator fails or `frames` were
+    /// emulated
+    fn run(
+        &mut self,
+        held: &Held,
+        running: &AtomicBool,
+        commands: &Receiver<Command>,
+        frames: Option<u64>,
+    ) {
+        let mut frame = 0;
+        let mut skipped = 0;
+        while running.load(Ordering::Relaxed)
+            && frames.is_none_or(|frames| frame < frames)
+        {
+            for command in commands.try_iter() {
+                self.run_command(command);
+            }
+            if self.paused {
+                self.limiter.wait();
+                continue;
+            }
+            // Only draw one frame out of every few when fast-forwarding
+            let fast_forward = held.fast_forward.load(Ordering::Relaxed);
+            if fast_forward {
+                skipped = (skipped + 1) % (self.frame_skip + 1);
+            } else {
+                skipped = 0;
+            }
+            self.emulator.memory.gpu.set_rendering(skipped == 0);
+
+            // Going back in time would desync movies
+            let rewinding = held.rewind.load(Ordering::Relaxed)
+                && self.playback.is_none()
+                && self.recording.is_none();
+            if let (Some(rewind), true) = (&mut self.rewind, rewinding) {
+                let emulator = &mut self.emulator;
+                for _ in 0..self.rewind_speed {
+                    if !rewind.step_back(emulator) {
+                        break;
+                    }
+                }
+                emulator.memory.gpu.present();
+            } else {
+                let input = self.next_input(held);
+                input.apply(&mut self.emulator);
+                let gdb = &mut self.gdb;
+                if gdb.as_mut().is_some_and(GdbStub::poll_interrupt) {
+                    self.debugger.interrupt();
+                }
+                let mut stopped = false;
+                let result = self.debugger.run_frame(
+                    &mut self.emulator,
+                    |debugger, emulator| {
+                        stopped = true;
+                        match gdb {
+                            Some(gdb) => gdb.on_break(debugger, emulator),
+                            None => repl::prompt(debugger, emulator),
+                        }
+                    },
+                );
+                if let Some((movie, _)) = &mut self.recording {
+                    movie.push(input);
+                }
+                if stopped {
+                    // Don't rush to catch up with the time spent stopped
+                    self.limiter.reset();
+                }
+                if let Ok(false) = result {
+                    // Quit from the debugger
+                    break;
+                }
+                if let Err(e) = result {
+                    let report = self.symbols.exit_report(&self.emulator, &e);
+                    eprintln!("Emulator stopped: {} {}", report, self.emulator);
+                    break;
+                }
+                if let Some(rewind) = &mut self.rewind {
+                    rewind.record(&self.emulator);
+                }
+                frame += 1;
+            }
+
+            if fast_forward {
+                self.limiter.reset();
+            } else {
+                self.limiter.wait();
+            }
+        }
+    }
+
+    /// Input of the next frame, from the movie replayed or the keyboard
+    fn next_input(&mut self, held: &Held) -> Input {
+        if let Some(movie) = &self.playback {
+            if let Some(input) = movie.input(self.playback_frame) {
+                self.playback_frame += 1;
+                return input;
+            }
+            println!("Movie finished");
+            self.playback = None;
+        }
+        let pressed = held.buttons.load(Ordering::Relaxed);
+        Input {
+            buttons: Buttons::from_bits(pressed),
+            reset: std::mem::take(&mut self.reset),
+        }
+    }
+
+    /// Write the movie recorded, if any, and stop recording
+    fn stop_recording(&mut self) {
+        if let Some((movie, path)) = self.recording.take() {
+            match write_movie(&movie, &path) {
+                Ok(()) => println!("Saved movie to {}", path.display()),
+                Err(e) => eprintln!("error: {}", e),
+            }
+        }
+    }
+
+    fn run_command(&mut self, command: Command) {
+        let emulator = &mut self.emulator;
+        let save_dir = self.save_dir.as_deref();
+        match command {
+            Command::TogglePause => {
+                self.paused = !self.paused;
+                println!("{}", if self.paused { "Paused" } else { "Resumed" });
+            }
+            Command::Reset => {
+                // Done with the next frame, so that movies record it
+                self.reset = true;
+                if let Some(rewind) = &mut self.rewind {
+                    rewind.clear();
+                }
+                println!("Reset");
+            }
+            Command::Break => {
+                println!("Stopping in the debugger, type help for commands");
+                self.debugger.interrupt();
+                self.paused = false;
+            }
+            Command::LoadRom(path) => {
+                let rom = match std::fs::read(&path) {
+                    Ok(rom) => rom,
+                    Err(e) => {
+                        eprintln!(
+                            "error: can't read ROM {}: {}",
+                            path.display(),
+                            e
+                        );
+                        return;
+                    }
+                };
+                self.stop_recording();
+                self.playback = None;
+                let emulator = &mut self.emulator;
+                let save_dir = self.save_dir.as_deref();
+                if let Err(e) = save_battery(emulator, save_dir, &self.rom) {
+                    eprintln!("error: {}", e);
+                }
+                emulator.load_rom_from_bytes(rom);
+                if let Err(e) = load_battery(emulator, save_dir, &path) {
+                    eprintln!("error: {}", e);
+                }
+                if let Some(rewind) = &mut self.rewind {
+                    rewind.clear();
+                }
+                println!("Loaded {}", path.display());
+                self.symbols = load_symbols(&path);
+                self.debugger.set_symbols(self.symbols.clone());
+                self.rom = path;
+                self.paused = false;
+            }
+            Command::SaveState(slot) => {
+                let path = state_path(save_dir, &self.rom, slot);
+                let result = path
+                    .parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|_| std::fs::write(&path, emulator.save_state()));
+                match result {
+                    Ok(()) => println!("Saved state to {}", path.display()),
+                    Err(e) => eprintln!(
+                        "error: can't write state {}: {}",
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+            Command::LoadState(_)
+                if self.playback.is_some() || self.recording.is_some() =>
+            {
+                eprintln!("error: can't load states along with a movie");
+            }
+            Command::LoadState(slot) => {
+                let path = state_path(save_dir, &self.rom, slot);
+                let result = std::fs::read(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|data| {
+                        emulator.load_state(&data).map_err(|e| e.to_string())
+                    });
+                match result {
+                    Ok(()) => println!("Loaded state from {}", path.display()),
+                    Err(e) => eprintln!(
+                        "error: can't load state {}: {}",
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+            Command::ScaleSpeed(factor) => {
+                let speed =
+                    (self.limiter.speed() * factor).clamp(MIN_SPEED, MAX_SPEED);
+                self.limiter.set_speed(speed);
+                println!("Speed x{}", speed);
+            }
+        }
+    }
+}
+
+/// Run `emulator` in its own thread and show it in a window until either
+/// is closed
+pub fn run(mut emulator: Emulator, options: Options) {
+    let event_loop = EventLoop::with_user_event();
+
+    // Start the emulator and sync the GPU
+    let (output, mut frames) = triple_buffer([0; FRAME_LENGTH]);
+    emulator.memory.gpu.sync(output);
+    let held = Arc::new(Held::default());
+    let held2 = held.clone();
+    let running = Arc::new(AtomicBool::new(true));
+    let running2 = running.clone();
+    let (commands, commands_rx) = mpsc::channel();
+    let proxy = event_loop.create_proxy();
+    let playback = start_playback(&mut emulator, &options);
+    let recording =
+        start_recording(&mut emulator, &options).zip(options.record.clone());
+    let mut runner = Runner {
+        emulator,
+        rom: options.rom.clone(),
+        save_dir: options.save_dir.clone(),
+        limiter: FrameLimiter::new(options.speed),
+        rewind: match options.rewind_memory {
+            0 => None,
+            budget => Some(Rewind::new(
+                options.rewind_interval,
+                budget.saturating_mul(1 << 20),
+            )),
+        },
+        rewind_speed: options.rewind_speed,
+        frame_skip: options.frame_skip,
+        paused: false,
+        reset: false,
+        playback,
+        playback_frame: 0,
+        recording,
+        debugger: Debugger::new(),
+        symbols: load_symbols(&options.rom),
+        gdb: start_gdb(&options),
+    };
+    runner.debugger.set_symbols(runner.symbols.clone());
+    if options.debug || runner.gdb.is_some() {
+        runner.debugger.interrupt();
+    }
+    let frame_count = options.frames;
+    let emulator_thread = thread::spawn(move || {
+        runner.run(&held2, &running2, &commands_rx, frame_count);
+        runner.stop_recording();
+        // Let the window close
+        runner.emulator.memory.gpu.unsync();
+        let _ = proxy.send_event(());
+        runner
+    });
+    let mut emulator_thread = Some(emulator_thread);
+
+    let mut input = WinitInputHelper::new();
+    let window = {
+        let min_size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
+        let size = LogicalSize::new(
+            (WIDTH * options.scale) as f64,
+            (HEIGHT * options.scale) as f64,
+        );
+        WindowBuilder::new()
+            .with_title("GBEMU")
+            .with_inner_size(size)
+            .with_min_inner_size(min_size)
+            .build(&event_loop)
+            .unwrap()
+    };
+
+    let mut pixels = {
+        let window_size = window.inner_size();
+        let surface_texture =
+            SurfaceTexture::new(window_size.width, window_size.height, &window);
+        Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
+    };
+
+    let mut slot = 1;
+
+    event_loop.run(move |event, _, control_flow| {
+        // Stop the emulator thread and keep the cartridge RAM
+        let mut quit = |control_flow: &mut ControlFlow| {
+            running.store(false, Ordering::Relaxed);
+            if let Some(Ok(runner)) = emulator_thread.take().map(|t| t.join()) {
+                let save_dir = runner.save_dir.as_deref();
+                if let Err(e) =
+                    save_battery(&runner.emulator, save_dir, &runner.rom)
+                {
+                    eprintln!("error: {}", e);
+                }
+            }
+            *control_flow = ControlFlow::Exit;
+        };
+
+        // The emulator stopped on its own
+        if let Event::UserEvent(()) = event {
+            quit(control_flow);
+            return;
+        }
+
+        // Draw the latest frame published by the emulator
+        if let Event::RedrawRequested(_) = event {
+            pixels.get_frame().copy_from_slice(frames.read());
+
+            if pixels
+                .render()
+                .map_err(|e| error!("pixels.render() failed: {}", e))
+                .is_err()
+            {
+                quit(control_flow);
+                return;
+            }
+        }
+
+        // Handle input events
+        if input.update(event) {
+            // Close events
+            if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
+                quit(control_flow);
+                return;
+            }
+
+            // Forward the joypad state to the emulator
+            let pressed = Buttons {
+                right: input.key_held(VirtualKeyCode::Right),
+                left: input.key_held(VirtualKeyCode::Left),
+                up: input.key_held(VirtualKeyCode::Up),
+                down: input.key_held(VirtualKeyCode::Down),
+                a: input.key_held(VirtualKeyCode::Z),
+                b: input.key_held(VirtualKeyCode::X),
+                select: input.key_held(VirtualKeyCode::RShift),
+                start: input.key_held(VirtualKeyCode::Return),
+            };
+            held.buttons.store(pressed.to_bits(), Ordering::Relaxed);
+
+            // Play backwards while the key is held
+            let rewind = input.key_held(VirtualKeyCode::Back);
+            held.rewind.store(rewind, Ordering::Relaxed);
+
+            // Run unlimited while the key is held, change the speed
+            let fast_forward = input.key_held(VirtualKeyCode::Tab);
+            held.fast_forward.store(fast_forward, Ordering::Relaxed);
+            if input.key_pressed(VirtualKeyCode::Minus) {
+                let _ = commands.send(Command::ScaleSpeed(0.5));
+            }
+            if input.key_pressed(VirtualKeyCode::Equals) {
+                let _ = commands.send(Command::ScaleSpeed(2.0));
+            }
+
+            if input.key_pressed(VirtualKeyCode::P) {
+                let _ = commands.send(Command::TogglePause);
+            }
+            if input.key_pressed(VirtualKeyCode::F2) {
+                let _ = commands.send(Command::Reset);
+            }
+            if input.key_pressed(VirtualKeyCode::F12) {
+                let _ = commands.send(Command::Break);
+            }
+            if let Some(path) = input.dropped_file() {
+                let _ = commands.send(Command::LoadRom(path));
+            }
+
+            // Save states
+            for (i, &key) in SLOT_KEYS.iter().enumerate() {
+                if input.key_pressed(key) {
+                    slot = i + 1;
+                    println!("Save state slot {}", slot);
+                }
+            }
+            if input.key_pressed(VirtualKeyCode::F5) {
+                let _ = commands.send(Command::SaveState(slot));
+            }
+            if input.key_pressed(VirtualKeyCode::F8) {
+                let _ = commands.send(Command::LoadState(slot));
+            }
+
+            // Resize the window
+            if let Some(size) = input.window_resized() {
+                pixels.resize(size.width, size.height);
+            }
+
+            // Request a redraw
+            window.request_redraw();
+        }
+    });
+}